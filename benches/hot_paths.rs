@@ -0,0 +1,168 @@
+//! Baselines for the per-update hot paths. Redis isn't set up here, so the
+//! cache runs on its in-memory path.
+//!
+//! cargo bench --bench hot_paths
+
+use std::hint::black_box;
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
+use criterion::{BatchSize, BenchmarkId, Criterion, criterion_group, criterion_main};
+use dashmap::DashMap;
+use presence::config::{Config, SharedConfig};
+use presence::discord::PresenceProcessor;
+use presence::redis::Cache;
+use presence::{
+    OnlineStatus, PresenceData, SharedPresence, SpotifyActivity, UserWatchers, Watched,
+};
+use serenity::all::Presence;
+use tokio::runtime::Runtime;
+use tokio::sync::watch;
+
+const USER_ID: &str = "492731761680187403";
+
+fn config() -> SharedConfig {
+    Arc::new(ArcSwap::from_pointee(
+        Config::load().expect("valid config in environment"),
+    ))
+}
+
+fn runtime() -> Runtime {
+    tokio::runtime::Builder::new_current_thread()
+        .build()
+        .expect("tokio runtime")
+}
+
+/// The README example payload.
+fn presence_data() -> PresenceData {
+    PresenceData {
+        user_id: USER_ID.to_string(),
+        status: OnlineStatus::Online,
+        spotify: Some(SpotifyActivity {
+            track: Some("A Shoulder to Cry On".to_string()),
+            artist: Some("Dance Gavin Dance".to_string()),
+            album: Some("Pantheon".to_string()),
+            album_art_url: Some(
+                "https://i.scdn.co/image/ab67616d0000b273bb86aa29f862c224e21b96d8".to_string(),
+            ),
+            album_art_hash: Some("ab67616d0000b273bb86aa29f862c224e21b96d8".to_string()),
+            album_art: presence::cdn::spotify_album_art_sizes(
+                "ab67616d0000b273bb86aa29f862c224e21b96d8",
+            ),
+            track_url: Some("https://open.spotify.com/track/6rqhFgbbKwnb9MLmUQDhG6".to_string()),
+            started_at_ms: Some(1766447419972),
+            ends_at_ms: Some(1766447701646),
+        }),
+        game: None,
+        rich_presence: None,
+        custom_status: None,
+        stage: None,
+        client_status: None,
+        primary_platform: None,
+        username: None,
+        display_name: None,
+        avatar_url: None,
+        idle_since_ms: None,
+        first_seen_ms: None,
+        timestamp_ms: 1766447420190,
+        seq: 12,
+    }
+}
+
+/// A gateway presence update for someone listening to Spotify on desktop.
+fn gateway_presence() -> Presence {
+    serde_json::from_value(serde_json::json!({
+        "user": { "id": USER_ID },
+        "guild_id": "1",
+        "status": "online",
+        "client_status": { "desktop": "online" },
+        "activities": [
+            {
+                "name": "Custom Status",
+                "type": 4,
+                "created_at": 1766447400000u64,
+                "state": "working"
+            },
+            {
+                "name": "Spotify",
+                "type": 2,
+                "created_at": 1766447419972u64,
+                "sync_id": "6rqhFgbbKwnb9MLmUQDhG6",
+                "details": "A Shoulder to Cry On",
+                "state": "Dance Gavin Dance",
+                "assets": {
+                    "large_image": "spotify:ab67616d0000b273bb86aa29f862c224e21b96d8",
+                    "large_text": "Pantheon"
+                },
+                "timestamps": { "start": 1766447419972u64, "end": 1766447701646u64 }
+            }
+        ]
+    }))
+    .expect("valid presence payload")
+}
+
+fn cache(c: &mut Criterion) {
+    let rt = runtime();
+    let cache = Cache::new(config());
+    let data = presence_data();
+    rt.block_on(cache.set(USER_ID, &data));
+
+    c.bench_function("cache/get", |b| {
+        b.to_async(&rt).iter(|| cache.get(black_box(USER_ID)))
+    });
+    c.bench_function("cache/set", |b| {
+        b.to_async(&rt)
+            .iter(|| cache.set(black_box(USER_ID), black_box(&data)))
+    });
+}
+
+fn presence_update(c: &mut Criterion) {
+    let rt = runtime();
+    let config = config();
+    let watchers: UserWatchers = Arc::new(DashMap::new());
+    let (tx, _rx) = watch::channel(Watched::Nothing);
+    watchers.insert(USER_ID.to_string(), tx);
+    let processor = PresenceProcessor::new(Arc::new(Cache::new(config.clone())), watchers, config);
+    let presence = gateway_presence();
+
+    c.bench_function("presence_update", |b| {
+        b.to_async(&rt).iter_batched(
+            || presence.clone(),
+            |presence| processor.process(presence),
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+/// Serializing an update once and handing it to N watchers, each copying the
+/// JSON into its WebSocket message.
+fn broadcast(c: &mut Criterion) {
+    let data = presence_data();
+    let mut group = c.benchmark_group("broadcast");
+
+    for watchers in [1, 10, 100, 1000] {
+        let (tx, rx) = watch::channel(Watched::Nothing);
+        let mut receivers = vec![rx; watchers];
+
+        group.bench_with_input(BenchmarkId::from_parameter(watchers), &watchers, |b, _| {
+            b.iter_batched(
+                || data.clone(),
+                |data| {
+                    let _ = tx.send(Watched::Presence(SharedPresence::new(data)));
+                    for rx in &mut receivers {
+                        let message = rx
+                            .borrow_and_update()
+                            .presence()
+                            .map(|shared| shared.json().to_owned());
+                        black_box(message);
+                    }
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, cache, presence_update, broadcast);
+criterion_main!(benches);
@@ -0,0 +1,46 @@
+fn main() {
+    #[cfg(feature = "grpc")]
+    grpc();
+}
+
+// Messages are hand-written prost structs in src/grpc.rs (mirroring
+// proto/presence.proto) so building doesn't depend on protoc.
+#[cfg(feature = "grpc")]
+fn grpc() {
+    use tonic_build::manual::{Builder, Method, Service};
+
+    let method = |name: &str, route: &str, output: &str| {
+        Method::builder()
+            .name(name)
+            .route_name(route)
+            .input_type("crate::grpc::pb::UserRequest")
+            .output_type(output)
+            .codec_path("tonic_prost::ProstCodec")
+    };
+
+    let service = Service::builder()
+        .name("Presence")
+        .package("presence.v1")
+        .method(
+            method(
+                "get_presence",
+                "GetPresence",
+                "crate::grpc::pb::PresenceData",
+            )
+            .build(),
+        )
+        .method(
+            method(
+                "stream_presence",
+                "StreamPresence",
+                "crate::grpc::pb::PresenceData",
+            )
+            .server_streaming()
+            .build(),
+        )
+        .method(method("is_member", "IsMember", "crate::grpc::pb::MemberResponse").build())
+        .build();
+
+    println!("cargo:rerun-if-changed=build.rs");
+    Builder::new().build_client(false).compile(&[service]);
+}
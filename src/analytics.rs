@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
+
+use arc_swap::ArcSwap;
+use chrono::{Duration, Utc};
+use redis::AsyncCommands;
+use serde::Serialize;
+use tracing::debug;
+
+use crate::config::Config;
+use crate::redis::get_redis;
+
+const DAILY_KEY_TTL_SECS: i64 = 90 * 24 * 60 * 60;
+pub const MAX_WINDOW_DAYS: u32 = 90;
+pub const MAX_LIMIT: usize = 100;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Ranked {
+    pub name: String,
+    pub plays: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TopStats {
+    pub days: u32,
+    /// Unix ms the stats were summed at, they may be served for a while after.
+    pub computed_at_ms: i64,
+    pub tracks: Vec<Ranked>,
+    pub artists: Vec<Ranked>,
+}
+
+/// `presence:stats:daily:{date}` by default, see [`Config::redis_key`].
+fn daily_key(config: &Config, date: chrono::NaiveDate) -> String {
+    config.redis_key(&format!("stats:daily:{}", date.format("%Y-%m-%d")))
+}
+
+/// Counts one play of a track in today's `stats:daily:{date}` hash, under
+/// `track:{artist} - {track}` and `artist:{artist}`. No-op without Redis.
+pub async fn record_play(config: Arc<Config>, track: String, artist: String) {
+    let Some(mut redis) = get_redis().await else {
+        return;
+    };
+
+    let key = daily_key(&config, Utc::now().date_naive());
+    let result: redis::RedisResult<()> = redis::pipe()
+        .hincr(&key, format!("track:{} - {}", artist, track), 1)
+        .hincr(&key, format!("artist:{}", artist), 1)
+        .expire(&key, DAILY_KEY_TTL_SECS)
+        .query_async(&mut redis)
+        .await;
+
+    if let Err(err) = result {
+        debug!(?err, "failed to record play");
+    }
+}
+
+/// Sums the daily hashes for the last `days` days (including today). Returns
+/// `None` when Redis isn't available.
+pub async fn top(config: &Config, days: u32, limit: usize) -> Option<TopStats> {
+    let mut redis = get_redis().await?;
+    let today = Utc::now().date_naive();
+
+    let mut tracks: HashMap<String, u64> = HashMap::new();
+    let mut artists: HashMap<String, u64> = HashMap::new();
+
+    for offset in 0..days {
+        let key = daily_key(config, today - Duration::days(offset as i64));
+        let counts: HashMap<String, u64> = redis.hgetall(&key).await.ok()?;
+        for (field, plays) in counts {
+            if let Some(name) = field.strip_prefix("track:") {
+                *tracks.entry(name.to_string()).or_default() += plays;
+            } else if let Some(name) = field.strip_prefix("artist:") {
+                *artists.entry(name.to_string()).or_default() += plays;
+            }
+        }
+    }
+
+    Some(TopStats {
+        days,
+        computed_at_ms: Utc::now().timestamp_millis(),
+        tracks: ranked(tracks, limit),
+        artists: ranked(artists, limit),
+    })
+}
+
+fn ranked(counts: HashMap<String, u64>, limit: usize) -> Vec<Ranked> {
+    let mut ranked: Vec<_> = counts
+        .into_iter()
+        .map(|(name, plays)| Ranked { name, plays })
+        .collect();
+    ranked.sort_by(|a, b| b.plays.cmp(&a.plays).then_with(|| a.name.cmp(&b.name)));
+    ranked.truncate(limit);
+    ranked
+}
+
+/// Memoized [`top`] results per window, computed with [`MAX_LIMIT`] and cut
+/// down per request. Expired entries are recomputed on the next request, so
+/// an idle endpoint costs nothing.
+#[derive(Default)]
+pub struct TopCache {
+    entries: ArcSwap<HashMap<u32, (Instant, Arc<TopStats>)>>,
+}
+
+impl TopCache {
+    /// Top tracks and artists, summed at most once per `max_age` per window.
+    /// Concurrent requests right after expiry may each recompute.
+    pub async fn get(
+        &self,
+        config: &Config,
+        days: u32,
+        limit: usize,
+        max_age: std::time::Duration,
+    ) -> Option<TopStats> {
+        let cached = self
+            .entries
+            .load()
+            .get(&days)
+            .filter(|(at, _)| at.elapsed() < max_age)
+            .map(|(_, stats)| stats.clone());
+
+        let stats = match cached {
+            Some(stats) => stats,
+            None => {
+                let stats = Arc::new(top(config, days, MAX_LIMIT).await?);
+                let now = Instant::now();
+                self.entries.rcu(|entries| {
+                    let mut entries: HashMap<_, _> = entries
+                        .iter()
+                        .filter(|(_, (at, _))| at.elapsed() < max_age)
+                        .map(|(days, entry)| (*days, entry.clone()))
+                        .collect();
+                    entries.insert(days, (now, stats.clone()));
+                    entries
+                });
+                stats
+            }
+        };
+
+        let mut stats = (*stats).clone();
+        stats.tracks.truncate(limit);
+        stats.artists.truncate(limit);
+        Some(stats)
+    }
+}
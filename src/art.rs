@@ -0,0 +1,198 @@
+//! `GET /v1/art/{hash}`: Spotify album art served from this origin, for embeds
+//! that can't load `i.scdn.co` directly.
+//!
+//! Only bare image hashes are accepted and they are always fetched from
+//! `i.scdn.co`, so the route can't be pointed at any other host.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use bytes::Bytes;
+use presence::cdn;
+
+/// Images kept in memory, the least recently served is dropped first.
+const CACHE_ENTRIES: usize = 128;
+/// Largest image proxied, Spotify's 640px covers are well under this.
+const MAX_IMAGE_BYTES: usize = 2 * 1024 * 1024;
+const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+/// For responses without one. An image hash always names the same image.
+const DEFAULT_CACHE_CONTROL: &str = "public, max-age=86400";
+
+#[derive(Debug, Clone)]
+pub struct Art {
+    pub body: Bytes,
+    pub content_type: String,
+    pub cache_control: String,
+}
+
+#[derive(Debug)]
+pub enum ArtError {
+    /// Spotify has no image under this hash.
+    NotFound,
+    /// The fetch failed or didn't return an image.
+    Upstream(String),
+}
+
+#[derive(Default)]
+struct Lru {
+    /// Each image with the tick it was last served at.
+    entries: HashMap<String, (Art, u64)>,
+    tick: u64,
+}
+
+impl Lru {
+    fn get(&mut self, hash: &str) -> Option<Art> {
+        self.tick += 1;
+        let tick = self.tick;
+        self.entries.get_mut(hash).map(|(art, used)| {
+            *used = tick;
+            art.clone()
+        })
+    }
+
+    fn insert(&mut self, hash: String, art: Art) {
+        if self.entries.len() >= CACHE_ENTRIES && !self.entries.contains_key(&hash) {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(hash, _)| hash.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.tick += 1;
+        self.entries.insert(hash, (art, self.tick));
+    }
+}
+
+pub struct ArtProxy {
+    client: reqwest::Client,
+    cache: Mutex<Lru>,
+}
+
+impl Default for ArtProxy {
+    fn default() -> Self {
+        Self {
+            client: reqwest::Client::builder()
+                .timeout(FETCH_TIMEOUT)
+                .build()
+                .expect("the TLS backend initializes"),
+            cache: Mutex::default(),
+        }
+    }
+}
+
+/// Whether `hash` is a bare Spotify image hash, as found in `album_art_hash`.
+pub fn is_image_hash(hash: &str) -> bool {
+    cdn::spotify_album_art_hash(hash) == Some(hash)
+}
+
+impl ArtProxy {
+    /// The image for a hash that passed `is_image_hash`, from the cache or
+    /// fetched from Spotify. Concurrent misses for one hash may each fetch.
+    pub async fn get(&self, hash: &str) -> Result<Art, ArtError> {
+        if let Some(art) = self.cache.lock().unwrap().get(hash) {
+            return Ok(art);
+        }
+        let art = self.fetch(hash).await?;
+        self.cache
+            .lock()
+            .unwrap()
+            .insert(hash.to_string(), art.clone());
+        Ok(art)
+    }
+
+    async fn fetch(&self, hash: &str) -> Result<Art, ArtError> {
+        let url = cdn::spotify_album_art(hash).ok_or(ArtError::NotFound)?;
+        let mut response = self
+            .client
+            .get(url)
+            .send()
+            .await
+            .map_err(|e| ArtError::Upstream(e.to_string()))?;
+
+        let status = response.status().as_u16();
+        if status == 404 {
+            return Err(ArtError::NotFound);
+        }
+        if !(200..300).contains(&status) {
+            return Err(ArtError::Upstream(format!("spotify answered {status}")));
+        }
+
+        let header = |name: &str| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        let content_type = header("content-type")
+            .filter(|content_type| content_type.starts_with("image/"))
+            .ok_or_else(|| ArtError::Upstream("spotify didn't answer with an image".into()))?;
+        let cache_control =
+            header("cache-control").unwrap_or_else(|| DEFAULT_CACHE_CONTROL.to_string());
+
+        let mut body = Vec::new();
+        while let Some(chunk) = response
+            .chunk()
+            .await
+            .map_err(|e| ArtError::Upstream(e.to_string()))?
+        {
+            if body.len() + chunk.len() > MAX_IMAGE_BYTES {
+                return Err(ArtError::Upstream("image too large".into()));
+            }
+            body.extend_from_slice(&chunk);
+        }
+
+        Ok(Art {
+            body: Bytes::from(body),
+            content_type,
+            cache_control,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn art(body: &'static str) -> Art {
+        Art {
+            body: Bytes::from_static(body.as_bytes()),
+            content_type: "image/jpeg".into(),
+            cache_control: DEFAULT_CACHE_CONTROL.into(),
+        }
+    }
+
+    #[test]
+    fn only_bare_hashes_are_proxied() {
+        assert!(is_image_hash("ab67616d0000b273bb86aa29f862c224e21b96d8"));
+        for hash in [
+            "",
+            "spotify:ab67616d0000b273bb86aa29f862c224e21b96d8",
+            "AB67616D0000B273BB86AA29F862C224E21B96D8",
+            "ab67616d0000b273bb86aa29f862c224e21b96d",
+            "..%2F..%2Fevil.example",
+        ] {
+            assert!(!is_image_hash(hash), "{hash:?} was accepted");
+        }
+    }
+
+    #[test]
+    fn lru_drops_the_least_recently_served() {
+        let mut lru = Lru::default();
+        for i in 0..CACHE_ENTRIES {
+            lru.insert(i.to_string(), art("x"));
+        }
+        // serving the oldest keeps it around
+        assert!(lru.get("0").is_some());
+        lru.insert("new".into(), art("y"));
+
+        assert_eq!(lru.entries.len(), CACHE_ENTRIES);
+        assert!(lru.get("0").is_some());
+        assert!(lru.get("1").is_none());
+        assert_eq!(lru.get("new").unwrap().body, "y");
+    }
+}
@@ -0,0 +1,300 @@
+//! Builders for the Discord and Spotify CDN URLs handed out to clients.
+//!
+//! Hashes come straight from gateway payloads, so every builder validates its
+//! input and returns `None` rather than formatting something unexpected into a URL.
+
+use crate::AlbumArt;
+
+const SPOTIFY_IMAGE_HASH_LEN: usize = 40;
+const SPOTIFY_ID_LEN: usize = 22;
+
+/// Album cover image ids are this prefix, a size code and the cover's id.
+const SPOTIFY_COVER_PREFIX: &str = "ab67616d";
+/// Size codes for 64, 300 and 640px covers.
+const SPOTIFY_COVER_SIZES: [&str; 3] = ["00004851", "00001e02", "0000b273"];
+
+fn is_lower_hex(s: &str) -> bool {
+    s.chars()
+        .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+}
+
+fn is_snowflake(s: &str) -> bool {
+    !s.is_empty() && s.len() <= 20 && s.chars().all(|c| c.is_ascii_digit())
+}
+
+/// Spotify image URL prefixes seen in place of a bare hash.
+const SPOTIFY_IMAGE_PREFIXES: &[&str] = &["https://i.scdn.co/image/", "i.scdn.co/image/"];
+
+/// Strips the `spotify:` prefix from an activity's `large_image` and returns the
+/// image hash, or `None` if it doesn't look like one (40 lowercase hex chars).
+///
+/// A value that is already a full `i.scdn.co/image/<hash>` URL is reduced to
+/// its hash; anything else with a `/` or `:` left in it is rejected.
+pub fn spotify_album_art_hash(large_image: &str) -> Option<&str> {
+    let value = large_image.trim();
+    let value = value.strip_prefix("spotify:").unwrap_or(value);
+    let hash = SPOTIFY_IMAGE_PREFIXES
+        .iter()
+        .find_map(|prefix| value.strip_prefix(prefix))
+        .unwrap_or(value);
+
+    (hash.len() == SPOTIFY_IMAGE_HASH_LEN && is_lower_hex(hash)).then_some(hash)
+}
+
+/// Album art for a Spotify activity's `large_image` (`spotify:<hash>`).
+pub fn spotify_album_art(large_image: &str) -> Option<String> {
+    spotify_album_art_hash(large_image).map(|hash| format!("https://i.scdn.co/image/{hash}"))
+}
+
+/// The 64, 300 and 640px versions of an album cover, for hashes carrying one
+/// of Spotify's cover size codes. Other images only come in one size.
+pub fn spotify_album_art_sizes(hash: &str) -> Option<AlbumArt> {
+    let rest = hash.strip_prefix(SPOTIFY_COVER_PREFIX)?;
+    let (size, id) = rest.split_at_checked(8)?;
+    if !SPOTIFY_COVER_SIZES.contains(&size) || spotify_album_art_hash(hash).is_none() {
+        return None;
+    }
+
+    let [small, medium, large] = SPOTIFY_COVER_SIZES
+        .map(|size| format!("https://i.scdn.co/image/{SPOTIFY_COVER_PREFIX}{size}{id}"));
+    Some(AlbumArt {
+        small,
+        medium,
+        large,
+    })
+}
+
+/// The open.spotify.com page for a Spotify activity's `sync_id`, which is the
+/// track id (22 base62 chars).
+pub fn spotify_track_url(sync_id: &str) -> Option<String> {
+    (sync_id.len() == SPOTIFY_ID_LEN && sync_id.chars().all(|c| c.is_ascii_alphanumeric()))
+        .then(|| format!("https://open.spotify.com/track/{sync_id}"))
+}
+
+/// A user's avatar. Animated avatars (`a_` prefixed hashes) are served as gif.
+pub fn discord_avatar(user_id: &str, hash: &str) -> Option<String> {
+    let (animated, bare) = match hash.strip_prefix("a_") {
+        Some(bare) => (true, bare),
+        None => (false, hash),
+    };
+    if !is_snowflake(user_id) || bare.len() != 32 || !is_lower_hex(bare) {
+        return None;
+    }
+
+    let ext = if animated { "gif" } else { "png" };
+    Some(format!(
+        "https://cdn.discordapp.com/avatars/{user_id}/{hash}.{ext}"
+    ))
+}
+
+/// The default avatar Discord shows for users without one. Users on the new
+/// username system have no discriminator and are assigned by id instead.
+pub fn discord_default_avatar(user_id: u64, discriminator: Option<u16>) -> String {
+    let index = match discriminator {
+        Some(discriminator) => u64::from(discriminator) % 5,
+        None => (user_id >> 22) % 6,
+    };
+    format!("https://cdn.discordapp.com/embed/avatars/{index}.png")
+}
+
+/// A custom emoji.
+pub fn discord_emoji(id: &str, animated: bool) -> Option<String> {
+    if !is_snowflake(id) {
+        return None;
+    }
+
+    let ext = if animated { "gif" } else { "png" };
+    Some(format!("https://cdn.discordapp.com/emojis/{id}.{ext}"))
+}
+
+/// A rich presence asset. `mp:` assets are proxied external images, anything
+/// else is an asset id uploaded to the application.
+pub fn discord_app_asset(app_id: &str, asset: &str) -> Option<String> {
+    if let Some(path) = asset.strip_prefix("mp:") {
+        return (!path.is_empty() && !path.contains("..") && !path.starts_with('/'))
+            .then(|| format!("https://media.discordapp.net/{path}"));
+    }
+
+    if !is_snowflake(app_id) || !is_snowflake(asset) {
+        return None;
+    }
+    Some(format!(
+        "https://cdn.discordapp.com/app-assets/{app_id}/{asset}.png"
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn album_art_hash_accepts_spotify_hashes() {
+        let hash = "ab67616d0000b273bb86aa29f862c224e21b96d8";
+        assert_eq!(
+            spotify_album_art_hash(&format!("spotify:{hash}")),
+            Some(hash)
+        );
+        assert_eq!(spotify_album_art_hash(hash), Some(hash));
+        assert_eq!(
+            spotify_album_art(&format!("spotify:{hash}")).as_deref(),
+            Some("https://i.scdn.co/image/ab67616d0000b273bb86aa29f862c224e21b96d8")
+        );
+    }
+
+    #[test]
+    fn album_art_hash_rejects_malformed_values() {
+        assert_eq!(spotify_album_art_hash(""), None);
+        assert_eq!(spotify_album_art_hash("spotify:"), None);
+        assert_eq!(spotify_album_art_hash("spotify:ab67616d0000b273"), None);
+        assert_eq!(
+            spotify_album_art_hash("spotify:ab67616d0000b273bb86aa29f862c224e21b96d8ff"),
+            None
+        );
+        assert_eq!(
+            spotify_album_art_hash("spotify:AB67616D0000B273BB86AA29F862C224E21B96D8"),
+            None
+        );
+        assert_eq!(
+            spotify_album_art_hash("spotify:../../evil.example/xxxxxxxxxxxxxxxxxxxxxxxxxx"),
+            None
+        );
+        assert_eq!(
+            spotify_album_art_hash("mp:external/abc/https/example.com/a.png"),
+            None
+        );
+    }
+
+    #[test]
+    fn album_art_hash_handles_observed_large_image_variants() {
+        let hash = "ab67616d0000b273bb86aa29f862c224e21b96d8";
+        let url = format!("https://i.scdn.co/image/{hash}");
+        for variant in [
+            format!("spotify:{hash}"),
+            format!(" spotify:{hash} "),
+            format!("spotify:https://i.scdn.co/image/{hash}"),
+            format!("spotify:i.scdn.co/image/{hash}"),
+            format!("https://i.scdn.co/image/{hash}"),
+        ] {
+            assert_eq!(
+                spotify_album_art(&variant).as_deref(),
+                Some(url.as_str()),
+                "{variant}"
+            );
+        }
+
+        for variant in [
+            format!("spotify:spotify:{hash}"),
+            format!("spotify:image/{hash}"),
+            format!("spotify:https://evil.example/image/{hash}"),
+            format!("spotify:https://i.scdn.co/image/{hash}/extra"),
+            format!("spotify:{hash}:extra"),
+            format!("mp:external/x/https/i.scdn.co/image/{hash}"),
+        ] {
+            assert_eq!(spotify_album_art(&variant), None, "{variant}");
+        }
+    }
+
+    #[test]
+    fn album_art_sizes_swap_the_size_code() {
+        let art = spotify_album_art_sizes("ab67616d00001e02bb86aa29f862c224e21b96d8").unwrap();
+        assert_eq!(
+            art.small,
+            "https://i.scdn.co/image/ab67616d00004851bb86aa29f862c224e21b96d8"
+        );
+        assert_eq!(
+            art.medium,
+            "https://i.scdn.co/image/ab67616d00001e02bb86aa29f862c224e21b96d8"
+        );
+        assert_eq!(
+            art.large,
+            "https://i.scdn.co/image/ab67616d0000b273bb86aa29f862c224e21b96d8"
+        );
+
+        // an unknown size code and an artist image
+        assert_eq!(
+            spotify_album_art_sizes("ab67616d0000ffffbb86aa29f862c224e21b96d8"),
+            None
+        );
+        assert_eq!(
+            spotify_album_art_sizes("ab6761610000e5ebbb86aa29f862c224e21b96d8"),
+            None
+        );
+        assert_eq!(spotify_album_art_sizes("ab67616d0000b273"), None);
+    }
+
+    #[test]
+    fn track_url_requires_a_spotify_id() {
+        assert_eq!(
+            spotify_track_url("6rqhFgbbKwnb9MLmUQDhG6").as_deref(),
+            Some("https://open.spotify.com/track/6rqhFgbbKwnb9MLmUQDhG6")
+        );
+        assert_eq!(spotify_track_url(""), None);
+        assert_eq!(spotify_track_url("6rqhFgbbKwnb9MLmUQDhG"), None);
+        assert_eq!(spotify_track_url("../../6rqhFgbbKwnb9MLmU"), None);
+    }
+
+    #[test]
+    fn avatar_uses_gif_for_animated_hashes() {
+        let hash = "0123456789abcdef0123456789abcdef";
+        assert_eq!(
+            discord_avatar("492731761680187403", hash).as_deref(),
+            Some(
+                "https://cdn.discordapp.com/avatars/492731761680187403/0123456789abcdef0123456789abcdef.png"
+            )
+        );
+        assert_eq!(
+            discord_avatar("492731761680187403", &format!("a_{hash}")).as_deref(),
+            Some(
+                "https://cdn.discordapp.com/avatars/492731761680187403/a_0123456789abcdef0123456789abcdef.gif"
+            )
+        );
+        assert_eq!(discord_avatar("abc", hash), None);
+        assert_eq!(discord_avatar("492731761680187403", "a_"), None);
+        assert_eq!(discord_avatar("492731761680187403", "../x"), None);
+    }
+
+    #[test]
+    fn default_avatar_depends_on_username_system() {
+        assert_eq!(
+            discord_default_avatar(492731761680187403, None),
+            "https://cdn.discordapp.com/embed/avatars/0.png"
+        );
+        assert_eq!(
+            discord_default_avatar(492731761680187403, Some(1337)),
+            "https://cdn.discordapp.com/embed/avatars/2.png"
+        );
+    }
+
+    #[test]
+    fn emoji_extension_follows_animated_flag() {
+        assert_eq!(
+            discord_emoji("1234", false).as_deref(),
+            Some("https://cdn.discordapp.com/emojis/1234.png")
+        );
+        assert_eq!(
+            discord_emoji("1234", true).as_deref(),
+            Some("https://cdn.discordapp.com/emojis/1234.gif")
+        );
+        assert_eq!(discord_emoji("", false), None);
+        assert_eq!(discord_emoji("12/34", false), None);
+    }
+
+    #[test]
+    fn app_asset_handles_uploaded_and_external_assets() {
+        assert_eq!(
+            discord_app_asset("383226320970055681", "565945770067623946").as_deref(),
+            Some("https://cdn.discordapp.com/app-assets/383226320970055681/565945770067623946.png")
+        );
+        assert_eq!(
+            discord_app_asset(
+                "383226320970055681",
+                "mp:external/abc/https/example.com/a.png"
+            )
+            .as_deref(),
+            Some("https://media.discordapp.net/external/abc/https/example.com/a.png")
+        );
+        assert_eq!(discord_app_asset("383226320970055681", "mp:"), None);
+        assert_eq!(discord_app_asset("383226320970055681", "mp:../x"), None);
+        assert_eq!(discord_app_asset("x", "565945770067623946"), None);
+    }
+}
@@ -0,0 +1,610 @@
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::{Arc, OnceLock};
+
+use arc_swap::ArcSwap;
+use tracing::{info, warn};
+
+pub type SharedConfig = Arc<ArcSwap<Config>>;
+
+/// Where settings are read from, by variable name.
+type Env<'a> = &'a dyn Fn(&str) -> Option<String>;
+
+/// Variables set in the process environment before `.env` was read at
+/// startup, see [`load_dotenv`].
+static PROCESS_ENV: OnceLock<HashSet<String>> = OnceLock::new();
+
+/// Reads `.env` into the environment at startup, without overriding what is
+/// already set. Remembers what was, so [`Config::reload`] keeps its precedence.
+pub fn load_dotenv() {
+    PROCESS_ENV.get_or_init(|| {
+        std::env::vars_os()
+            .filter_map(|(name, _)| name.into_string().ok())
+            .collect()
+    });
+    let _ = dotenvy::dotenv();
+}
+
+/// Upper bound for `PRESENCE_TTL_MINUTES`, a day.
+const MAX_PRESENCE_TTL_MINUTES: u64 = 24 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ActivityKind {
+    Spotify,
+    Game,
+    CustomStatus,
+}
+
+impl ActivityKind {
+    pub const ALL: &'static [ActivityKind] = &[
+        ActivityKind::Spotify,
+        ActivityKind::Game,
+        ActivityKind::CustomStatus,
+    ];
+
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "spotify" => Some(Self::Spotify),
+            "game" => Some(Self::Game),
+            "custom_status" | "custom" => Some(Self::CustomStatus),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorVerbosity {
+    /// Generic messages only, details are logged server-side.
+    Minimal,
+    /// Internal error details are included in responses.
+    Detailed,
+}
+
+impl ErrorVerbosity {
+    fn parse(raw: &str) -> Result<Self, String> {
+        match raw.trim().to_ascii_lowercase().as_str() {
+            "minimal" => Ok(Self::Minimal),
+            "detailed" => Ok(Self::Detailed),
+            other => Err(format!(
+                "ERROR_VERBOSITY: expected minimal or detailed, got {other:?}"
+            )),
+        }
+    }
+}
+
+/// Runtime configuration read from the environment.
+///
+/// Everything here is swapped in by `POST /admin/reload`, except the values
+/// `reloaded` keeps from the running config, which only apply on restart.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub activity_types: Vec<ActivityKind>,
+    #[cfg(feature = "grpc")]
+    pub grpc_port: u16,
+    pub touch_on_read: bool,
+    pub presence_queue_size: usize,
+    pub api_key: Option<String>,
+    pub stage_tracking: bool,
+    pub error_verbosity: ErrorVerbosity,
+    pub commands_enabled: bool,
+    pub analytics_enabled: bool,
+    pub log_presence: bool,
+    pub batch_concurrency: usize,
+    pub stale_if_error_secs: u64,
+    pub idle_as_offline_secs: u64,
+    /// How long a presence counts as current after its last update.
+    pub presence_ttl_minutes: u64,
+    pub max_connections_per_ip: usize,
+    /// Open WebSocket and NDJSON connections across all clients, 0 for no limit.
+    pub max_total_connections: usize,
+    /// Proxies in front that append to `X-Forwarded-For`, 0 to ignore it.
+    pub trusted_proxy_hops: usize,
+    /// How long a `GET /v1/{id}` keeps the user tracked, 0 to only track
+    /// streamed users.
+    pub interest_ttl_secs: u64,
+    /// Requests per client IP the REST routes allow in a burst, 0 for no limit.
+    pub rate_limit_burst: u32,
+    /// Requests per second each client IP gets back after a burst.
+    pub rate_limit_per_sec: u32,
+    pub stats_cache_secs: u64,
+    pub require_membership: bool,
+    pub ws_send_queue_depth: usize,
+    /// Distinct users that may be watched at once, 0 for no limit.
+    pub max_watched_users: usize,
+    /// Streams that may watch the same user at once, 0 for no limit.
+    pub max_subscribers_per_user: usize,
+    pub mirror_connection_counts: bool,
+    pub flatten_spotify: bool,
+    pub gateway_stall_secs: u64,
+    pub gateway_stall_reconnect: bool,
+    pub respect_invisible: bool,
+    /// Line `/v1/{id}/text` returns for users with no presence.
+    pub text_no_presence: String,
+    /// Announcement shown as `message` in the root response.
+    pub motd: Option<String>,
+    /// URL encoded by `/v1/{id}/qr`, `{user_id}` is replaced with the id.
+    pub qr_url_template: Option<String>,
+    /// Serve album art from this origin at `/v1/art/{hash}`.
+    pub album_art_proxy: bool,
+    /// Origins allowed cross-origin requests with credentials, from
+    /// `CORS_ORIGINS`. `None` allows any origin, without credentials.
+    pub cors_origins: Option<Vec<String>>,
+    pub redis_hash_tags: bool,
+    /// What Redis keys and channels start with, `presence` unless
+    /// `REDIS_KEY_PREFIX` is set.
+    pub redis_key_prefix: String,
+    /// Lifetime of a presence key in Redis, before the stale-if-error window.
+    pub redis_ttl_secs: u64,
+    /// Share presence updates between instances over Redis pub/sub.
+    pub redis_pubsub: bool,
+    /// HTTP listen address, from `BIND_ADDR` and `PORT`.
+    pub bind_addr: SocketAddr,
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    pub h2c: bool,
+    #[cfg(feature = "nats")]
+    pub nats_url: Option<String>,
+    #[cfg(feature = "nats")]
+    pub nats_subject: String,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        let config = Self::load().unwrap_or_else(|e| panic!("{e}"));
+        info!(activity_types = ?config.activity_types, "enabled activity types");
+        config
+    }
+
+    pub fn load() -> Result<Self, String> {
+        Self::load_from(&|name| std::env::var(name).ok())
+    }
+
+    /// Rereads `.env` for a reload without touching the process environment.
+    /// Variables that were set before startup read `.env` keep precedence over
+    /// it, as they do at startup.
+    pub fn reload() -> Result<Self, String> {
+        let file: HashMap<String, String> = match dotenvy::dotenv_iter() {
+            Ok(iter) => iter
+                .collect::<Result<_, _>>()
+                .map_err(|e| format!("failed to read .env: {e}"))?,
+            Err(e) if e.not_found() => HashMap::new(),
+            Err(e) => return Err(format!("failed to read .env: {e}")),
+        };
+        Self::load_from(&|name| reload_var(name, &file, PROCESS_ENV.get()))
+    }
+
+    fn load_from(env: Env) -> Result<Self, String> {
+        let activity_types = match env("ENABLED_ACTIVITY_TYPES") {
+            Some(raw) => parse_activity_types(&raw)?,
+            None => ActivityKind::ALL.to_vec(),
+        };
+
+        let tls_cert = env("TLS_CERT").filter(|p| !p.is_empty());
+        let tls_key = env("TLS_KEY").filter(|p| !p.is_empty());
+        if tls_cert.is_some() != tls_key.is_some() {
+            return Err("TLS_CERT and TLS_KEY must be set together".to_string());
+        }
+        let bind_ip: IpAddr = env_or(env, "BIND_ADDR", IpAddr::from([0, 0, 0, 0]))
+            .map_err(|_| "BIND_ADDR must be an IP address such as 127.0.0.1 or ::".to_string())?;
+        let port: u16 = env_or(env, "PORT", 8787)?;
+        let presence_ttl_minutes = env_positive(env, "PRESENCE_TTL_MINUTES", 5)?;
+        if presence_ttl_minutes > MAX_PRESENCE_TTL_MINUTES {
+            return Err(format!(
+                "PRESENCE_TTL_MINUTES must be at most {MAX_PRESENCE_TTL_MINUTES}"
+            ));
+        }
+
+        let redis_key_prefix = match env("REDIS_KEY_PREFIX") {
+            Some(raw) => parse_redis_key_prefix(&raw)?,
+            None => "presence".to_string(),
+        };
+
+        Ok(Self {
+            activity_types,
+            #[cfg(feature = "grpc")]
+            grpc_port: env_or(env, "GRPC_PORT", 50051)?,
+            touch_on_read: env_flag(env, "TOUCH_ON_READ"),
+            presence_queue_size: env_positive(env, "PRESENCE_QUEUE_SIZE", 1024)?,
+            api_key: env("API_KEY").filter(|k| !k.is_empty()),
+            stage_tracking: env_flag(env, "ENABLE_STAGE_TRACKING"),
+            error_verbosity: match env("ERROR_VERBOSITY") {
+                Some(raw) => ErrorVerbosity::parse(&raw)?,
+                None => ErrorVerbosity::Minimal,
+            },
+            commands_enabled: env_flag(env, "ENABLE_COMMANDS"),
+            analytics_enabled: env_flag(env, "ENABLE_ANALYTICS"),
+            log_presence: env_flag(env, "LOG_PRESENCE"),
+            batch_concurrency: env_positive(env, "BATCH_CONCURRENCY", 16)?,
+            stale_if_error_secs: env_or(env, "STALE_IF_ERROR_SECS", 0)?,
+            idle_as_offline_secs: env_or(env, "IDLE_AS_OFFLINE_SECS", 0)?,
+            presence_ttl_minutes,
+            max_connections_per_ip: env_positive(env, "MAX_CONNECTIONS_PER_IP", 10)?,
+            max_total_connections: env_or(env, "MAX_TOTAL_CONNECTIONS", 0)?,
+            trusted_proxy_hops: env_or(env, "TRUSTED_PROXY_HOPS", 0)?,
+            interest_ttl_secs: env_or(env, "INTEREST_TTL_SECS", 300)?,
+            rate_limit_burst: env_or(env, "RATE_LIMIT_BURST", 0)?,
+            rate_limit_per_sec: env_positive(env, "RATE_LIMIT_PER_SEC", 5)?,
+            stats_cache_secs: env_or(env, "STATS_CACHE_SECS", 5)?,
+            require_membership: env_flag(env, "REQUIRE_MEMBERSHIP"),
+            ws_send_queue_depth: env_positive(env, "WS_SEND_QUEUE_DEPTH", 16)?,
+            max_watched_users: env_or(env, "MAX_WATCHED_USERS", 0)?,
+            max_subscribers_per_user: env_or(env, "MAX_SUBSCRIBERS_PER_USER", 0)?,
+            mirror_connection_counts: env_flag(env, "MIRROR_CONNECTION_COUNTS"),
+            flatten_spotify: env_flag(env, "FLATTEN_SPOTIFY"),
+            gateway_stall_secs: env_or(env, "GATEWAY_STALL_SECS", 600)?,
+            gateway_stall_reconnect: env_flag(env, "GATEWAY_STALL_RECONNECT"),
+            respect_invisible: env_flag(env, "RESPECT_INVISIBLE"),
+            text_no_presence: env("TEXT_NO_PRESENCE").unwrap_or_else(|| "⚫ offline".to_string()),
+            motd: env("MOTD").filter(|m| !m.is_empty()),
+            qr_url_template: env("QR_URL_TEMPLATE").filter(|t| !t.is_empty()),
+            album_art_proxy: env_flag(env, "ALBUM_ART_PROXY"),
+            cors_origins: match env("CORS_ORIGINS") {
+                Some(raw) if !raw.trim().is_empty() => Some(parse_cors_origins(&raw)?),
+                _ => None,
+            },
+            redis_hash_tags: env_flag(env, "REDIS_HASH_TAGS"),
+            redis_key_prefix,
+            redis_ttl_secs: env_positive(env, "REDIS_TTL_SECS", presence_ttl_minutes * 60)?,
+            redis_pubsub: env_flag(env, "REDIS_PUBSUB"),
+            bind_addr: SocketAddr::new(bind_ip, port),
+            tls_cert,
+            tls_key,
+            h2c: env_flag(env, "ENABLE_H2C"),
+            #[cfg(feature = "nats")]
+            nats_url: env("NATS_URL").filter(|u| !u.is_empty()),
+            #[cfg(feature = "nats")]
+            nats_subject: env("NATS_SUBJECT").unwrap_or_else(|| "presence.{user_id}".to_string()),
+        })
+    }
+
+    pub fn presence_ttl_ms(&self) -> i64 {
+        self.presence_ttl_minutes as i64 * 60 * 1000
+    }
+
+    pub fn activity_enabled(&self, kind: ActivityKind) -> bool {
+        self.activity_types.contains(&kind)
+    }
+
+    /// `<prefix>:<name>`, the name of every Redis key and channel. With
+    /// `REDIS_HASH_TAGS` the name is wrapped in a cluster hash tag
+    /// (`<prefix>:{<name>}`), so e.g. all of a user's keys hash to one slot.
+    pub fn redis_key(&self, name: &str) -> String {
+        let prefix = &self.redis_key_prefix;
+        if self.redis_hash_tags {
+            format!("{prefix}:{{{name}}}")
+        } else {
+            format!("{prefix}:{name}")
+        }
+    }
+
+    /// Builds the config to swap in on reload, returning the names of the
+    /// values that changed. Startup-only values are carried over from `self`.
+    pub fn reloaded(&self, next: Config) -> (Config, Vec<&'static str>) {
+        let mut changed = Vec::new();
+        if self.activity_types != next.activity_types {
+            changed.push("ENABLED_ACTIVITY_TYPES");
+        }
+        if self.touch_on_read != next.touch_on_read {
+            changed.push("TOUCH_ON_READ");
+        }
+        if self.api_key != next.api_key {
+            changed.push("API_KEY");
+        }
+        if self.error_verbosity != next.error_verbosity {
+            changed.push("ERROR_VERBOSITY");
+        }
+        if self.analytics_enabled != next.analytics_enabled {
+            changed.push("ENABLE_ANALYTICS");
+        }
+        if self.log_presence != next.log_presence {
+            changed.push("LOG_PRESENCE");
+        }
+        if self.batch_concurrency != next.batch_concurrency {
+            changed.push("BATCH_CONCURRENCY");
+        }
+        if self.stale_if_error_secs != next.stale_if_error_secs {
+            changed.push("STALE_IF_ERROR_SECS");
+        }
+        if self.idle_as_offline_secs != next.idle_as_offline_secs {
+            changed.push("IDLE_AS_OFFLINE_SECS");
+        }
+        if self.presence_ttl_minutes != next.presence_ttl_minutes {
+            changed.push("PRESENCE_TTL_MINUTES");
+        }
+        if self.redis_ttl_secs != next.redis_ttl_secs {
+            changed.push("REDIS_TTL_SECS");
+        }
+        if self.max_connections_per_ip != next.max_connections_per_ip {
+            changed.push("MAX_CONNECTIONS_PER_IP");
+        }
+        if self.max_total_connections != next.max_total_connections {
+            changed.push("MAX_TOTAL_CONNECTIONS");
+        }
+        if self.trusted_proxy_hops != next.trusted_proxy_hops {
+            changed.push("TRUSTED_PROXY_HOPS");
+        }
+        if self.interest_ttl_secs != next.interest_ttl_secs {
+            changed.push("INTEREST_TTL_SECS");
+        }
+        if self.rate_limit_burst != next.rate_limit_burst {
+            changed.push("RATE_LIMIT_BURST");
+        }
+        if self.rate_limit_per_sec != next.rate_limit_per_sec {
+            changed.push("RATE_LIMIT_PER_SEC");
+        }
+        if self.stats_cache_secs != next.stats_cache_secs {
+            changed.push("STATS_CACHE_SECS");
+        }
+        if self.require_membership != next.require_membership {
+            changed.push("REQUIRE_MEMBERSHIP");
+        }
+        if self.ws_send_queue_depth != next.ws_send_queue_depth {
+            changed.push("WS_SEND_QUEUE_DEPTH");
+        }
+        if self.max_watched_users != next.max_watched_users {
+            changed.push("MAX_WATCHED_USERS");
+        }
+        if self.max_subscribers_per_user != next.max_subscribers_per_user {
+            changed.push("MAX_SUBSCRIBERS_PER_USER");
+        }
+        if self.mirror_connection_counts != next.mirror_connection_counts {
+            changed.push("MIRROR_CONNECTION_COUNTS");
+        }
+        if self.flatten_spotify != next.flatten_spotify {
+            changed.push("FLATTEN_SPOTIFY");
+        }
+        if self.gateway_stall_secs != next.gateway_stall_secs {
+            changed.push("GATEWAY_STALL_SECS");
+        }
+        if self.gateway_stall_reconnect != next.gateway_stall_reconnect {
+            changed.push("GATEWAY_STALL_RECONNECT");
+        }
+        if self.respect_invisible != next.respect_invisible {
+            changed.push("RESPECT_INVISIBLE");
+        }
+        if self.motd != next.motd {
+            changed.push("MOTD");
+        }
+        if self.qr_url_template != next.qr_url_template {
+            changed.push("QR_URL_TEMPLATE");
+        }
+        if self.album_art_proxy != next.album_art_proxy {
+            changed.push("ALBUM_ART_PROXY");
+        }
+        if self.text_no_presence != next.text_no_presence {
+            changed.push("TEXT_NO_PRESENCE");
+        }
+
+        #[cfg(feature = "grpc")]
+        if self.grpc_port != next.grpc_port {
+            warn!("GRPC_PORT changed, restart to apply");
+        }
+        if self.presence_queue_size != next.presence_queue_size {
+            warn!("PRESENCE_QUEUE_SIZE changed, restart to apply");
+        }
+        if self.stage_tracking != next.stage_tracking {
+            warn!("ENABLE_STAGE_TRACKING changed, restart to apply");
+        }
+        if self.commands_enabled != next.commands_enabled {
+            warn!("ENABLE_COMMANDS changed, restart to apply");
+        }
+        if self.redis_hash_tags != next.redis_hash_tags {
+            warn!("REDIS_HASH_TAGS changed, restart to apply");
+        }
+        if self.redis_key_prefix != next.redis_key_prefix {
+            warn!("REDIS_KEY_PREFIX changed, restart to apply");
+        }
+        if self.redis_pubsub != next.redis_pubsub {
+            warn!("REDIS_PUBSUB changed, restart to apply");
+        }
+        if self.bind_addr != next.bind_addr {
+            warn!("BIND_ADDR/PORT changed, restart to apply");
+        }
+        if self.tls_cert != next.tls_cert || self.tls_key != next.tls_key {
+            warn!("TLS_CERT/TLS_KEY changed, restart to apply");
+        }
+        if self.h2c != next.h2c {
+            warn!("ENABLE_H2C changed, restart to apply");
+        }
+        if self.cors_origins != next.cors_origins {
+            warn!("CORS_ORIGINS changed, restart to apply");
+        }
+        #[cfg(feature = "nats")]
+        if self.nats_url != next.nats_url || self.nats_subject != next.nats_subject {
+            warn!("NATS_URL/NATS_SUBJECT changed, restart to apply");
+        }
+
+        let config = Config {
+            #[cfg(feature = "grpc")]
+            grpc_port: self.grpc_port,
+            presence_queue_size: self.presence_queue_size,
+            stage_tracking: self.stage_tracking,
+            commands_enabled: self.commands_enabled,
+            redis_hash_tags: self.redis_hash_tags,
+            redis_key_prefix: self.redis_key_prefix.clone(),
+            bind_addr: self.bind_addr,
+            tls_cert: self.tls_cert.clone(),
+            tls_key: self.tls_key.clone(),
+            h2c: self.h2c,
+            cors_origins: self.cors_origins.clone(),
+            #[cfg(feature = "nats")]
+            nats_url: self.nats_url.clone(),
+            #[cfg(feature = "nats")]
+            nats_subject: self.nats_subject.clone(),
+            ..next
+        };
+        (config, changed)
+    }
+}
+
+/// A variable's value on reload: `.env`'s unless the variable was set in the
+/// process environment before startup (or that is unknown).
+fn reload_var(
+    name: &str,
+    file: &HashMap<String, String>,
+    process: Option<&HashSet<String>>,
+) -> Option<String> {
+    match process {
+        Some(process) if !process.contains(name) => file.get(name).cloned(),
+        _ => std::env::var(name).ok().or_else(|| file.get(name).cloned()),
+    }
+}
+
+fn env_or<T: FromStr>(env: Env, name: &str, default: T) -> Result<T, String> {
+    match env(name) {
+        Some(v) => v
+            .trim()
+            .parse()
+            .map_err(|_| format!("{name} must be a valid {}", std::any::type_name::<T>())),
+        None => Ok(default),
+    }
+}
+
+fn env_positive<T: FromStr + Default + PartialOrd>(
+    env: Env,
+    name: &str,
+    default: T,
+) -> Result<T, String> {
+    let value = env_or(env, name, default)?;
+    if value > T::default() {
+        Ok(value)
+    } else {
+        Err(format!("{name} must be greater than zero"))
+    }
+}
+
+/// A key prefix has to be a single non-empty word, so keys stay easy to scan
+/// for and can't be mistaken for separate arguments.
+fn parse_redis_key_prefix(raw: &str) -> Result<String, String> {
+    if raw.is_empty() || raw.chars().any(char::is_whitespace) {
+        return Err("REDIS_KEY_PREFIX must be non-empty and contain no whitespace".to_string());
+    }
+    Ok(raw.to_string())
+}
+
+fn env_flag(env: Env, name: &str) -> bool {
+    env(name)
+        .is_some_and(|v| matches!(v.trim().to_ascii_lowercase().as_str(), "1" | "true" | "yes"))
+}
+
+/// Splits `CORS_ORIGINS` into origins like `https://example.com:8443`, which
+/// is all a browser sends in `Origin`: no path, not even a trailing slash.
+fn parse_cors_origins(raw: &str) -> Result<Vec<String>, String> {
+    raw.split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|origin| {
+            let host = origin
+                .strip_prefix("https://")
+                .or_else(|| origin.strip_prefix("http://"));
+            match host {
+                Some(host)
+                    if !host.is_empty() && !host.contains(['/', '?', '#', '*', '@', ' ']) =>
+                {
+                    Ok(origin.to_string())
+                }
+                _ => Err(format!(
+                    "CORS_ORIGINS: {origin:?} isn't an origin like https://example.com"
+                )),
+            }
+        })
+        .collect()
+}
+
+fn parse_activity_types(raw: &str) -> Result<Vec<ActivityKind>, String> {
+    raw.split(',')
+        .map(|s| s.trim().to_ascii_lowercase())
+        .filter(|s| !s.is_empty())
+        .map(|s| {
+            ActivityKind::parse(&s)
+                .ok_or_else(|| format!("ENABLED_ACTIVITY_TYPES: unknown activity type {s:?}"))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cors_origins_must_be_bare_origins() {
+        assert_eq!(
+            parse_cors_origins("https://example.com, http://localhost:3000,"),
+            Ok(vec![
+                "https://example.com".to_string(),
+                "http://localhost:3000".to_string()
+            ])
+        );
+        for invalid in [
+            "example.com",
+            "https://example.com/",
+            "https://",
+            "*",
+            "ftp://x",
+        ] {
+            assert!(
+                parse_cors_origins(invalid).is_err(),
+                "{invalid:?} was accepted"
+            );
+        }
+    }
+
+    #[test]
+    fn activity_types_accept_custom_for_custom_status() {
+        assert_eq!(
+            parse_activity_types("spotify,custom"),
+            Ok(vec![ActivityKind::Spotify, ActivityKind::CustomStatus])
+        );
+        assert_eq!(
+            parse_activity_types(" Game, custom_status ,"),
+            Ok(vec![ActivityKind::Game, ActivityKind::CustomStatus])
+        );
+        assert!(parse_activity_types("spotify,streaming").is_err());
+    }
+
+    #[test]
+    fn redis_keys_share_the_prefix() {
+        let mut config = Config::load().unwrap();
+        config.redis_key_prefix = "staging".to_string();
+        assert_eq!(config.redis_key("1"), "staging:1");
+        assert_eq!(config.redis_key("updates"), "staging:updates");
+
+        config.redis_hash_tags = true;
+        assert_eq!(config.redis_key("1"), "staging:{1}");
+    }
+
+    #[test]
+    fn reload_prefers_the_environment_only_for_variables_set_before_startup() {
+        let file = HashMap::from([
+            ("PATH".to_string(), "from-file".to_string()),
+            ("PORT".to_string(), "9000".to_string()),
+        ]);
+        let process = HashSet::from(["PATH".to_string()]);
+        let path = std::env::var("PATH").ok();
+
+        assert_eq!(reload_var("PATH", &file, Some(&process)), path);
+        assert_eq!(
+            reload_var("PORT", &file, Some(&process)).as_deref(),
+            Some("9000")
+        );
+        assert_eq!(reload_var("MOTD", &file, Some(&process)), None);
+
+        let config = Config::load_from(&|name| reload_var(name, &file, Some(&process))).unwrap();
+        assert_eq!(config.bind_addr.port(), 9000);
+    }
+
+    #[test]
+    fn redis_key_prefix_is_one_word() {
+        assert_eq!(
+            parse_redis_key_prefix("staging:presence"),
+            Ok("staging:presence".to_string())
+        );
+        for invalid in ["", " ", "prod presence", "presence\n"] {
+            assert!(
+                parse_redis_key_prefix(invalid).is_err(),
+                "{invalid:?} was accepted"
+            );
+        }
+    }
+}
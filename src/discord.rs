@@ -1,63 +1,511 @@
-use std::time::Duration;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+use dashmap::DashMap;
+use serde::Deserialize;
 use serenity::all::{
-    ActivityType, Client, Context, EventHandler, GatewayIntents, Presence, Ready, ResumedEvent,
+    Activity, ActivityEmoji, ActivityType, ChannelType, Client, CommandInteraction,
+    CommandOptionType, ConnectionStage, Context, CreateCommand, CreateCommandOption,
+    CreateInteractionResponse, CreateInteractionResponseMessage, Event, EventHandler,
+    GatewayIntents, Guild, Interaction, OnlineStatus, Presence, PresenceUser, RawEventHandler,
+    Ready, ResumedEvent, ShardStageUpdateEvent, UnavailableGuild, User, VoiceState,
 };
 use serenity::async_trait;
 use serenity::http::Http as SerenityHttp;
 use serenity::model::id::{GuildId, UserId};
+use tokio::sync::{mpsc, watch};
+use tokio::time::timeout;
 use tracing::{debug, error, info, warn};
 
-use crate::{PresenceCache, PresenceData, SpotifyActivity, UserWatchers};
+use crate::analytics;
+use crate::cdn;
+use crate::config::{ActivityKind, SharedConfig};
+use crate::metrics::Metrics;
+use crate::redis;
+use crate::{
+    ClientStatus, CustomStatus, GameActivity, Interest, PresenceCache, PresenceData, RichPresence,
+    RichPresenceButton, SharedPresence, SpotifyActivity, StageInfo, UserWatchers, Watched,
+    is_presence_stale,
+};
+
+/// Whether the gateway is currently connected, i.e. whether fresh presence can
+/// arrive at all.
+#[derive(Debug, Default)]
+pub struct GatewayStatus {
+    connected: watch::Sender<bool>,
+    /// Unix ms of the last gateway event of any kind, 0 before the first.
+    last_event_ms: AtomicI64,
+    /// The `GUILD_IDS` guilds.
+    guild_ids: Vec<GuildId>,
+    /// Whether the bot is in each configured guild, once the gateway said.
+    guilds: DashMap<GuildId, bool>,
+}
+
+impl GatewayStatus {
+    pub fn new(guild_ids: Vec<GuildId>) -> Self {
+        Self {
+            guild_ids,
+            ..Self::default()
+        }
+    }
+
+    pub fn connected(&self) -> bool {
+        *self.connected.borrow()
+    }
+
+    /// Notified whenever the gateway drops or comes back, so open WebSockets
+    /// can tell their clients presence is on hold.
+    pub fn subscribe(&self) -> watch::Receiver<bool> {
+        self.connected.subscribe()
+    }
+
+    pub fn set_connected(&self, connected: bool) {
+        let changed = self
+            .connected
+            .send_if_modified(|current| std::mem::replace(current, connected) != connected);
+        if changed {
+            info!(connected, "discord gateway connectivity changed");
+        }
+    }
+
+    /// Whether the bot is a member of at least one configured guild, `None`
+    /// before the gateway first connected.
+    pub fn in_guild(&self) -> Option<bool> {
+        if self.guilds.is_empty() {
+            return None;
+        }
+        Some(self.guilds.iter().any(|entry| *entry.value()))
+    }
+
+    /// The configured guilds membership can be checked in, i.e. all but those
+    /// the bot is known not to be in.
+    pub fn reachable_guilds(&self) -> Vec<GuildId> {
+        self.guild_ids
+            .iter()
+            .copied()
+            .filter(|id| self.guilds.get(id).is_none_or(|entry| *entry.value()))
+            .collect()
+    }
+
+    fn set_in_guild(&self, guild_id: GuildId, in_guild: bool) {
+        if self.guilds.insert(guild_id, in_guild) == Some(in_guild) {
+            return;
+        }
+        if in_guild {
+            info!(%guild_id, "bot is in the configured guild");
+        } else {
+            error!(
+                %guild_id,
+                "bot is not in a GUILD_IDS guild, no presence will arrive from it and membership \
+                 checks skip it. Invite the bot to the guild or fix GUILD_IDS"
+            );
+        }
+    }
+
+    /// Time since the last gateway event, `None` if none arrived yet.
+    pub fn last_event_age(&self) -> Option<Duration> {
+        let last = self.last_event_ms.load(Ordering::Relaxed);
+        let age = chrono::Utc::now().timestamp_millis() - last;
+        (last > 0).then(|| Duration::from_millis(age.max(0) as u64))
+    }
+}
+
+/// Records every gateway event for the stall watchdog.
+struct EventClock(Arc<GatewayStatus>);
+
+#[async_trait]
+impl RawEventHandler for EventClock {
+    async fn raw_event(&self, _ctx: Context, _ev: Event) {
+        self.0
+            .last_event_ms
+            .store(chrono::Utc::now().timestamp_millis(), Ordering::Relaxed);
+    }
+}
+
+const WATCHDOG_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Resolves when the gateway claims to be connected but has delivered no
+/// event for `GATEWAY_STALL_SECS` and `GATEWAY_STALL_RECONNECT` is on. Stalls
+/// are logged either way.
+async fn watch_for_stall(gateway: &GatewayStatus, config: &SharedConfig) {
+    let mut stalled = false;
+    loop {
+        tokio::time::sleep(WATCHDOG_INTERVAL).await;
+
+        let config = config.load();
+        let limit = Duration::from_secs(config.gateway_stall_secs);
+        let age = gateway.last_event_age();
+        let is_stalled = config.gateway_stall_secs > 0
+            && gateway.connected()
+            && age.is_some_and(|age| age > limit);
+
+        if is_stalled && !stalled {
+            error!(
+                age_secs = age.map(|a| a.as_secs()),
+                "no discord gateway events despite being connected"
+            );
+        }
+        stalled = is_stalled;
+        if stalled && config.gateway_stall_reconnect {
+            return;
+        }
+    }
+}
+
+type StageStates = Arc<DashMap<String, StageInfo>>;
+
+enum Update {
+    Presence(Box<Presence>),
+    Stage(String),
+    /// A profile lookup finished, see [`Profiles`].
+    Profile(UserId),
+}
 
 pub struct Handler {
-    pub cache: PresenceCache,
-    pub watchers: UserWatchers,
+    cache: PresenceCache,
+    watchers: UserWatchers,
+    updates: mpsc::Sender<Update>,
+    stages: StageStates,
+    metrics: Arc<Metrics>,
+    config: SharedConfig,
+    gateway: Arc<GatewayStatus>,
+    interest: Arc<Interest>,
+}
+
+/// Does the actual per-update work off the gateway event loop, fed through a
+/// bounded queue so a burst of updates can't stall the shard or grow memory.
+pub struct PresenceProcessor {
+    cache: PresenceCache,
+    watchers: UserWatchers,
+    stages: StageStates,
+    interest: Arc<Interest>,
+    config: SharedConfig,
+    /// Looks up profile fields the gateway leaves out, see [`Profiles`].
+    profiles: Option<Profiles>,
+    #[cfg(feature = "nats")]
+    sink: Option<crate::nats::Sink>,
+}
+
+/// Whether updates for `user_id` have to be processed: someone streams or
+/// recently requested them, or with `REDIS_PUBSUB` anyone might, since the
+/// watcher may be on another instance.
+fn is_tracked(
+    config: &SharedConfig,
+    watchers: &UserWatchers,
+    interest: &Interest,
+    user_id: &str,
+) -> bool {
+    let config = config.load();
+    config.redis_pubsub
+        || watchers.contains_key(user_id)
+        || interest.contains(user_id, Duration::from_secs(config.interest_ttl_secs))
+}
+
+impl Handler {
+    fn is_watched(&self, user_id: &str) -> bool {
+        is_tracked(&self.config, &self.watchers, &self.interest, user_id)
+    }
+
+    fn enqueue(&self, update: Update) {
+        // shed the newest update when full, the next one for that user supersedes it anyway
+        if self.updates.try_send(update).is_err() {
+            self.metrics.dropped_presence_updates.inc();
+            debug!("presence queue full, dropping update");
+        }
+    }
+
+    /// Queues the presences a guild arrives with for tracked users, so the
+    /// cache doesn't stay empty after a restart until each of them changes.
+    async fn backfill(&self, presences: impl IntoIterator<Item = Presence>) {
+        let mut queued = 0;
+        for presence in presences {
+            if !self.is_watched(&presence.user.id.to_string()) {
+                continue;
+            }
+            // nothing newer is coming for these, so wait for room instead of shedding
+            if self
+                .updates
+                .send(Update::Presence(Box::new(presence)))
+                .await
+                .is_err()
+            {
+                return;
+            }
+            queued += 1;
+        }
+        if queued > 0 {
+            info!(queued, "backfilling presences from the guild snapshot");
+        }
+    }
+
+    async fn presence_command(&self, ctx: &Context, command: &CommandInteraction) {
+        let Some(user_id) = command
+            .data
+            .options
+            .first()
+            .and_then(|o| o.value.as_user_id())
+        else {
+            return;
+        };
+
+        let presence = self
+            .cache
+            .get(&user_id.to_string())
+            .await
+            .filter(|p| !is_presence_stale(&self.config.load(), p));
+
+        let content = match presence.as_ref().and_then(|p| p.spotify.as_ref()) {
+            Some(spotify) => format!(
+                "<@{}> is listening to **{}** by {}",
+                user_id,
+                spotify.track.as_deref().unwrap_or("unknown track"),
+                spotify.artist.as_deref().unwrap_or("unknown artist"),
+            ),
+            None if presence.is_some() => format!("<@{}> isn't listening to anything", user_id),
+            None => format!("No tracked presence for <@{}>", user_id),
+        };
+
+        let response = CreateInteractionResponse::Message(
+            CreateInteractionResponseMessage::new()
+                .content(content)
+                .ephemeral(true),
+        );
+        if let Err(err) = command.create_response(&ctx.http, response).await {
+            warn!(?err, "failed to respond to /presence");
+        }
+    }
 }
 
 #[async_trait]
 impl EventHandler for Handler {
-    async fn ready(&self, _ctx: Context, ready: Ready) {
+    async fn ready(&self, ctx: Context, ready: Ready) {
+        self.metrics.gateway_event("ready");
         info!(user = %ready.user.name, "discord gateway connected");
+        self.gateway.set_connected(true);
+        for &guild_id in &self.gateway.guild_ids {
+            self.gateway
+                .set_in_guild(guild_id, ready.guilds.iter().any(|g| g.id == guild_id));
+        }
+
+        if self.config.load().commands_enabled {
+            let command = CreateCommand::new("presence")
+                .description("Show a member's tracked presence")
+                .add_option(
+                    CreateCommandOption::new(CommandOptionType::User, "user", "Member to look up")
+                        .required(true),
+                );
+            for guild_id in &self.gateway.guild_ids {
+                if let Err(err) = guild_id
+                    .set_commands(&ctx.http, vec![command.clone()])
+                    .await
+                {
+                    warn!(?err, %guild_id, "failed to register slash commands");
+                }
+            }
+        }
+    }
+
+    async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
+        self.metrics.gateway_event("interaction_create");
+        if let Interaction::Command(command) = interaction
+            && command.data.name == "presence"
+            && self.config.load().commands_enabled
+        {
+            self.presence_command(&ctx, &command).await;
+        }
+    }
+
+    async fn guild_create(&self, _ctx: Context, guild: Guild, _is_new: Option<bool>) {
+        self.metrics.gateway_event("guild_create");
+        if self.gateway.guild_ids.contains(&guild.id) {
+            self.gateway.set_in_guild(guild.id, true);
+            self.backfill(guild.presences.into_values()).await;
+        }
+    }
+
+    async fn guild_delete(
+        &self,
+        _ctx: Context,
+        incomplete: UnavailableGuild,
+        _full: Option<Guild>,
+    ) {
+        self.metrics.gateway_event("guild_delete");
+        // an outage also deletes the guild, only `unavailable: false` means removal
+        if self.gateway.guild_ids.contains(&incomplete.id) && !incomplete.unavailable {
+            self.gateway.set_in_guild(incomplete.id, false);
+        }
     }
 
     async fn resume(&self, _ctx: Context, _: ResumedEvent) {
+        self.metrics.gateway_event("resumed");
         info!("discord gateway resumed");
+        self.gateway.set_connected(true);
+    }
+
+    async fn shard_stage_update(&self, _ctx: Context, event: ShardStageUpdateEvent) {
+        self.gateway
+            .set_connected(event.new == ConnectionStage::Connected);
     }
 
     async fn presence_update(&self, _ctx: Context, new: Presence) {
-        let user_id = new.user.id.to_string();
+        self.metrics.gateway_event("presence_update");
+        if !self.is_watched(&new.user.id.to_string()) {
+            return;
+        }
 
-        let watcher = match self.watchers.get(&user_id) {
-            Some(w) => w,
-            None => return,
+        self.enqueue(Update::Presence(Box::new(new)));
+    }
+
+    // only delivered when ENABLE_STAGE_TRACKING requested the voice states intent
+    async fn voice_state_update(&self, ctx: Context, _old: Option<VoiceState>, new: VoiceState) {
+        self.metrics.gateway_event("voice_state_update");
+        let user_id = new.user_id.to_string();
+
+        let stage = new
+            .channel_id
+            .zip(new.guild_id)
+            .and_then(|(channel_id, guild_id)| {
+                let guild = ctx.cache.guild(guild_id)?;
+                let channel = guild.channels.get(&channel_id)?;
+                (channel.kind == ChannelType::Stage).then(|| StageInfo {
+                    channel_id: channel_id.to_string(),
+                    channel_name: channel.name.clone(),
+                    speaker: !new.suppress,
+                })
+            });
+
+        let changed = match stage {
+            Some(stage) => self.stages.insert(user_id.clone(), stage.clone()) != Some(stage),
+            None => self.stages.remove(&user_id).is_some(),
         };
 
-        let raw_spotify_activity = new
-            .activities
-            .iter()
-            .find(|a| a.kind == ActivityType::Listening);
+        if changed && self.is_watched(&user_id) {
+            self.enqueue(Update::Stage(user_id));
+        }
+    }
+}
+
+impl PresenceProcessor {
+    /// A processor without stage tracking, profile lookups or a NATS sink.
+    pub fn new(cache: PresenceCache, watchers: UserWatchers, config: SharedConfig) -> Self {
+        Self {
+            cache,
+            watchers,
+            stages: Arc::new(DashMap::new()),
+            interest: Arc::new(Interest::default()),
+            config,
+            profiles: None,
+            #[cfg(feature = "nats")]
+            sink: None,
+        }
+    }
+
+    async fn run(self, mut rx: mpsc::Receiver<Update>) {
+        while let Some(update) = rx.recv().await {
+            match update {
+                Update::Presence(new) => self.process(*new).await,
+                Update::Stage(user_id) => self.restage(user_id).await,
+                Update::Profile(user_id) => self.reprofile(user_id).await,
+            }
+        }
+    }
+
+    fn is_tracked(&self, user_id: &str) -> bool {
+        is_tracked(&self.config, &self.watchers, &self.interest, user_id)
+    }
+
+    /// Re-publishes the current presence with the user's latest stage state.
+    async fn restage(&self, user_id: String) {
+        if !self.is_tracked(&user_id) {
+            return;
+        }
+        let Some(prev) = self.cache.get(&user_id).await else {
+            return;
+        };
+
+        let presence = PresenceData {
+            stage: self.stages.get(&user_id).map(|s| s.clone()),
+            timestamp_ms: chrono::Utc::now().timestamp_millis(),
+            seq: prev.seq + 1,
+            ..prev
+        };
+        self.broadcast(presence).await;
+    }
+
+    /// Re-publishes the current presence with the profile fields a lookup
+    /// filled in.
+    async fn reprofile(&self, user_id: UserId) {
+        let Some(profile) = self.profiles.as_ref().and_then(|p| p.cached(user_id)) else {
+            return;
+        };
+        let user_id = user_id.to_string();
+        if !self.is_tracked(&user_id) {
+            return;
+        }
+        let Some(prev) = self.cache.get(&user_id).await else {
+            return;
+        };
+        let current = Profile::of(&prev);
+        let profile = current.clone().or(profile);
+        if profile == current {
+            return;
+        }
+
+        let presence = PresenceData {
+            username: profile.username,
+            display_name: profile.display_name,
+            avatar_url: profile.avatar_url,
+            timestamp_ms: chrono::Utc::now().timestamp_millis(),
+            seq: prev.seq + 1,
+            ..prev
+        };
+        self.broadcast(presence).await;
+    }
+
+    pub async fn process(&self, new: Presence) {
+        let user_id = new.user.id.to_string();
+        let config = self.config.load();
+
+        if !self.is_tracked(&user_id) {
+            return;
+        }
+
+        // Discord can still deliver activities for a user who is invisible,
+        // report them exactly as it would an offline user instead
+        let hidden = config.respect_invisible
+            && matches!(new.status, OnlineStatus::Offline | OnlineStatus::Invisible);
+        if hidden && !new.activities.is_empty() {
+            debug!(user_id = %user_id, "dropping activities of invisible user");
+        }
+
+        let raw_spotify_activity = if !hidden && config.activity_enabled(ActivityKind::Spotify) {
+            new.activities
+                .iter()
+                .find(|a| a.kind == ActivityType::Listening)
+        } else {
+            None
+        };
 
         if let Some(a) = raw_spotify_activity {
             debug!(user_id = %user_id, activity = ?a, "spotify activity");
         }
 
         let spotify: Option<SpotifyActivity> = raw_spotify_activity.map(|a| {
-            let album_art_hash = a
+            let large_image = a
                 .assets
                 .as_ref()
-                .and_then(|asst| asst.large_image.as_ref())
-                .map(|li| li.strip_prefix("spotify:").unwrap_or(li).to_string());
-
-            let album_art_url = album_art_hash
-                .as_ref()
-                .map(|hash| format!("https://i.scdn.co/image/{}", hash));
+                .and_then(|asst| asst.large_image.as_deref());
+            let album_art_hash = large_image.and_then(cdn::spotify_album_art_hash);
 
             SpotifyActivity {
                 track: a.details.clone(),
                 artist: a.state.clone(),
                 album: a.assets.as_ref().and_then(|asst| asst.large_text.clone()),
-                album_art_url,
+                album_art_url: large_image.and_then(cdn::spotify_album_art),
+                album_art: album_art_hash.and_then(cdn::spotify_album_art_sizes),
+                album_art_hash: album_art_hash.map(str::to_string),
+                track_url: a.sync_id.as_deref().and_then(cdn::spotify_track_url),
                 started_at_ms: a
                     .timestamps
                     .as_ref()
@@ -66,21 +514,235 @@ impl EventHandler for Handler {
             }
         });
 
-        let presence = PresenceData {
+        let playing = new
+            .activities
+            .iter()
+            .find(|a| a.kind == ActivityType::Playing)
+            .filter(|_| !hidden && config.activity_enabled(ActivityKind::Game));
+        let rich_presence = playing.and_then(rich_presence);
+        let game = playing.map(|a| GameActivity {
+            name: a.name.clone(),
+            details: a.details.clone(),
+            state: a.state.clone(),
+            started_at_ms: a
+                .timestamps
+                .as_ref()
+                .and_then(|t| t.start.map(|v| v as i64)),
+            application_id: a.application_id.map(|id| id.to_string()),
+        });
+
+        let custom_status = new
+            .activities
+            .iter()
+            .find(|a| a.kind == ActivityType::Custom)
+            .filter(|_| !hidden && config.activity_enabled(ActivityKind::CustomStatus))
+            .map(|a| CustomStatus {
+                emoji: a.emoji.as_ref().map(emoji),
+                text: a.state.clone(),
+            })
+            .filter(|c| c.emoji.is_some() || c.text.is_some());
+
+        let prev = self.cache.get(&user_id).await;
+        let seq = prev.as_ref().map(|p| p.seq + 1).unwrap_or(1);
+        let first_seen_ms = prev
+            .as_ref()
+            .filter(|p| !is_presence_stale(&config, p))
+            .and_then(|p| p.first_seen_ms);
+
+        if config.analytics_enabled
+            && let Some(SpotifyActivity {
+                track: Some(track),
+                artist: Some(artist),
+                ..
+            }) = &spotify
+        {
+            let prev_spotify = prev.as_ref().and_then(|p| p.spotify.as_ref());
+            let same_track = prev_spotify.is_some_and(|p| {
+                p.track.as_ref() == Some(track) && p.artist.as_ref() == Some(artist)
+            });
+            if !same_track {
+                tokio::spawn(analytics::record_play(
+                    self.config.load_full(),
+                    track.clone(),
+                    artist.clone(),
+                ));
+            }
+        }
+
+        let client_status = new
+            .client_status
+            .as_ref()
+            .filter(|_| !hidden)
+            .map(client_status);
+        let primary_platform = client_status
+            .as_ref()
+            .and_then(primary_platform)
+            .map(str::to_string);
+
+        let profile = match &self.profiles {
+            Some(profiles) => profiles.resolve(&new.user),
+            None => Profile::from_gateway(&new.user),
+        };
+
+        let now = chrono::Utc::now().timestamp_millis();
+        let mut presence = PresenceData {
             user_id: user_id.clone(),
+            status: if hidden {
+                crate::OnlineStatus::Offline
+            } else {
+                online_status(new.status)
+            },
             spotify,
-            timestamp_ms: chrono::Utc::now().timestamp_millis(),
+            game,
+            rich_presence,
+            custom_status,
+            stage: self.stages.get(&user_id).map(|s| s.clone()),
+            client_status,
+            primary_platform,
+            username: profile.username,
+            display_name: profile.display_name,
+            avatar_url: profile.avatar_url,
+            idle_since_ms: None,
+            first_seen_ms: Some(first_seen_ms.unwrap_or(now)),
+            timestamp_ms: now,
+            seq,
         };
+        if presence.status == crate::OnlineStatus::Idle {
+            let since = prev
+                .as_ref()
+                .filter(|p| p.status == crate::OnlineStatus::Idle)
+                .and_then(|p| p.idle_since_ms);
+            presence.idle_since_ms = Some(since.unwrap_or(now));
+        }
 
-        if watcher.send(Some(presence.clone())).is_ok() {
-            self.cache.set(&user_id, &presence).await;
+        self.broadcast(presence).await;
+    }
+
+    async fn broadcast(&self, presence: PresenceData) {
+        let shared = SharedPresence::new(presence);
+        let sent = self
+            .watchers
+            .get(&shared.presence.user_id)
+            .is_some_and(|w| w.send(Watched::Presence(shared.clone())).is_ok());
+
+        #[cfg(feature = "nats")]
+        if let Some(sink) = &self.sink {
+            sink.publish(&shared);
+        }
+
+        let config = self.config.load();
+        if sent || self.is_tracked(&shared.presence.user_id) {
+            let presence = &shared.presence;
+            if config.log_presence {
+                debug!(user_id = %presence.user_id, presence = %shared.json(), "broadcast presence");
+            }
+            self.cache.set(&presence.user_id, presence).await;
+        }
+        if config.redis_pubsub {
+            redis::publish_presence(&config, &shared).await;
         }
     }
 }
 
-pub async fn start_discord(cache: PresenceCache, watchers: UserWatchers) -> ! {
+fn online_status(status: OnlineStatus) -> crate::OnlineStatus {
+    match status {
+        OnlineStatus::Online => crate::OnlineStatus::Online,
+        OnlineStatus::Idle => crate::OnlineStatus::Idle,
+        OnlineStatus::DoNotDisturb => crate::OnlineStatus::Dnd,
+        _ => crate::OnlineStatus::Offline,
+    }
+}
+
+/// The party and buttons of a "Playing" activity, `None` when it has neither.
+fn rich_presence(activity: &Activity) -> Option<RichPresence> {
+    let party = activity.party.as_ref().and_then(|p| p.size);
+    if party.is_none() && activity.buttons.is_empty() {
+        return None;
+    }
+    Some(RichPresence {
+        name: activity.name.clone(),
+        details: activity.details.clone(),
+        state: activity.state.clone(),
+        party_size: party.map(|[size, _]| size),
+        party_max: party.map(|[_, max]| max),
+        buttons: activity
+            .buttons
+            .iter()
+            .map(|b| RichPresenceButton {
+                label: b.label.clone(),
+                url: Some(b.url.clone()).filter(|url| !url.is_empty()),
+            })
+            .collect(),
+    })
+}
+
+/// Standard emoji are sent as just the character, custom ones get their id.
+fn emoji(emoji: &ActivityEmoji) -> String {
+    match emoji.id {
+        Some(id) => format!("{}:{id}", emoji.name),
+        None => emoji.name.clone(),
+    }
+}
+
+fn client_status(status: &serenity::all::ClientStatus) -> ClientStatus {
+    ClientStatus {
+        desktop: status.desktop.map(online_status),
+        mobile: status.mobile.map(online_status),
+        web: status.web.map(online_status),
+    }
+}
+
+/// Picks the platform the user is most active on: `online` beats `dnd` beats
+/// `idle`, ties go to desktop, then mobile, then web.
+fn primary_platform(status: &ClientStatus) -> Option<&'static str> {
+    let rank = |s: &Option<crate::OnlineStatus>| match s {
+        Some(crate::OnlineStatus::Online) => 3,
+        Some(crate::OnlineStatus::Dnd) => 2,
+        Some(crate::OnlineStatus::Idle) => 1,
+        _ => 0,
+    };
+
+    [
+        ("desktop", rank(&status.desktop)),
+        ("mobile", rank(&status.mobile)),
+        ("web", rank(&status.web)),
+    ]
+    .into_iter()
+    .filter(|(_, rank)| *rank > 0)
+    // max_by_key keeps the last maximum, so reverse to prefer earlier platforms
+    .rev()
+    .max_by_key(|(_, rank)| *rank)
+    .map(|(platform, _)| platform)
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn start_discord(
+    http: Arc<SerenityHttp>,
+    cache: PresenceCache,
+    watchers: UserWatchers,
+    config: SharedConfig,
+    metrics: Arc<Metrics>,
+    gateway: Arc<GatewayStatus>,
+    interest: Arc<Interest>,
+    mut shutdown: watch::Receiver<bool>,
+) {
     let token = std::env::var("DISCORD_BOT_TOKEN").expect("DISCORD_BOT_TOKEN not set");
-    let intents = GatewayIntents::GUILDS | GatewayIntents::GUILD_PRESENCES;
+    let mut intents = GatewayIntents::GUILDS | GatewayIntents::GUILD_PRESENCES;
+    if config.load().stage_tracking {
+        intents |= GatewayIntents::GUILD_VOICE_STATES;
+    }
+
+    let stages: StageStates = Arc::new(DashMap::new());
+    let (updates_tx, updates_rx) = mpsc::channel(config.load().presence_queue_size);
+    let processor = PresenceProcessor {
+        stages: stages.clone(),
+        interest: interest.clone(),
+        profiles: Some(Profiles::new(http, &updates_tx)),
+        #[cfg(feature = "nats")]
+        sink: crate::nats::Sink::from_config(&config.load(), metrics.clone()),
+        ..PresenceProcessor::new(cache.clone(), watchers.clone(), config.clone())
+    };
+    tokio::spawn(processor.run(updates_rx));
 
     let mut attempt: u32 = 0;
 
@@ -88,17 +750,40 @@ pub async fn start_discord(cache: PresenceCache, watchers: UserWatchers) -> ! {
         let handler = Handler {
             cache: cache.clone(),
             watchers: watchers.clone(),
+            updates: updates_tx.clone(),
+            stages: stages.clone(),
+            metrics: metrics.clone(),
+            config: config.clone(),
+            gateway: gateway.clone(),
+            interest: interest.clone(),
         };
 
         match Client::builder(&token, intents)
             .event_handler(handler)
+            .raw_event_handler(EventClock(gateway.clone()))
             .await
         {
             Ok(mut client) => {
                 attempt = 0;
                 info!("discord client starting");
-                if let Err(err) = client.start().await {
-                    warn!(?err, "discord client stopped, will restart");
+                let shard_manager = client.shard_manager.clone();
+                tokio::select! {
+                    result = client.start() => {
+                        if let Err(err) = result {
+                            warn!(?err, "discord client stopped, will restart");
+                        }
+                    }
+                    _ = watch_for_stall(&gateway, &config) => {
+                        warn!("forcing discord gateway reconnect after stall");
+                        shard_manager.shutdown_all().await;
+                        gateway.set_connected(false);
+                    }
+                    _ = crate::wait_for_shutdown(&mut shutdown) => {
+                        info!("closing discord gateway");
+                        shard_manager.shutdown_all().await;
+                        gateway.set_connected(false);
+                        return;
+                    }
                 }
             }
             Err(err) => {
@@ -106,27 +791,669 @@ pub async fn start_discord(cache: PresenceCache, watchers: UserWatchers) -> ! {
             }
         }
 
+        gateway.set_connected(false);
+        metrics.discord_reconnects.inc();
         attempt = attempt.saturating_add(1);
         let backoff_secs = 2_u64.saturating_pow(attempt.min(6)).min(60);
         warn!(attempt, backoff_secs, "reconnecting after backoff");
-        tokio::time::sleep(Duration::from_secs(backoff_secs)).await;
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(backoff_secs)) => {}
+            _ = crate::wait_for_shutdown(&mut shutdown) => return,
+        }
+    }
+}
+
+const MEMBERSHIP_CACHE_TTL: Duration = Duration::from_secs(60);
+const MEMBERSHIP_CACHE_MAX: usize = 50_000;
+/// How long membership lookups stay off Discord after it answered 429.
+const MEMBERSHIP_BACKOFF: Duration = Duration::from_secs(10);
+
+/// Recent membership results per guild and user, so membership lookups don't
+/// mean a Discord API call per request. Non-members are cached as well,
+/// failed checks aren't. After a 429 uncached lookups fail until
+/// `MEMBERSHIP_BACKOFF` has passed instead of adding to the rate limit.
+#[derive(Debug, Default)]
+pub struct MembershipCache {
+    results: DashMap<(GuildId, u64), (bool, Instant)>,
+    backoff_until: Mutex<Option<Instant>>,
+}
+
+impl MembershipCache {
+    pub async fn is_member(
+        &self,
+        http: &SerenityHttp,
+        guild_id: GuildId,
+        user_id: u64,
+    ) -> Result<bool, String> {
+        if let Some(entry) = self.results.get(&(guild_id, user_id))
+            && entry.1.elapsed() < MEMBERSHIP_CACHE_TTL
+        {
+            return Ok(entry.0);
+        }
+        if let Some(until) = *self.backoff_until.lock().unwrap()
+            && Instant::now() < until
+        {
+            return Err("discord rate limited membership lookups, backing off".to_string());
+        }
+
+        let in_server = match fetch_membership(http, guild_id, user_id).await {
+            Ok(in_server) => in_server,
+            Err(err) => {
+                if let serenity::Error::Http(http_err) = &err
+                    && http_err.status_code().map(|s| s.as_u16()) == Some(429)
+                {
+                    *self.backoff_until.lock().unwrap() = Some(Instant::now() + MEMBERSHIP_BACKOFF);
+                }
+                return Err(format!("discord api error: {:?}", err));
+            }
+        };
+        if self.results.len() >= MEMBERSHIP_CACHE_MAX {
+            self.results
+                .retain(|_, (_, at)| at.elapsed() < MEMBERSHIP_CACHE_TTL);
+        }
+        self.results
+            .insert((guild_id, user_id), (in_server, Instant::now()));
+        Ok(in_server)
+    }
+
+    /// The guilds out of `guild_ids` that the user is a member of.
+    pub async fn member_guilds(
+        &self,
+        http: &SerenityHttp,
+        guild_ids: &[GuildId],
+        user_id: u64,
+    ) -> Result<Vec<GuildId>, String> {
+        let mut guilds = Vec::new();
+        for &guild_id in guild_ids {
+            if self.is_member(http, guild_id, user_id).await? {
+                guilds.push(guild_id);
+            }
+        }
+        Ok(guilds)
+    }
+
+    /// Whether the user is a member of any of `guild_ids`, stopping at the first.
+    pub async fn is_member_of_any(
+        &self,
+        http: &SerenityHttp,
+        guild_ids: &[GuildId],
+        user_id: u64,
+    ) -> Result<bool, String> {
+        for &guild_id in guild_ids {
+            if self.is_member(http, guild_id, user_id).await? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+const PROFILE_CACHE_TTL: Duration = Duration::from_secs(60 * 60);
+const PROFILE_RETRY_AFTER: Duration = Duration::from_secs(60);
+const PROFILE_LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+const PROFILE_CACHE_MAX: usize = 10_000;
+
+/// The profile fields of a presence.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Profile {
+    username: Option<String>,
+    display_name: Option<String>,
+    avatar_url: Option<String>,
+}
+
+impl Profile {
+    /// What the gateway sent. Presence updates only sometimes carry the
+    /// username and avatar, and never the display name. A user sent with
+    /// their username but no avatar has the default one.
+    fn from_gateway(user: &PresenceUser) -> Self {
+        let avatar_url = match (user.avatar, &user.name) {
+            (Some(hash), _) => cdn::discord_avatar(&user.id.to_string(), &hash.to_string()),
+            (None, Some(_)) => Some(cdn::discord_default_avatar(
+                user.id.get(),
+                user.discriminator.map(|d| d.get()),
+            )),
+            (None, None) => None,
+        };
+        Self {
+            username: user.name.clone(),
+            display_name: None,
+            avatar_url,
+        }
+    }
+
+    fn of(presence: &PresenceData) -> Self {
+        Self {
+            username: presence.username.clone(),
+            display_name: presence.display_name.clone(),
+            avatar_url: presence.avatar_url.clone(),
+        }
+    }
+
+    /// These fields, with the ones missing taken from `other`.
+    fn or(self, other: Profile) -> Self {
+        Self {
+            username: self.username.or(other.username),
+            display_name: self.display_name.or(other.display_name),
+            avatar_url: self.avatar_url.or(other.avatar_url),
+        }
+    }
+
+    fn from_user(user: &User) -> Self {
+        let avatar_url = match user.avatar {
+            Some(hash) => cdn::discord_avatar(&user.id.to_string(), &hash.to_string()),
+            None => Some(cdn::discord_default_avatar(
+                user.id.get(),
+                user.discriminator.map(|d| d.get()),
+            )),
+        };
+        Self {
+            username: Some(user.name.clone()),
+            display_name: user.global_name.clone(),
+            avatar_url,
+        }
+    }
+}
+
+/// Profiles fetched with `GET /users/{id}` for users the gateway sent only
+/// the id of, cached for an hour. Lookups run in the background and queue an
+/// [`Update::Profile`] once they succeed. Failed ones are retried after a
+/// minute, until then the gateway data is used.
+struct Profiles {
+    http: Arc<SerenityHttp>,
+    cache: Arc<DashMap<UserId, (Profile, Instant)>>,
+    /// Weak so the processor still stops once the gateway side is gone.
+    updates: mpsc::WeakSender<Update>,
+}
+
+impl Profiles {
+    fn new(http: Arc<SerenityHttp>, updates: &mpsc::Sender<Update>) -> Self {
+        Self {
+            http,
+            cache: Arc::new(DashMap::new()),
+            updates: updates.downgrade(),
+        }
+    }
+
+    fn cached(&self, user_id: UserId) -> Option<Profile> {
+        self.cache
+            .get(&user_id)
+            .filter(|entry| Instant::now() < entry.1)
+            .map(|entry| entry.0.clone())
+    }
+
+    /// The user's profile, with whatever the gateway sent taking precedence
+    /// as it's newer. Starts a lookup if the gateway sent only the id and
+    /// there's nothing cached.
+    fn resolve(&self, user: &PresenceUser) -> Profile {
+        let cached = self.cached(user.id).unwrap_or_else(|| {
+            if user.name.is_none() {
+                self.lookup(user.id);
+            }
+            Profile::default()
+        });
+        Profile::from_gateway(user).or(cached)
+    }
+
+    fn lookup(&self, user_id: UserId) {
+        // keeps the updates arriving meanwhile from starting the same lookup
+        cache_profile(
+            &self.cache,
+            user_id,
+            Profile::default(),
+            PROFILE_LOOKUP_TIMEOUT,
+        );
+        let http = self.http.clone();
+        let cache = self.cache.clone();
+        let updates = self.updates.clone();
+        tokio::spawn(async move {
+            match timeout(PROFILE_LOOKUP_TIMEOUT, http.get_user(user_id)).await {
+                Ok(Ok(fetched)) => {
+                    let profile = Profile::from_user(&fetched);
+                    cache_profile(&cache, user_id, profile, PROFILE_CACHE_TTL);
+                    if let Some(updates) = updates.upgrade() {
+                        // nothing newer is coming for this, so wait for room
+                        let _ = updates.send(Update::Profile(user_id)).await;
+                    }
+                }
+                Ok(Err(err)) => {
+                    debug!(user_id = %user_id, ?err, "profile lookup failed");
+                    cache_profile(&cache, user_id, Profile::default(), PROFILE_RETRY_AFTER);
+                }
+                Err(_) => {
+                    debug!(user_id = %user_id, "profile lookup timed out");
+                    cache_profile(&cache, user_id, Profile::default(), PROFILE_RETRY_AFTER);
+                }
+            }
+        });
+    }
+}
+
+fn cache_profile(
+    cache: &DashMap<UserId, (Profile, Instant)>,
+    user_id: UserId,
+    profile: Profile,
+    ttl: Duration,
+) {
+    if cache.len() >= PROFILE_CACHE_MAX {
+        let now = Instant::now();
+        cache.retain(|_, (_, expires)| now < *expires);
+    }
+    cache.insert(user_id, (profile, Instant::now() + ttl));
+}
+
+const OAUTH_USER_CACHE_TTL: Duration = Duration::from_secs(60);
+/// How long a token Discord rejected is turned away without asking again.
+const OAUTH_REJECTED_TTL: Duration = Duration::from_secs(10);
+const OAUTH_USER_CACHE_MAX: usize = 10_000;
+/// Uncached tokens checked with Discord per `OAUTH_LOOKUP_WINDOW`, across all
+/// clients, so a flood of made-up tokens can't exhaust the bot's rate limit.
+const OAUTH_LOOKUPS_PER_WINDOW: u32 = 10;
+const OAUTH_LOOKUP_WINDOW: Duration = Duration::from_secs(1);
+const OAUTH_LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long token lookups stay off Discord after it answered 429.
+const OAUTH_BACKOFF: Duration = Duration::from_secs(10);
+const DISCORD_CURRENT_USER_URL: &str = "https://discord.com/api/v10/users/@me";
+
+/// Maps OAuth2 bearer tokens to the Discord user they belong to, checked
+/// against `/users/@me` and cached briefly. Rejected tokens are cached too,
+/// for `OAUTH_REJECTED_TTL`. Beyond `OAUTH_LOOKUPS_PER_WINDOW` uncached
+/// tokens, and for `OAUTH_BACKOFF` after Discord answered 429, lookups fail
+/// with [`OAuthError::Throttled`] instead of reaching Discord.
+#[derive(Debug)]
+pub struct OAuthUsers {
+    /// The user id, or `None` for a token Discord rejected.
+    tokens: DashMap<String, (Option<u64>, Instant)>,
+    /// Start of the current window and the lookups made in it.
+    lookups: Mutex<Option<(Instant, u32)>>,
+    backoff_until: Mutex<Option<Instant>>,
+    client: reqwest::Client,
+}
+
+impl Default for OAuthUsers {
+    fn default() -> Self {
+        Self {
+            tokens: DashMap::new(),
+            lookups: Mutex::default(),
+            backoff_until: Mutex::default(),
+            client: reqwest::Client::builder()
+                .timeout(OAUTH_LOOKUP_TIMEOUT)
+                .build()
+                .expect("the TLS backend initializes"),
+        }
     }
 }
 
-pub async fn is_member(
+#[derive(Debug, PartialEq)]
+pub enum OAuthError {
+    /// Lookups are held back for this long, see [`OAuthUsers`].
+    Throttled(Duration),
+    /// Discord couldn't be asked or gave an unexpected answer.
+    Upstream(String),
+}
+
+#[derive(Deserialize)]
+struct CurrentUser {
+    id: UserId,
+}
+
+impl OAuthUsers {
+    /// Returns the user id for `token`, or `None` if Discord rejected it.
+    pub async fn resolve(&self, token: &str) -> Result<Option<u64>, OAuthError> {
+        if let Some(entry) = self.tokens.get(token) {
+            let ttl = match entry.0 {
+                Some(_) => OAUTH_USER_CACHE_TTL,
+                None => OAUTH_REJECTED_TTL,
+            };
+            if entry.1.elapsed() < ttl {
+                return Ok(entry.0);
+            }
+        }
+        if let Some(until) = *self.backoff_until.lock().unwrap()
+            && Instant::now() < until
+        {
+            return Err(OAuthError::Throttled(until - Instant::now()));
+        }
+        self.take_lookup().map_err(OAuthError::Throttled)?;
+
+        let user_id = self.fetch(token).await?;
+        if self.tokens.len() >= OAUTH_USER_CACHE_MAX {
+            self.tokens
+                .retain(|_, (_, at)| at.elapsed() < OAUTH_USER_CACHE_TTL);
+        }
+        self.tokens
+            .insert(token.to_string(), (user_id, Instant::now()));
+        Ok(user_id)
+    }
+
+    /// Counts a lookup against the current window, or returns how long until
+    /// the next one once it is used up.
+    fn take_lookup(&self) -> Result<(), Duration> {
+        let mut lookups = self.lookups.lock().unwrap();
+        match &mut *lookups {
+            Some((start, count)) if start.elapsed() < OAUTH_LOOKUP_WINDOW => {
+                if *count >= OAUTH_LOOKUPS_PER_WINDOW {
+                    return Err(OAUTH_LOOKUP_WINDOW - start.elapsed());
+                }
+                *count += 1;
+                Ok(())
+            }
+            _ => {
+                *lookups = Some((Instant::now(), 1));
+                Ok(())
+            }
+        }
+    }
+
+    async fn fetch(&self, token: &str) -> Result<Option<u64>, OAuthError> {
+        let upstream =
+            |err: reqwest::Error| OAuthError::Upstream(format!("discord api error: {err}"));
+        let response = self
+            .client
+            .get(DISCORD_CURRENT_USER_URL)
+            .bearer_auth(token)
+            .send()
+            .await
+            .map_err(upstream)?;
+        match response.status().as_u16() {
+            401 => return Ok(None),
+            429 => {
+                *self.backoff_until.lock().unwrap() = Some(Instant::now() + OAUTH_BACKOFF);
+                return Err(OAuthError::Throttled(OAUTH_BACKOFF));
+            }
+            _ => {}
+        }
+        let body = response
+            .error_for_status()
+            .map_err(upstream)?
+            .bytes()
+            .await
+            .map_err(upstream)?;
+        let user: CurrentUser = serde_json::from_slice(&body).map_err(|err| {
+            OAuthError::Upstream(format!("unexpected /users/@me response: {err}"))
+        })?;
+        Ok(Some(user.id.get()))
+    }
+}
+
+/// Looks the user up in the guild, a 404 meaning they aren't a member.
+async fn fetch_membership(
     http: &SerenityHttp,
     guild_id: GuildId,
     user_id: u64,
-) -> Result<bool, String> {
+) -> Result<bool, serenity::Error> {
     match http.get_member(guild_id, UserId::new(user_id)).await {
         Ok(_) => Ok(true),
-        Err(err) => {
-            if let serenity::Error::Http(http_err) = &err
-                && http_err.status_code().map(|s| s.as_u16()) == Some(404)
-            {
-                return Ok(false);
+        Err(serenity::Error::Http(http_err))
+            if http_err.status_code().map(|s| s.as_u16()) == Some(404) =>
+        {
+            Ok(false)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Parses `GUILD_IDS`, a comma separated list of guild ids.
+pub fn parse_guild_ids(value: &str) -> Result<Vec<GuildId>, String> {
+    let mut guild_ids = Vec::new();
+    for id in value.split(',').map(str::trim).filter(|id| !id.is_empty()) {
+        match id.parse::<u64>() {
+            Ok(id) if id != 0 => {
+                let guild_id = GuildId::new(id);
+                if !guild_ids.contains(&guild_id) {
+                    guild_ids.push(guild_id);
+                }
             }
-            Err(format!("discord api error: {:?}", err))
+            _ => return Err(format!("invalid guild id {id:?}")),
+        }
+    }
+    if guild_ids.is_empty() {
+        return Err("no guild ids given".to_string());
+    }
+    Ok(guild_ids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(
+        desktop: Option<crate::OnlineStatus>,
+        mobile: Option<crate::OnlineStatus>,
+        web: Option<crate::OnlineStatus>,
+    ) -> ClientStatus {
+        ClientStatus {
+            desktop,
+            mobile,
+            web,
+        }
+    }
+
+    #[test]
+    fn emoji_uses_name_and_id_for_custom_emoji() {
+        let parse = |v| serde_json::from_value::<ActivityEmoji>(v).unwrap();
+        assert_eq!(emoji(&parse(serde_json::json!({"name": "🎮"}))), "🎮");
+        assert_eq!(
+            emoji(&parse(
+                serde_json::json!({"name": "pepega", "id": "402578478613004289"})
+            )),
+            "pepega:402578478613004289"
+        );
+    }
+
+    #[test]
+    fn rich_presence_needs_a_party_or_buttons() {
+        let parse = |v| serde_json::from_value::<Activity>(v).unwrap();
+        let plain = parse(serde_json::json!({
+            "name": "Rocket League", "type": 0, "created_at": 0
+        }));
+        assert_eq!(rich_presence(&plain), None);
+
+        let party = parse(serde_json::json!({
+            "name": "Rocket League", "type": 0, "created_at": 0,
+            "state": "In a match",
+            "party": {"id": "abc", "size": [2, 5]},
+            "buttons": ["Join"]
+        }));
+        let rich = rich_presence(&party).unwrap();
+        assert_eq!((rich.party_size, rich.party_max), (Some(2), Some(5)));
+        assert_eq!(rich.state.as_deref(), Some("In a match"));
+        assert_eq!(
+            rich.buttons,
+            vec![RichPresenceButton {
+                label: "Join".to_string(),
+                url: None
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn oauth_lookups_are_cached_and_capped() {
+        let users = OAuthUsers::default();
+        users
+            .tokens
+            .insert("rejected".to_string(), (None, Instant::now()));
+        users
+            .tokens
+            .insert("valid".to_string(), (Some(7), Instant::now()));
+        assert_eq!(users.resolve("rejected").await, Ok(None));
+        assert_eq!(users.resolve("valid").await, Ok(Some(7)));
+
+        for _ in 0..OAUTH_LOOKUPS_PER_WINDOW {
+            assert_eq!(users.take_lookup(), Ok(()));
         }
+        assert!(matches!(
+            users.resolve("unknown").await,
+            Err(OAuthError::Throttled(wait)) if wait <= OAUTH_LOOKUP_WINDOW
+        ));
+
+        *users.backoff_until.lock().unwrap() = Some(Instant::now() + OAUTH_BACKOFF);
+        *users.lookups.lock().unwrap() = None;
+        assert!(matches!(
+            users.resolve("unknown").await,
+            Err(OAuthError::Throttled(wait)) if wait > OAUTH_LOOKUP_WINDOW
+        ));
+    }
+
+    #[test]
+    fn guild_ids_parse_from_a_comma_separated_list() {
+        assert_eq!(
+            parse_guild_ids("1, 2,,3,1"),
+            Ok(vec![GuildId::new(1), GuildId::new(2), GuildId::new(3)])
+        );
+        assert_eq!(parse_guild_ids("42"), Ok(vec![GuildId::new(42)]));
+        assert!(parse_guild_ids("1,abc").is_err());
+        assert!(parse_guild_ids("0").is_err());
+        assert!(parse_guild_ids(" , ").is_err());
+    }
+
+    #[tokio::test]
+    async fn first_seen_survives_updates_until_the_presence_expires() {
+        let config: SharedConfig = Arc::new(arc_swap::ArcSwap::from_pointee(
+            crate::config::Config::load().unwrap(),
+        ));
+        let cache: PresenceCache = Arc::new(redis::Cache::new(config.clone()));
+        let watchers: UserWatchers = Arc::new(DashMap::new());
+        let (tx, _rx) = watch::channel(Watched::Nothing);
+        watchers.insert("1".to_string(), tx);
+        let processor = PresenceProcessor::new(cache.clone(), watchers, config);
+        let update = || {
+            serde_json::from_value::<Presence>(serde_json::json!({
+                "user": {"id": "1"},
+                "status": "online",
+                "activities": []
+            }))
+            .unwrap()
+        };
+
+        processor.process(update()).await;
+        let mut first = cache.get("1").await.unwrap();
+        first.first_seen_ms = Some(1000);
+        cache.set("1", &first).await;
+
+        processor.process(update()).await;
+        let second = cache.get("1").await.unwrap();
+        assert_eq!(second.first_seen_ms, Some(1000));
+
+        let expired = PresenceData {
+            timestamp_ms: 0,
+            ..second
+        };
+        cache.set("1", &expired).await;
+        processor.process(update()).await;
+        let third = cache.get("1").await.unwrap();
+        assert_eq!(third.first_seen_ms, Some(third.timestamp_ms));
+    }
+
+    #[tokio::test]
+    async fn profile_lookups_fill_in_what_the_gateway_left_out() {
+        let config: SharedConfig = Arc::new(arc_swap::ArcSwap::from_pointee(
+            crate::config::Config::load().unwrap(),
+        ));
+        let cache: PresenceCache = Arc::new(redis::Cache::new(config.clone()));
+        let watchers: UserWatchers = Arc::new(DashMap::new());
+        watchers.insert("1".to_string(), watch::channel(Watched::Nothing).0);
+        let (updates, _rx) = mpsc::channel(1);
+        let processor = PresenceProcessor {
+            profiles: Some(Profiles::new(Arc::new(SerenityHttp::new("test")), &updates)),
+            ..PresenceProcessor::new(cache.clone(), watchers, config)
+        };
+        let profiles = processor.profiles.as_ref().unwrap();
+
+        let full = serde_json::json!({
+            "user": {"id": "1", "username": "ada", "avatar": null},
+            "status": "online",
+            "activities": []
+        });
+        processor
+            .process(serde_json::from_value(full).unwrap())
+            .await;
+        assert!(profiles.cache.is_empty(), "looked up a full user");
+        let sent = cache.get("1").await.unwrap();
+        assert_eq!(sent.username.as_deref(), Some("ada"));
+        assert!(sent.avatar_url.is_some(), "no default avatar");
+
+        let fetched = Profile {
+            username: Some("lovelace".to_string()),
+            display_name: Some("Ada".to_string()),
+            avatar_url: None,
+        };
+        cache_profile(&profiles.cache, UserId::new(1), fetched, PROFILE_CACHE_TTL);
+        processor.reprofile(UserId::new(1)).await;
+        let republished = cache.get("1").await.unwrap();
+        assert_eq!(republished.seq, sent.seq + 1);
+        assert_eq!(republished.username.as_deref(), Some("ada"));
+        assert_eq!(republished.display_name.as_deref(), Some("Ada"));
+
+        processor.reprofile(UserId::new(1)).await;
+        assert_eq!(cache.get("1").await.unwrap().seq, republished.seq);
+    }
+
+    #[tokio::test]
+    async fn backfill_queues_only_tracked_users() {
+        let config: SharedConfig = Arc::new(arc_swap::ArcSwap::from_pointee(
+            crate::config::Config::load().unwrap(),
+        ));
+        let watchers: UserWatchers = Arc::new(DashMap::new());
+        watchers.insert("1".to_string(), watch::channel(Watched::Nothing).0);
+        let interest = Arc::new(Interest::default());
+        interest.register("2", Duration::from_secs(60));
+        let (updates, mut rx) = mpsc::channel(1);
+        let handler = Handler {
+            cache: Arc::new(redis::Cache::new(config.clone())),
+            watchers,
+            updates,
+            stages: Arc::new(DashMap::new()),
+            metrics: Arc::new(Metrics::default()),
+            config,
+            gateway: Arc::new(GatewayStatus::new(vec![GuildId::new(1)])),
+            interest,
+        };
+        let presence = |id: &str| {
+            serde_json::from_value::<Presence>(serde_json::json!({
+                "user": {"id": id},
+                "status": "online",
+                "activities": []
+            }))
+            .unwrap()
+        };
+
+        // a queue of one only fits everything if the backfill waits for room
+        let backfill = handler.backfill(["1", "2", "3"].map(presence));
+        let drain = async {
+            let mut queued = Vec::new();
+            while let Some(Update::Presence(p)) = rx.recv().await {
+                queued.push(p.user.id.get());
+                if queued.len() == 2 {
+                    break;
+                }
+            }
+            queued
+        };
+        let ((), queued) = tokio::join!(backfill, drain);
+        assert_eq!(queued, [1, 2]);
+        assert!(rx.try_recv().is_err(), "untracked user was queued");
+    }
+
+    #[test]
+    fn primary_platform_prefers_most_active_status() {
+        use crate::OnlineStatus::{Dnd, Idle, Online};
+
+        assert_eq!(
+            primary_platform(&status(Some(Idle), Some(Online), None)),
+            Some("mobile")
+        );
+        assert_eq!(
+            primary_platform(&status(None, Some(Idle), Some(Dnd))),
+            Some("web")
+        );
+        assert_eq!(
+            primary_platform(&status(Some(Online), Some(Online), Some(Online))),
+            Some("desktop")
+        );
+        assert_eq!(
+            primary_platform(&status(None, Some(Idle), Some(Idle))),
+            Some("mobile")
+        );
+        assert_eq!(primary_platform(&status(None, None, None)), None);
     }
 }
@@ -0,0 +1,243 @@
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+
+use futures_util::Stream;
+use tokio::sync::watch;
+use tonic::{Request, Response, Status};
+use tracing::{error, info, warn};
+
+use crate::config::ErrorVerbosity;
+use crate::{AppState, Watched, idle_as_offline, is_presence_stale, normalize_user_id};
+
+pub mod pb {
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct UserRequest {
+        #[prost(string, tag = "1")]
+        pub user_id: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct SpotifyActivity {
+        #[prost(string, optional, tag = "1")]
+        pub track: Option<String>,
+        #[prost(string, optional, tag = "2")]
+        pub artist: Option<String>,
+        #[prost(string, optional, tag = "3")]
+        pub album: Option<String>,
+        #[prost(string, optional, tag = "4")]
+        pub album_art_url: Option<String>,
+        #[prost(int64, optional, tag = "5")]
+        pub started_at_ms: Option<i64>,
+        #[prost(int64, optional, tag = "6")]
+        pub ends_at_ms: Option<i64>,
+        #[prost(string, optional, tag = "7")]
+        pub track_url: Option<String>,
+        #[prost(string, optional, tag = "8")]
+        pub album_art_hash: Option<String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct PresenceData {
+        #[prost(string, tag = "1")]
+        pub user_id: String,
+        #[prost(message, optional, tag = "2")]
+        pub spotify: Option<SpotifyActivity>,
+        #[prost(int64, tag = "3")]
+        pub timestamp_ms: i64,
+        #[prost(uint64, tag = "4")]
+        pub seq: u64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct MemberResponse {
+        #[prost(bool, tag = "1")]
+        pub in_server: bool,
+    }
+
+    include!(concat!(env!("OUT_DIR"), "/presence.v1.Presence.rs"));
+}
+
+impl From<crate::PresenceData> for pb::PresenceData {
+    fn from(p: crate::PresenceData) -> Self {
+        Self {
+            user_id: p.user_id,
+            spotify: p.spotify.map(|s| pb::SpotifyActivity {
+                track: s.track,
+                artist: s.artist,
+                album: s.album,
+                album_art_url: s.album_art_url,
+                album_art_hash: s.album_art_hash,
+                track_url: s.track_url,
+                started_at_ms: s.started_at_ms,
+                ends_at_ms: s.ends_at_ms,
+            }),
+            timestamp_ms: p.timestamp_ms,
+            seq: p.seq,
+        }
+    }
+}
+
+type PresenceStream = Pin<Box<dyn Stream<Item = Result<pb::PresenceData, Status>> + Send>>;
+
+struct PresenceService {
+    state: AppState,
+}
+
+/// The client address, from the same headers as over HTTP or else the peer.
+fn client_ip<T>(request: &Request<T>, state: &AppState) -> IpAddr {
+    let header = |name| request.metadata().get(name).and_then(|v| v.to_str().ok());
+    crate::client_ip(
+        &state.config.load(),
+        header("cf-connecting-ip"),
+        header("x-forwarded-for"),
+        request.remote_addr().map(|addr| addr.ip()),
+    )
+}
+
+/// A 500 that only carries `detail` with `ERROR_VERBOSITY=detailed`.
+fn internal_error(state: &AppState, message: &str, detail: String) -> Status {
+    error!(detail = %detail, "grpc {}", message);
+    match state.config.load().error_verbosity {
+        ErrorVerbosity::Minimal => Status::internal(message),
+        ErrorVerbosity::Detailed => Status::internal(detail),
+    }
+}
+
+/// `PERMISSION_DENIED` for users `REQUIRE_MEMBERSHIP` hides.
+async fn membership_gate(state: &AppState, user_id: &str) -> Result<(), Status> {
+    match crate::membership_allowed(state, user_id).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(Status::permission_denied(
+            "user is not a member of the guild",
+        )),
+        Err(e) => Err(internal_error(state, "membership check failed", e)),
+    }
+}
+
+/// Expired presences and users idle past `IDLE_AS_OFFLINE_SECS` aren't served.
+fn is_servable(state: &AppState, presence: &crate::PresenceData) -> bool {
+    let config = state.config.load();
+    !is_presence_stale(&config, presence) && !idle_as_offline(&config, presence)
+}
+
+fn user_id_from(request: Request<pb::UserRequest>) -> Result<String, Status> {
+    let user_id = normalize_user_id(request.into_inner().user_id);
+    crate::parse_user_id(&user_id).map_err(Status::invalid_argument)?;
+    Ok(user_id)
+}
+
+#[tonic::async_trait]
+impl pb::presence_server::Presence for PresenceService {
+    async fn get_presence(
+        &self,
+        request: Request<pb::UserRequest>,
+    ) -> Result<Response<pb::PresenceData>, Status> {
+        let user_id = user_id_from(request)?;
+        membership_gate(&self.state, &user_id).await?;
+        self.state.register_interest(&user_id);
+
+        match self.state.cache.get(&user_id).await {
+            Some(presence) if is_servable(&self.state, &presence) => {
+                Ok(Response::new(presence.into()))
+            }
+            _ => Err(Status::not_found("User not found")),
+        }
+    }
+
+    type StreamPresenceStream = PresenceStream;
+
+    async fn stream_presence(
+        &self,
+        request: Request<pb::UserRequest>,
+    ) -> Result<Response<Self::StreamPresenceStream>, Status> {
+        let ip = client_ip(&request, &self.state);
+        let user_id = user_id_from(request)?;
+        membership_gate(&self.state, &user_id).await?;
+
+        let conn_guard = crate::acquire_connection(&self.state, ip).map_err(|limit| {
+            warn!(ip = %ip, ?limit, "connection limit exceeded");
+            Status::resource_exhausted(limit.reason())
+        })?;
+        let watching = crate::subscribe(&self.state, &user_id)
+            .map_err(|e| Status::resource_exhausted(e.reason()))?;
+        let snapshot = self
+            .state
+            .cache
+            .get(&user_id)
+            .await
+            .filter(|p| is_servable(&self.state, p));
+
+        let initial = (watching, snapshot, conn_guard, self.state.clone());
+        let stream = futures_util::stream::unfold(
+            initial,
+            |(mut watching, snapshot, conn_guard, state)| async move {
+                if let Some(p) = snapshot {
+                    return Some((Ok(p.into()), (watching, None, conn_guard, state)));
+                }
+
+                loop {
+                    if watching.rx.changed().await.is_err() {
+                        watching.resubscribe();
+                        continue;
+                    }
+                    let watched = watching.rx.borrow_and_update().clone();
+                    if matches!(watched, Watched::Evicted) {
+                        return None;
+                    }
+                    if let Some(shared) = watched
+                        .presence()
+                        .filter(|s| is_servable(&state, &s.presence))
+                    {
+                        return Some((
+                            Ok(shared.presence.clone().into()),
+                            (watching, None, conn_guard, state),
+                        ));
+                    }
+                }
+            },
+        );
+
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn is_member(
+        &self,
+        request: Request<pb::UserRequest>,
+    ) -> Result<Response<pb::MemberResponse>, Status> {
+        let authorization = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok());
+        if crate::missing_api_key(&self.state, authorization) {
+            return Err(Status::unauthenticated("missing or invalid API key"));
+        }
+        let user_id = user_id_from(request)?;
+        let uid = crate::parse_user_id(&user_id).map_err(Status::invalid_argument)?;
+
+        let guild_ids = self.state.gateway.reachable_guilds();
+        match self
+            .state
+            .membership
+            .is_member_of_any(&self.state.http, &guild_ids, uid)
+            .await
+        {
+            Ok(in_server) => Ok(Response::new(pb::MemberResponse { in_server })),
+            Err(e) => Err(internal_error(&self.state, "membership check failed", e)),
+        }
+    }
+}
+
+pub async fn serve(state: AppState, addr: SocketAddr, mut shutdown: watch::Receiver<bool>) {
+    info!("starting grpc server on {}", addr);
+
+    let service = pb::presence_server::PresenceServer::new(PresenceService { state });
+    if let Err(err) = tonic::transport::Server::builder()
+        .add_service(service)
+        .serve_with_shutdown(addr, async move {
+            crate::wait_for_shutdown(&mut shutdown).await;
+        })
+        .await
+    {
+        error!(?err, "grpc server stopped");
+    }
+}
@@ -0,0 +1,302 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use dashmap::DashMap;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+pub mod analytics;
+pub mod cdn;
+pub mod config;
+pub mod discord;
+pub mod metrics;
+#[cfg(feature = "nats")]
+pub mod nats;
+pub mod redis;
+pub mod text;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct SpotifyActivity {
+    pub track: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub album_art_url: Option<String>,
+    /// The `i.scdn.co/image/` id in `album_art_url`, for building other URLs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub album_art_hash: Option<String>,
+    /// The cover in several sizes, when Spotify serves it in them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub album_art: Option<AlbumArt>,
+    /// open.spotify.com link to the track, when Discord sent its id.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub track_url: Option<String>,
+    pub started_at_ms: Option<i64>,
+    pub ends_at_ms: Option<i64>,
+}
+
+/// Album cover URLs at 64, 300 and 640px.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct AlbumArt {
+    pub small: String,
+    pub medium: String,
+    pub large: String,
+}
+
+impl SpotifyActivity {
+    /// Track length, when Discord sent both timestamps.
+    pub fn duration_ms(&self) -> Option<i64> {
+        Some(self.ends_at_ms? - self.started_at_ms?)
+    }
+
+    /// Playback position at `now_ms`, clamped to the track length so a track
+    /// that ended before the presence did doesn't run past its end.
+    pub fn progress_ms(&self, now_ms: i64) -> Option<i64> {
+        let elapsed = (now_ms - self.started_at_ms?).max(0);
+        Some(match self.duration_ms() {
+            Some(duration) => elapsed.min(duration),
+            None => elapsed,
+        })
+    }
+
+    /// Time left in the track at `now_ms`, 0 once it should have ended.
+    pub fn remaining_ms(&self, now_ms: i64) -> Option<i64> {
+        Some((self.ends_at_ms? - now_ms).max(0))
+    }
+}
+
+/// The game from a "Playing" activity.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct GameActivity {
+    pub name: String,
+    pub details: Option<String>,
+    pub state: Option<String>,
+    pub started_at_ms: Option<i64>,
+    pub application_id: Option<String>,
+}
+
+/// Party size and buttons from a "Playing" activity's rich presence, when the
+/// game sets either.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct RichPresence {
+    pub name: String,
+    pub details: Option<String>,
+    pub state: Option<String>,
+    /// Players in the party, counting the user.
+    pub party_size: Option<u32>,
+    pub party_max: Option<u32>,
+    #[serde(default)]
+    pub buttons: Vec<RichPresenceButton>,
+}
+
+/// `url` is missing for presences received over the gateway, bots only get
+/// the label.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct RichPresenceButton {
+    pub label: String,
+    pub url: Option<String>,
+}
+
+/// A user's custom status. `emoji` is the character itself for standard
+/// emoji and `name:id` for custom guild emoji.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct CustomStatus {
+    pub emoji: Option<String>,
+    pub text: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct StageInfo {
+    pub channel_id: String,
+    pub channel_name: String,
+    /// `false` while the user is in the audience.
+    pub speaker: bool,
+}
+
+/// A user's status as Discord reports it. Invisible users show as offline.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum OnlineStatus {
+    Online,
+    Idle,
+    Dnd,
+    #[default]
+    Offline,
+}
+
+impl OnlineStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Online => "online",
+            Self::Idle => "idle",
+            Self::Dnd => "dnd",
+            Self::Offline => "offline",
+        }
+    }
+}
+
+/// Per-platform status (`online`, `idle` or `dnd`), absent where the user
+/// isn't connected.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ClientStatus {
+    pub desktop: Option<OnlineStatus>,
+    pub mobile: Option<OnlineStatus>,
+    pub web: Option<OnlineStatus>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct PresenceData {
+    pub user_id: String,
+    /// Overall status across platforms.
+    #[serde(default)]
+    pub status: OnlineStatus,
+    pub spotify: Option<SpotifyActivity>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub game: Option<GameActivity>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rich_presence: Option<RichPresence>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub custom_status: Option<CustomStatus>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stage: Option<StageInfo>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_status: Option<ClientStatus>,
+    /// The platform from `client_status` the user is most active on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub primary_platform: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    /// The user's global display name, if they set one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    /// The user's avatar, or the default one Discord shows without.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub avatar_url: Option<String>,
+    /// Unix ms the user went idle, while their status is `idle`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idle_since_ms: Option<i64>,
+    /// Unix ms of the first update since the presence was last expired, i.e.
+    /// since when the user has been continuously tracked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub first_seen_ms: Option<i64>,
+    pub timestamp_ms: i64,
+    /// Per-user update counter, restarts at 1 once a presence expires.
+    #[serde(default)]
+    pub seq: u64,
+}
+
+pub type PresenceCache = Arc<redis::Cache>;
+pub type UserWatchers = Arc<DashMap<String, watch::Sender<Watched>>>;
+
+/// What the streams watching a user see.
+#[derive(Debug, Clone, Default)]
+pub enum Watched {
+    /// Nothing was received since the watcher was created.
+    #[default]
+    Nothing,
+    Presence(Arc<SharedPresence>),
+    /// The presence was evicted with `DELETE /v1/{id}`. The watcher is torn
+    /// down with it, so this is the last value its streams see.
+    Evicted,
+}
+
+impl Watched {
+    pub fn presence(&self) -> Option<Arc<SharedPresence>> {
+        match self {
+            Self::Presence(shared) => Some(shared.clone()),
+            Self::Nothing | Self::Evicted => None,
+        }
+    }
+}
+
+/// Tears down the user's watcher and tells the streams on it the presence was
+/// evicted, whichever way they buffer updates. Streams subscribing afterwards
+/// get a fresh watcher.
+pub fn evict_watcher(watchers: &UserWatchers, user_id: &str) {
+    if let Some((_, watcher)) = watchers.remove(user_id) {
+        watcher.send_replace(Watched::Evicted);
+    }
+}
+
+const MAX_INTERESTED_USERS: usize = 10_000;
+
+/// Users recently asked for over HTTP. Their presence is tracked like that of
+/// watched users, so polling clients get data without holding a stream open.
+#[derive(Debug, Default)]
+pub struct Interest(DashMap<String, Instant>);
+
+impl Interest {
+    /// Notes a request for `user_id`. Once full, users are only added after
+    /// expired ones were dropped.
+    pub fn register(&self, user_id: &str, ttl: Duration) {
+        if let Some(mut at) = self.0.get_mut(user_id) {
+            *at = Instant::now();
+            return;
+        }
+        if self.0.len() >= MAX_INTERESTED_USERS {
+            self.0.retain(|_, at| at.elapsed() < ttl);
+            if self.0.len() >= MAX_INTERESTED_USERS {
+                return;
+            }
+        }
+        self.0.insert(user_id.to_string(), Instant::now());
+    }
+
+    /// Whether `user_id` was asked for within the last `ttl`.
+    pub fn contains(&self, user_id: &str, ttl: Duration) -> bool {
+        self.0.get(user_id).is_some_and(|at| at.elapsed() < ttl)
+    }
+}
+
+/// A presence as handed to watchers. It is serialized once when broadcast, so
+/// fanning out to N connections costs one serialization plus N copies of the
+/// finished bytes (none for NDJSON) instead of N clones and serializations.
+/// For the README example payload that is 1 allocation (~300 bytes) per
+/// WebSocket instead of 8 (~1 KB).
+#[derive(Debug)]
+pub struct SharedPresence {
+    pub presence: PresenceData,
+    /// The JSON encoding followed by a newline, i.e. a ready NDJSON line.
+    line: Bytes,
+}
+
+impl SharedPresence {
+    pub fn new(presence: PresenceData) -> Arc<Self> {
+        let mut line = serde_json::to_vec(&presence).expect("PresenceData serializes");
+        line.push(b'\n');
+        Arc::new(Self {
+            presence,
+            line: Bytes::from(line),
+        })
+    }
+
+    pub fn json(&self) -> &str {
+        std::str::from_utf8(&self.line[..self.line.len() - 1]).expect("serde_json emits UTF-8")
+    }
+
+    pub fn json_bytes(&self) -> Bytes {
+        self.line.slice(..self.line.len() - 1)
+    }
+
+    pub fn ndjson_line(&self) -> Bytes {
+        self.line.clone()
+    }
+}
+
+/// Whether the presence is older than `PRESENCE_TTL_MINUTES`.
+pub fn is_presence_stale(config: &config::Config, presence: &PresenceData) -> bool {
+    is_timestamp_stale(config, presence.timestamp_ms)
+}
+
+/// Whether a presence updated at `timestamp_ms` is older than
+/// `PRESENCE_TTL_MINUTES`.
+pub fn is_timestamp_stale(config: &config::Config, timestamp_ms: i64) -> bool {
+    let now = chrono::Utc::now().timestamp_millis();
+    now - timestamp_ms > config.presence_ttl_ms()
+}
+
+/// Resolves once shutdown has been signalled (or the sender is gone).
+pub async fn wait_for_shutdown(shutdown: &mut watch::Receiver<bool>) {
+    let _ = shutdown.wait_for(|stop| *stop).await;
+}
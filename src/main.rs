@@ -1,42 +1,40 @@
+use std::collections::HashMap;
 use std::convert::Infallible;
 use std::net::IpAddr;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
+use arc_swap::ArcSwap;
+use bytes::Bytes;
 use dashmap::DashMap;
-use futures_util::{SinkExt, StreamExt};
+use futures_util::{Sink, SinkExt, Stream, StreamExt};
+use presence::{
+    Interest, OnlineStatus, PresenceCache, PresenceData, SharedPresence, UserWatchers, Watched,
+    analytics, config, discord, evict_watcher, is_presence_stale, is_timestamp_stale, metrics,
+    redis, text, wait_for_shutdown,
+};
 use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
 use serenity::http::Http as SerenityHttp;
-use serenity::model::id::GuildId;
-use tokio::sync::watch;
+use tokio::sync::{mpsc, oneshot, watch};
 use tokio::time::{Duration, Instant, interval_at, timeout};
-use tracing::{info, warn};
+use tracing::{Instrument, debug, error, info, info_span, warn};
 use warp::ws::{Message, WebSocket, Ws};
 use warp::{Filter, Rejection, Reply, http::StatusCode};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct SpotifyActivity {
-    pub track: Option<String>,
-    pub artist: Option<String>,
-    pub album: Option<String>,
-    pub album_art_url: Option<String>,
-    pub started_at_ms: Option<i64>,
-    pub ends_at_ms: Option<i64>,
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct PresenceData {
-    pub user_id: String,
-    pub spotify: Option<SpotifyActivity>,
-    pub timestamp_ms: i64,
-}
-
-const PRESENCE_TTL_MINUTES: i64 = 5;
-const PRESENCE_TTL_MS: i64 = PRESENCE_TTL_MINUTES * 60 * 1000;
-const MAX_CONNECTIONS_PER_IP: usize = 10;
 const WS_SEND_TIMEOUT: Duration = Duration::from_secs(5);
+const WS_RESUME_WINDOW: Duration = Duration::from_millis(500);
+/// Largest message a client may send, well above a full `/ws/v1` subscribe.
+const WS_MAX_MESSAGE_SIZE: usize = 16 * 1024;
+/// Where the WebSocket codec itself gives up, so a client can't make us
+/// buffer an endless frame before [`ws_policy_violation`] gets to see it.
+const WS_MAX_FRAME_BUFFER: usize = 4 * WS_MAX_MESSAGE_SIZE;
+const NDJSON_KEEPALIVE: Duration = Duration::from_secs(25);
+/// How often an idle SSE stream sends a comment line, under the 30s or so
+/// after which proxies commonly drop quiet connections.
+const SSE_KEEPALIVE: Duration = Duration::from_secs(15);
 
-pub type PresenceCache = Arc<redis::Cache>;
-pub type UserWatchers = Arc<DashMap<String, watch::Sender<Option<PresenceData>>>>;
+type PresenceReceiver = watch::Receiver<Watched>;
 type ConnectionCounter = Arc<DashMap<IpAddr, usize>>;
 
 #[derive(Clone)]
@@ -44,295 +42,4256 @@ struct AppState {
     cache: PresenceCache,
     watchers: UserWatchers,
     connections: ConnectionCounter,
+    /// All open connections, for `MAX_TOTAL_CONNECTIONS`.
+    total_connections: Arc<AtomicUsize>,
+    /// Set while Redis is available, for `MIRROR_CONNECTION_COUNTS`.
+    connection_mirror: Option<redis::ConnectionMirror>,
     http: Arc<SerenityHttp>,
-    guild_id: GuildId,
+    metrics: Arc<metrics::Metrics>,
+    gateway: Arc<discord::GatewayStatus>,
+    membership: Arc<discord::MembershipCache>,
+    oauth_users: Arc<discord::OAuthUsers>,
+    top_stats: Arc<analytics::TopCache>,
+    interest: Arc<Interest>,
+    rate_limiter: Arc<RateLimiter>,
+    art: Arc<art::ArtProxy>,
+    /// Set to true once the process is shutting down.
+    shutdown: Arc<watch::Sender<bool>>,
+    config: config::SharedConfig,
+}
+
+impl AppState {
+    /// Keeps `user_id` tracked for `INTEREST_TTL_SECS` after a request for it.
+    fn register_interest(&self, user_id: &str) {
+        let ttl = self.config.load().interest_ttl_secs;
+        if ttl > 0 {
+            self.interest.register(user_id, Duration::from_secs(ttl));
+        }
+    }
 }
 
-fn is_presence_stale(presence: &PresenceData) -> bool {
+/// Whether a stale presence may still be served because the gateway is down
+/// and nothing fresher can arrive, up to `STALE_IF_ERROR_SECS` past staleness.
+fn serve_stale_if_error(state: &AppState, presence: &PresenceData) -> bool {
+    let grace_ms = state.config.load().stale_if_error_secs as i64 * 1000;
     let now = chrono::Utc::now().timestamp_millis();
-    now - presence.timestamp_ms > PRESENCE_TTL_MS
+    grace_ms > 0
+        && !state.gateway.connected()
+        && now - presence.timestamp_ms <= state.config.load().presence_ttl_ms() + grace_ms
 }
 
-fn validate_user_id(user_id: &str) -> bool {
-    user_id.len() <= 20 && user_id.chars().all(|c| c.is_ascii_digit())
+/// Whether the user has been idle for longer than `IDLE_AS_OFFLINE_SECS`, in
+/// which case every route and stream treats the presence as expired.
+fn idle_as_offline(config: &config::Config, presence: &PresenceData) -> bool {
+    let limit_ms = config.idle_as_offline_secs as i64 * 1000;
+    let now = chrono::Utc::now().timestamp_millis();
+    limit_ms > 0
+        && presence
+            .idle_since_ms
+            .is_some_and(|since| now - since > limit_ms)
 }
 
-async fn get_presence_handler(user_id: String, state: AppState) -> Result<impl Reply, Rejection> {
-    if !validate_user_id(&user_id) {
-        return Ok(warp::reply::with_status(
-            warp::reply::json(&serde_json::json!({"error": "invalid user id"})),
-            StatusCode::BAD_REQUEST,
-        ));
+/// Serializes a presence that is past its TTL, flagged `"stale": true`.
+fn stale_presence_json(presence: &PresenceData) -> serde_json::Value {
+    let mut value = serde_json::to_value(presence).unwrap_or_default();
+    if let Some(obj) = value.as_object_mut() {
+        obj.insert("stale".to_string(), serde_json::Value::Bool(true));
     }
+    value
+}
 
-    if let Some(presence) = state.cache.get(&user_id).await {
-        if !is_presence_stale(&presence) {
-            return Ok(warp::reply::with_status(
-                warp::reply::json(&presence),
-                StatusCode::OK,
-            ));
-        }
-        state.cache.remove(&user_id).await;
+/// Unwraps `<@id>` / `<@!id>` mentions (raw or percent-encoded) to the bare id.
+/// Anything else is returned untouched and left for `validate_user_id` to judge.
+fn normalize_user_id(raw: String) -> String {
+    let decoded = raw
+        .replace("%3C", "<")
+        .replace("%3c", "<")
+        .replace("%40", "@")
+        .replace("%21", "!")
+        .replace("%3E", ">")
+        .replace("%3e", ">");
+
+    decoded
+        .strip_prefix("<@")
+        .and_then(|s| s.strip_suffix('>'))
+        .map(|s| s.strip_prefix('!').unwrap_or(s).to_string())
+        .unwrap_or(raw)
+}
+
+/// Parses a Discord user id, a snowflake: a non-zero `u64` written without
+/// leading zeros. The error says what's wrong with it.
+fn parse_user_id(user_id: &str) -> Result<u64, String> {
+    if user_id.is_empty() || !user_id.bytes().all(|b| b.is_ascii_digit()) {
+        return Err("invalid user id: expected digits only".to_string());
     }
-    Ok(warp::reply::with_status(
-        warp::reply::json(&serde_json::json!({"error": "User not found"})),
-        StatusCode::NOT_FOUND,
-    ))
+    if user_id.bytes().all(|b| b == b'0') {
+        return Err("invalid user id: can't be 0".to_string());
+    }
+    if user_id.starts_with('0') {
+        return Err("invalid user id: leading zeros aren't allowed".to_string());
+    }
+    user_id
+        .parse()
+        .map_err(|_| "invalid user id: too large for a Discord id".to_string())
 }
 
-async fn user_in_server_handler(user_id: String, state: AppState) -> Result<impl Reply, Rejection> {
-    let uid = match user_id.parse::<u64>() {
-        Ok(v) => v,
-        Err(_) => {
-            return Ok(warp::reply::with_status(
-                warp::reply::json(&serde_json::json!({ "error": "invalid user id" })),
-                StatusCode::BAD_REQUEST,
-            ));
-        }
-    };
+fn validate_user_id(user_id: &str) -> bool {
+    parse_user_id(user_id).is_ok()
+}
 
-    match discord::is_member(&state.http, state.guild_id, uid).await {
-        Ok(in_server) => Ok(warp::reply::with_status(
-            warp::reply::json(&serde_json::json!({ "in_server": in_server })),
-            StatusCode::OK,
-        )),
-        Err(e) => Ok(warp::reply::with_status(
-            warp::reply::json(&serde_json::json!({ "error": e })),
-            StatusCode::INTERNAL_SERVER_ERROR,
-        )),
+/// The machine-readable `code` of an error, the same on every route and in
+/// WebSocket error messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+enum ErrorCode {
+    InvalidUserId,
+    InvalidUserIds,
+    InvalidTimezone,
+    InvalidImageHash,
+    InvalidConfig,
+    InvalidBody,
+    InvalidQuery,
+    InvalidHeader,
+    InvalidMessage,
+    UnknownFields,
+    BatchTooLarge,
+    MinSeqNotReached,
+    Unauthorized,
+    NotAMember,
+    UserNotFound,
+    ImageNotFound,
+    NotFound,
+    MethodNotAllowed,
+    FeatureDisabled,
+    GuildUnavailable,
+    RateLimited,
+    TooManyConnections,
+    TooManySubscriptions,
+    WatcherLimit,
+    SubscriberLimit,
+    UpstreamError,
+    InternalError,
+}
+
+/// `{"code": ..., "message": ...}` plus the fields of `details`, if any.
+fn error_object(code: ErrorCode, message: &str, details: serde_json::Value) -> serde_json::Value {
+    let mut error = serde_json::json!({"code": code, "message": message});
+    if let (Some(error), serde_json::Value::Object(details)) = (error.as_object_mut(), details) {
+        error.extend(details);
     }
+    error
 }
 
-fn with_state(state: AppState) -> impl Filter<Extract = (AppState,), Error = Infallible> + Clone {
-    warp::any().map(move || state.clone())
+/// An `{"error": {"code": ..., "message": ...}}` reply.
+fn error_reply(
+    status: StatusCode,
+    code: ErrorCode,
+    message: &str,
+) -> warp::reply::WithStatus<warp::reply::Json> {
+    error_reply_with(status, code, message, serde_json::Value::Null)
 }
 
-fn extract_client_ip() -> impl Filter<Extract = (IpAddr,), Error = Rejection> + Clone {
-    warp::header::optional::<String>("cf-connecting-ip").map(|cf_ip: Option<String>| {
-        cf_ip
-            .and_then(|ip| ip.parse().ok())
-            .unwrap_or_else(|| IpAddr::from([127, 0, 0, 1]))
-    })
+/// [`error_reply`] with extra fields in the error object, e.g. the ids that
+/// were rejected.
+fn error_reply_with(
+    status: StatusCode,
+    code: ErrorCode,
+    message: &str,
+    details: serde_json::Value,
+) -> warp::reply::WithStatus<warp::reply::Json> {
+    let body = serde_json::json!({"error": error_object(code, message, details)});
+    warp::reply::with_status(warp::reply::json(&body), status)
 }
 
-struct ConnectionGuard {
-    connections: ConnectionCounter,
-    ip: IpAddr,
+/// Whether `user_id` may be looked up: always with `REQUIRE_MEMBERSHIP` off,
+/// else only for members of the guild.
+async fn membership_allowed(state: &AppState, user_id: &str) -> Result<bool, String> {
+    if !state.config.load().require_membership {
+        return Ok(true);
+    }
+    let Ok(uid) = user_id.parse::<u64>() else {
+        return Ok(true);
+    };
+    state
+        .membership
+        .is_member_of_any(&state.http, &state.gateway.reachable_guilds(), uid)
+        .await
 }
 
-impl Drop for ConnectionGuard {
-    fn drop(&mut self) {
-        if let dashmap::mapref::entry::Entry::Occupied(mut entry) = self.connections.entry(self.ip)
-        {
-            *entry.get_mut() -= 1;
-            if *entry.get() == 0 {
-                entry.remove();
+/// With `REQUIRE_MEMBERSHIP` on, returns the error reply for users that aren't
+/// (or can't be confirmed to be) members of the guild.
+async fn membership_gate(
+    state: &AppState,
+    user_id: &str,
+) -> Option<warp::reply::WithStatus<warp::reply::Json>> {
+    match membership_allowed(state, user_id).await {
+        Ok(true) => None,
+        Ok(false) => Some(error_reply(
+            StatusCode::FORBIDDEN,
+            ErrorCode::NotAMember,
+            "user is not a member of the guild",
+        )),
+        Err(e) => Some(internal_error(state, "membership check failed", &e)),
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct PresenceQuery {
+    /// IANA timezone to render ISO 8601 timestamps in, e.g. `Europe/Berlin`.
+    tz: Option<String>,
+    /// Overrides `FLATTEN_SPOTIFY` for this request.
+    flatten: Option<String>,
+    /// Wait for at least this `seq` before answering, see `wait_for_seq`.
+    min_seq: Option<u64>,
+}
+
+/// How long `?min_seq=` waits for the cache to catch up before giving up.
+const MIN_SEQ_WAIT: Duration = Duration::from_secs(2);
+const MIN_SEQ_POLL: Duration = Duration::from_millis(100);
+
+/// Waits until the cached presence has reached `min_seq`, for clients moving
+/// between instances that mustn't see an older update than they already saw.
+/// Wakes on local updates and polls the cache for ones written by other
+/// instances. False once `MIN_SEQ_WAIT` passes.
+async fn wait_for_seq(state: &AppState, user_id: &str, min_seq: u64) -> bool {
+    let mut rx = state.watchers.get(user_id).map(|w| w.subscribe());
+    let deadline = Instant::now() + MIN_SEQ_WAIT;
+
+    loop {
+        let seq = state.cache.get(user_id).await.map(|p| p.seq);
+        if seq.is_some_and(|seq| seq >= min_seq) {
+            return true;
+        }
+        if Instant::now() >= deadline {
+            return false;
+        }
+
+        let poll = tokio::time::sleep_until(deadline.min(Instant::now() + MIN_SEQ_POLL));
+        if let Some(watch) = &mut rx {
+            let closed = tokio::select! {
+                changed = watch.changed() => changed.is_err(),
+                _ = poll => false,
+            };
+            if closed {
+                rx = None;
             }
+        } else {
+            poll.await;
         }
     }
 }
 
-fn try_acquire_connection(connections: &ConnectionCounter, ip: IpAddr) -> Option<ConnectionGuard> {
-    let mut entry = connections.entry(ip).or_insert(0);
-    if *entry >= MAX_CONNECTIONS_PER_IP {
-        return None;
+/// Reads a `1`/`0` style query flag, `None` when absent or unrecognized.
+fn query_flag(value: Option<&str>) -> Option<bool> {
+    match value? {
+        "1" | "true" => Some(true),
+        "0" | "false" => Some(false),
+        _ => None,
     }
-    *entry += 1;
-    drop(entry);
+}
 
-    Some(ConnectionGuard {
-        connections: connections.clone(),
-        ip,
-    })
+/// Lifts the fields of `spotify` to the top level of a serialized presence,
+/// dropping the `spotify` key itself.
+fn flatten_spotify(body: &mut serde_json::Value) {
+    let Some(obj) = body.as_object_mut() else {
+        return;
+    };
+    if let Some(serde_json::Value::Object(spotify)) = obj.remove("spotify") {
+        obj.extend(spotify);
+    }
 }
 
-struct WatcherGuard {
-    watchers: UserWatchers,
-    memory_cache: Arc<DashMap<String, PresenceData>>,
-    user_id: String,
+/// Adds ISO 8601 renderings of the epoch ms timestamps, in `tz`, next to them.
+fn add_local_timestamps(body: &mut serde_json::Value, presence: &PresenceData, tz: chrono_tz::Tz) {
+    let iso = |ms: i64| {
+        chrono::DateTime::from_timestamp_millis(ms).map(|t| {
+            t.with_timezone(&tz)
+                .to_rfc3339_opts(chrono::SecondsFormat::Millis, false)
+        })
+    };
+
+    body["timestamp"] = serde_json::json!(iso(presence.timestamp_ms));
+    if let Some(spotify) = &presence.spotify
+        && let Some(obj) = body["spotify"].as_object_mut()
+    {
+        obj.insert(
+            "started_at".to_string(),
+            serde_json::json!(spotify.started_at_ms.and_then(iso)),
+        );
+        obj.insert(
+            "ends_at".to_string(),
+            serde_json::json!(spotify.ends_at_ms.and_then(iso)),
+        );
+    }
 }
 
-impl Drop for WatcherGuard {
-    fn drop(&mut self) {
-        if let Some(watcher) = self.watchers.get(&self.user_id)
-            && watcher.receiver_count() == 0
-        {
-            drop(watcher);
-            self.watchers.remove(&self.user_id);
-            self.memory_cache.remove(&self.user_id);
+/// Adds where playback is right now, so clients don't have to work it out
+/// from the timestamps against their own, possibly skewed, clock.
+/// `should_refresh` is set once the track should have ended, i.e. the next
+/// update is overdue.
+fn add_progress(body: &mut serde_json::Value, presence: &PresenceData, now_ms: i64) {
+    if let Some(spotify) = &presence.spotify
+        && let Some(obj) = body["spotify"].as_object_mut()
+    {
+        obj.insert(
+            "progress_ms".to_string(),
+            serde_json::json!(spotify.progress_ms(now_ms)),
+        );
+        obj.insert(
+            "duration_ms".to_string(),
+            serde_json::json!(spotify.duration_ms()),
+        );
+        let remaining = spotify.remaining_ms(now_ms);
+        obj.insert("remaining_ms".to_string(), serde_json::json!(remaining));
+        obj.insert(
+            "should_refresh".to_string(),
+            serde_json::json!(remaining == Some(0)),
+        );
+    }
+}
+
+async fn get_presence_handler(
+    user_id: String,
+    query: PresenceQuery,
+    authorization: Option<String>,
+    state: AppState,
+) -> Result<impl Reply, Rejection> {
+    let user_id = normalize_user_id(user_id);
+    if let Err(e) = parse_user_id(&user_id) {
+        return Ok(error_reply(
+            StatusCode::BAD_REQUEST,
+            ErrorCode::InvalidUserId,
+            &e,
+        ));
+    }
+    let tz = match query.tz.as_deref().map(str::parse::<chrono_tz::Tz>) {
+        None => None,
+        Some(Ok(tz)) => Some(tz),
+        Some(Err(_)) => {
+            return Ok(error_reply(
+                StatusCode::BAD_REQUEST,
+                ErrorCode::InvalidTimezone,
+                "invalid timezone",
+            ));
+        }
+    };
+    if let Some(reply) = membership_gate(&state, &user_id).await {
+        return Ok(reply);
+    }
+    state.register_interest(&user_id);
+    if let Some(min_seq) = query.min_seq
+        && !wait_for_seq(&state, &user_id, min_seq).await
+    {
+        return Ok(error_reply(
+            StatusCode::TOO_EARLY,
+            ErrorCode::MinSeqNotReached,
+            "presence hasn't reached min_seq yet",
+        ));
+    }
+
+    if let Some((presence, mut body)) = servable_presence(&state, &user_id).await {
+        state.metrics.cache_hits.inc();
+        if let Some(tz) = tz {
+            add_local_timestamps(&mut body, &presence, tz);
+        }
+        let flatten = query_flag(query.flatten.as_deref())
+            .unwrap_or_else(|| state.config.load().flatten_spotify);
+        if flatten {
+            flatten_spotify(&mut body);
         }
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&body),
+            StatusCode::OK,
+        ));
     }
+    state.metrics.cache_misses.inc();
+    let authenticated = has_api_key(&state, authorization.as_deref());
+    Ok(no_presence_reply(&state, &user_id, authenticated).await)
 }
 
-async fn ws_send_with_timeout(
-    ws_tx: &mut futures_util::stream::SplitSink<WebSocket, Message>,
-    msg: Message,
-) -> bool {
-    matches!(timeout(WS_SEND_TIMEOUT, ws_tx.send(msg)).await, Ok(Ok(_)))
+/// The cached presence and its JSON, with Spotify progress, if it may be
+/// served: fresh, or stale within `STALE_IF_ERROR_SECS`. A presence that can't
+/// be served anymore is dropped from the cache.
+async fn servable_presence(
+    state: &AppState,
+    user_id: &str,
+) -> Option<(PresenceData, serde_json::Value)> {
+    let presence = state
+        .cache
+        .get(user_id)
+        .await
+        .filter(|p| !idle_as_offline(&state.config.load(), p))?;
+    let mut body = if !is_presence_stale(&state.config.load(), &presence) {
+        serde_json::to_value(&presence).unwrap_or_default()
+    } else if serve_stale_if_error(state, &presence) {
+        stale_presence_json(&presence)
+    } else {
+        state.cache.remove(user_id).await;
+        return None;
+    };
+    add_progress(&mut body, &presence, chrono::Utc::now().timestamp_millis());
+    Some((presence, body))
 }
 
-async fn ws_handler(ws: WebSocket, user_id: String, state: AppState, _conn_guard: ConnectionGuard) {
-    let rx = state
-        .watchers
-        .entry(user_id.clone())
-        .or_insert_with(|| watch::channel(None).0)
-        .subscribe();
+/// `GET /v1/{id}/spotify`: just the `spotify` block of `GET /v1/{id}`, 204
+/// while the user is around but not listening.
+async fn spotify_handler(
+    user_id: String,
+    state: AppState,
+) -> Result<warp::reply::Response, Rejection> {
+    let user_id = normalize_user_id(user_id);
+    if let Err(e) = parse_user_id(&user_id) {
+        return Ok(
+            error_reply(StatusCode::BAD_REQUEST, ErrorCode::InvalidUserId, &e).into_response(),
+        );
+    }
+    if let Some(reply) = membership_gate(&state, &user_id).await {
+        return Ok(reply.into_response());
+    }
+    state.register_interest(&user_id);
 
-    let _watcher_guard = WatcherGuard {
-        watchers: state.watchers.clone(),
-        memory_cache: state.cache.get_memory(),
-        user_id: user_id.clone(),
+    let Some((_, mut body)) = servable_presence(&state, &user_id).await else {
+        state.metrics.cache_misses.inc();
+        return Ok(error_reply(
+            StatusCode::NOT_FOUND,
+            ErrorCode::UserNotFound,
+            "user not found",
+        )
+        .into_response());
     };
+    state.metrics.cache_hits.inc();
+    match body["spotify"].take() {
+        serde_json::Value::Null => Ok(StatusCode::NO_CONTENT.into_response()),
+        spotify => Ok(warp::reply::json(&spotify).into_response()),
+    }
+}
 
-    let (mut ws_tx, mut ws_rx) = ws.split();
+/// Reply for a user without a fresh presence. With the `API_KEY`, offline for
+/// guild members and 404 for anyone else. Everyone else gets offline without a
+/// lookup, so the route can't be used to probe the guild's members or to make
+/// Discord API calls.
+async fn no_presence_reply(
+    state: &AppState,
+    user_id: &str,
+    authenticated: bool,
+) -> warp::reply::WithStatus<warp::reply::Json> {
+    let not_found = || {
+        error_reply(
+            StatusCode::NOT_FOUND,
+            ErrorCode::UserNotFound,
+            "user not found",
+        )
+    };
+    let offline = || {
+        warp::reply::with_status(
+            warp::reply::json(
+                &serde_json::json!({"user_id": user_id, "online": false, "spotify": null}),
+            ),
+            StatusCode::OK,
+        )
+    };
+    let Ok(uid) = user_id.parse::<u64>() else {
+        return not_found();
+    };
+    if !authenticated {
+        return offline();
+    }
+    if let Some(reply) = guild_missing(state) {
+        return reply;
+    }
 
-    if let Some(presence) = state.cache.get(&user_id).await
-        && !is_presence_stale(&presence)
-        && let Ok(payload) = serde_json::to_string(&presence)
+    match state
+        .membership
+        .is_member_of_any(&state.http, &state.gateway.reachable_guilds(), uid)
+        .await
     {
-        let _ = ws_send_with_timeout(&mut ws_tx, Message::text(payload)).await;
+        Ok(true) => offline(),
+        Ok(false) => not_found(),
+        Err(e) => internal_error(state, "membership check failed", &e),
     }
+}
 
-    ws_loop(&mut ws_tx, &mut ws_rx, rx).await;
+#[derive(Debug, Default, Deserialize)]
+struct TextQuery {
+    /// Template for the line, see [`text::render`].
+    format: Option<String>,
 }
 
-async fn ws_loop(
-    ws_tx: &mut futures_util::stream::SplitSink<WebSocket, Message>,
-    ws_rx: &mut futures_util::stream::SplitStream<WebSocket>,
-    mut rx: watch::Receiver<Option<PresenceData>>,
-) {
-    let mut ping_interval = interval_at(
-        Instant::now() + Duration::from_secs(25),
-        Duration::from_secs(25),
-    );
+/// `GET /v1/{id}/text`: the presence as a one-line `text/plain` status.
+async fn text_presence_handler(
+    user_id: String,
+    query: TextQuery,
+    state: AppState,
+) -> Result<warp::reply::Response, Rejection> {
+    let user_id = normalize_user_id(user_id);
+    if let Err(e) = parse_user_id(&user_id) {
+        return Ok(
+            warp::reply::with_status(format!("{e}\n"), StatusCode::BAD_REQUEST).into_response(),
+        );
+    }
+    if query
+        .format
+        .as_ref()
+        .is_some_and(|f| f.len() > text::MAX_FORMAT_LEN)
+    {
+        return Ok(
+            warp::reply::with_status("format too long\n", StatusCode::BAD_REQUEST).into_response(),
+        );
+    }
+    if let Some(reply) = membership_gate(&state, &user_id).await {
+        return Ok(reply.into_response());
+    }
+    state.register_interest(&user_id);
 
-    loop {
-        tokio::select! {
-            _ = ping_interval.tick() => {
-                if !ws_send_with_timeout(ws_tx, Message::ping(Vec::new())).await {
-                    break;
-                }
-            }
+    let presence = servable_presence(&state, &user_id)
+        .await
+        .map(|(presence, _)| presence);
+    let line = match presence {
+        Some(presence) => text::render(
+            &presence,
+            query.format.as_deref(),
+            chrono::Utc::now().timestamp_millis(),
+        ),
+        None => state.config.load().text_no_presence.clone(),
+    };
+    Ok(warp::reply::with_status(line + "\n", StatusCode::OK).into_response())
+}
 
-            incoming = ws_rx.next() => {
-                match incoming {
-                    Some(Ok(msg)) if msg.is_close() => break,
-                    Some(Ok(msg)) if msg.is_ping() => {
-                        let _ = ws_send_with_timeout(ws_tx, Message::pong(msg.into_bytes())).await;
-                    }
-                    Some(Err(_)) | None => break,
-                    _ => {}
-                }
-            }
+/// `GET /v1/me`: the presence of whoever the Discord OAuth2 bearer token
+/// belongs to.
+async fn me_handler(
+    authorization: Option<String>,
+    state: AppState,
+) -> Result<warp::reply::Response, Rejection> {
+    let unauthorized = || unauthorized().into_response();
 
-            result = rx.changed() => {
-                if result.is_err() {
-                    break;
-                }
-                let presence = rx.borrow_and_update().clone();
-                if let Some(ref p) = presence
-                    && !is_presence_stale(p)
-                    && let Ok(payload) = serde_json::to_string(p)
-                    && !ws_send_with_timeout(ws_tx, Message::text(payload)).await
-                {
-                    break;
-                }
-            }
+    let Some(token) = authorization
+        .as_deref()
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .map(str::trim)
+        .filter(|t| !t.is_empty())
+    else {
+        return Ok(unauthorized());
+    };
+
+    match state.oauth_users.resolve(token).await {
+        Ok(Some(user_id)) => {
+            get_presence_handler(user_id.to_string(), PresenceQuery::default(), None, state)
+                .await
+                .map(Reply::into_response)
+        }
+        Ok(None) => Ok(unauthorized()),
+        Err(discord::OAuthError::Throttled(retry_after)) => {
+            let retry_after = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+            let reply = error_reply(
+                StatusCode::SERVICE_UNAVAILABLE,
+                ErrorCode::RateLimited,
+                "too many token lookups, try again shortly",
+            );
+            Ok(
+                warp::reply::with_header(reply, "retry-after", retry_after.to_string())
+                    .into_response(),
+            )
+        }
+        Err(discord::OAuthError::Upstream(e)) => {
+            Ok(internal_error(&state, "failed to verify token", &e).into_response())
         }
     }
 }
 
-mod discord;
-mod redis;
+/// QR codes only encode the id, so they can be cached for a long time.
+const QR_CACHE_CONTROL: &str = "public, max-age=86400";
 
-#[tokio::main]
-async fn main() {
-    let _ = dotenvy::dotenv();
-    let env_filter = tracing_subscriber::EnvFilter::try_from_default_env()
-        .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info"));
-    tracing_subscriber::fmt().with_env_filter(env_filter).init();
+/// `GET /v1/{id}/qr`: an SVG QR code of `QR_URL_TEMPLATE` for the user.
+async fn qr_handler(user_id: String, state: AppState) -> Result<warp::reply::Response, Rejection> {
+    let user_id = normalize_user_id(user_id);
+    if let Err(e) = parse_user_id(&user_id) {
+        return Ok(
+            error_reply(StatusCode::BAD_REQUEST, ErrorCode::InvalidUserId, &e).into_response(),
+        );
+    }
+    let Some(template) = state.config.load().qr_url_template.clone() else {
+        return Ok(error_reply(
+            StatusCode::NOT_FOUND,
+            ErrorCode::FeatureDisabled,
+            "qr codes are not configured",
+        )
+        .into_response());
+    };
 
-    let redis_available = redis::wait_for_redis(Duration::from_secs(10)).await;
-    if !redis_available {
-        warn!("redis not available after 10s, using in-memory cache");
+    let url = template.replace("{user_id}", &user_id);
+    let svg = match qrcode::QrCode::new(url.as_bytes()) {
+        Ok(code) => code
+            .render::<qrcode::render::svg::Color>()
+            .min_dimensions(256, 256)
+            .build(),
+        Err(e) => {
+            return Ok(
+                internal_error(&state, "failed to encode qr code", &e.to_string()).into_response(),
+            );
+        }
+    };
+
+    let reply = warp::reply::with_header(svg, "content-type", "image/svg+xml");
+    Ok(warp::reply::with_header(reply, "cache-control", QR_CACHE_CONTROL).into_response())
+}
+
+/// `GET /v1/art/{hash}`: album art proxied from Spotify, with `ALBUM_ART_PROXY`.
+async fn art_handler(hash: String, state: AppState) -> Result<warp::reply::Response, Rejection> {
+    let error = |status, code, message: &str| error_reply(status, code, message).into_response();
+    if !state.config.load().album_art_proxy {
+        return Ok(error(
+            StatusCode::NOT_FOUND,
+            ErrorCode::FeatureDisabled,
+            "album art proxy not enabled",
+        ));
+    }
+    if !art::is_image_hash(&hash) {
+        return Ok(error(
+            StatusCode::BAD_REQUEST,
+            ErrorCode::InvalidImageHash,
+            "invalid image hash",
+        ));
     }
 
-    let token = std::env::var("DISCORD_BOT_TOKEN").expect("DISCORD_BOT_TOKEN not set");
-    let guild_id: u64 = std::env::var("GUILD_ID")
-        .expect("GUILD_ID not set")
-        .parse()
-        .expect("GUILD_ID must be a valid u64");
+    match state.art.get(&hash).await {
+        Ok(art) => {
+            let reply = warp::http::Response::new(art.body);
+            let reply = warp::reply::with_header(reply, "content-type", art.content_type);
+            Ok(warp::reply::with_header(reply, "cache-control", art.cache_control).into_response())
+        }
+        Err(art::ArtError::NotFound) => Ok(error(
+            StatusCode::NOT_FOUND,
+            ErrorCode::ImageNotFound,
+            "image not found",
+        )),
+        Err(art::ArtError::Upstream(detail)) => {
+            warn!(hash = %hash, detail = %detail, "album art fetch failed");
+            Ok(error(
+                StatusCode::BAD_GATEWAY,
+                ErrorCode::UpstreamError,
+                "album art unavailable",
+            ))
+        }
+    }
+}
 
-    let http = Arc::new(SerenityHttp::new(&token));
-    let cache = Arc::new(redis::Cache::new());
-    let watchers: UserWatchers = Arc::new(DashMap::new());
-    let connections: ConnectionCounter = Arc::new(DashMap::new());
+/// A clear 503 for membership checks while the bot isn't in any `GUILD_IDS`
+/// guild, which Discord would otherwise answer with an unhelpful error.
+fn guild_missing(state: &AppState) -> Option<warp::reply::WithStatus<warp::reply::Json>> {
+    (state.gateway.in_guild() == Some(false)).then(|| {
+        error_reply(
+            StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::GuildUnavailable,
+            "bot is not in the configured guild",
+        )
+    })
+}
 
-    let state = AppState {
-        cache,
-        watchers,
-        connections,
-        http,
-        guild_id: GuildId::new(guild_id),
+async fn user_in_server_handler(
+    user_id: String,
+    authorization: Option<String>,
+    state: AppState,
+) -> Result<impl Reply, Rejection> {
+    if missing_api_key(&state, authorization.as_deref()) {
+        return Ok(unauthorized());
+    }
+    let user_id = normalize_user_id(user_id);
+    let uid = match parse_user_id(&user_id) {
+        Ok(v) => v,
+        Err(e) => {
+            return Ok(error_reply(
+                StatusCode::BAD_REQUEST,
+                ErrorCode::InvalidUserId,
+                &e,
+            ));
+        }
     };
 
-    let get_route = warp::path!("v1" / String)
-        .and(warp::get())
-        .and(with_state(state.clone()))
-        .and_then(get_presence_handler);
+    if let Some(reply) = guild_missing(&state) {
+        return Ok(reply);
+    }
 
-    let in_server_route = warp::path!("v1" / String / "in_server")
-        .and(warp::get())
-        .and(with_state(state.clone()))
-        .and_then(user_in_server_handler);
+    match state
+        .membership
+        .member_guilds(&state.http, &state.gateway.reachable_guilds(), uid)
+        .await
+    {
+        Ok(guilds) => Ok(warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({
+                "in_server": !guilds.is_empty(),
+                "guilds": guilds.iter().map(|id| id.to_string()).collect::<Vec<_>>(),
+            })),
+            StatusCode::OK,
+        )),
+        Err(e) => Ok(internal_error(&state, "membership check failed", &e)),
+    }
+}
 
-    let ws_route = warp::path!("ws" / "v1" / String)
-        .and(warp::ws())
-        .and(with_state(state.clone()))
-        .and(extract_client_ip())
-        .map(|user_id: String, ws: Ws, state: AppState, ip: IpAddr| {
-            ws.on_upgrade(move |socket| async move {
-                if !validate_user_id(&user_id) {
-                    return;
-                }
-                match try_acquire_connection(&state.connections, ip) {
-                    Some(guard) => ws_handler(socket, user_id, state, guard).await,
-                    None => {
-                        warn!(ip = %ip, "connection limit exceeded");
-                    }
+const MAX_BATCH_SIZE: usize = 100;
+
+#[derive(Deserialize)]
+struct BatchRequest {
+    user_ids: Vec<String>,
+}
+
+/// Normalizes and validates the ids of a batch request, deduplicating them.
+fn batch_user_ids(
+    request: BatchRequest,
+) -> Result<Vec<String>, warp::reply::WithStatus<warp::reply::Json>> {
+    if request.user_ids.len() > MAX_BATCH_SIZE {
+        return Err(error_reply(
+            StatusCode::BAD_REQUEST,
+            ErrorCode::BatchTooLarge,
+            &format!("at most {MAX_BATCH_SIZE} user ids per batch"),
+        ));
+    }
+
+    // check everything up front so the caller learns about every bad id at once
+    let mut user_ids = Vec::with_capacity(request.user_ids.len());
+    let mut invalid = Vec::new();
+    for raw in request.user_ids {
+        let user_id = normalize_user_id(raw.clone());
+        if validate_user_id(&user_id) {
+            user_ids.push(user_id);
+        } else if !invalid.contains(&raw) {
+            invalid.push(raw);
+        }
+    }
+    if !invalid.is_empty() {
+        return Err(error_reply_with(
+            StatusCode::BAD_REQUEST,
+            ErrorCode::InvalidUserIds,
+            "invalid user ids",
+            serde_json::json!({"invalid": invalid}),
+        ));
+    }
+    user_ids.sort_unstable();
+    user_ids.dedup();
+    Ok(user_ids)
+}
+
+/// What `GET /v1/{id}` would serve, for the routes that look up many users at
+/// once. Users `REQUIRE_MEMBERSHIP` hides come back as `None`, like users
+/// without a presence. Marks the user as requested, as `GET /v1/{id}` does.
+async fn lookup_presence(
+    state: &AppState,
+    user_id: &str,
+) -> Option<(PresenceData, serde_json::Value)> {
+    if membership_gate(state, user_id).await.is_some() {
+        return None;
+    }
+    state.register_interest(user_id);
+    servable_presence(state, user_id).await
+}
+
+async fn batch_presence_handler(
+    request: BatchRequest,
+    state: AppState,
+) -> Result<impl Reply, Rejection> {
+    let user_ids = match batch_user_ids(request) {
+        Ok(ids) => ids,
+        Err(reply) => return Ok(reply),
+    };
+
+    let concurrency = state.config.load().batch_concurrency;
+    let presences: serde_json::Map<String, serde_json::Value> =
+        futures_util::stream::iter(user_ids)
+            .map(|user_id| {
+                let state = state.clone();
+                async move {
+                    let value = lookup_presence(&state, &user_id)
+                        .await
+                        .map_or(serde_json::Value::Null, |(_, body)| body);
+                    (user_id, value)
                 }
             })
-        });
+            .buffer_unordered(concurrency)
+            .collect()
+            .await;
 
-    let root = warp::path::end().and(warp::get()).map(|| {
-        warp::reply::json(&serde_json::json!({
-            "endpoints": [
-                {"method": "GET", "path": "/v1/{userid}"},
-                {"method": "WS",  "path": "/ws/v1/{userid}"},
-                {"method": "GET", "path": "/v1/{userid}/in_server"},
-                {"method": "GET", "path": "/health"}
-            ]
-        }))
-    });
+    Ok(warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({ "presences": presences })),
+        StatusCode::OK,
+    ))
+}
 
-    let health_route = warp::path!("health").and(warp::get()).map(|| {
-        warp::reply::json(&serde_json::json!({
-            "status": "ok",
-            "redis": redis::is_redis_available()
-        }))
-    });
+/// Fields `POST /v1/query` can project to.
+const QUERY_FIELDS: &[&str] = &[
+    "user_id",
+    "status",
+    "spotify",
+    "game",
+    "rich_presence",
+    "custom_status",
+    "stage",
+    "client_status",
+    "primary_platform",
+    "username",
+    "display_name",
+    "avatar_url",
+    "idle_since_ms",
+    "first_seen_ms",
+    "timestamp_ms",
+    "seq",
+];
 
-    let routes = root
-        .or(health_route)
-        .or(get_route)
+#[derive(Deserialize)]
+struct QueryRequest {
+    user_ids: Vec<String>,
+    /// Only users currently listening to Spotify.
+    #[serde(default)]
+    require_listening: bool,
+    /// Only users that aren't offline.
+    #[serde(default)]
+    online_only: bool,
+    /// Fields to return per presence, all of them when absent.
+    fields: Option<Vec<String>>,
+}
+
+/// `POST /v1/query`: a batch lookup that also filters and projects, returning
+/// only the presences that match.
+async fn query_handler(request: QueryRequest, state: AppState) -> Result<impl Reply, Rejection> {
+    if let Some(fields) = &request.fields {
+        let unknown: Vec<&String> = fields
+            .iter()
+            .filter(|f| !QUERY_FIELDS.contains(&f.as_str()))
+            .collect();
+        if !unknown.is_empty() {
+            return Ok(error_reply_with(
+                StatusCode::BAD_REQUEST,
+                ErrorCode::UnknownFields,
+                "unknown fields",
+                serde_json::json!({"unknown": unknown}),
+            ));
+        }
+    }
+    let user_ids = match batch_user_ids(BatchRequest {
+        user_ids: request.user_ids,
+    }) {
+        Ok(ids) => ids,
+        Err(reply) => return Ok(reply),
+    };
+
+    let concurrency = state.config.load().batch_concurrency;
+    let presences: serde_json::Map<String, serde_json::Value> =
+        futures_util::stream::iter(user_ids)
+            .map(|user_id| {
+                let state = state.clone();
+                async move {
+                    let value = lookup_presence(&state, &user_id).await;
+                    (user_id, value)
+                }
+            })
+            .buffer_unordered(concurrency)
+            .filter_map(|(user_id, value)| {
+                let matched = value.and_then(|(presence, mut body)| {
+                    if (request.require_listening && presence.spotify.is_none())
+                        || (request.online_only && presence.status == OnlineStatus::Offline)
+                    {
+                        return None;
+                    }
+                    if let Some(fields) = &request.fields {
+                        let obj = body.as_object_mut()?;
+                        // the stale flag isn't a field, it always comes along
+                        obj.retain(|k, _| k == "stale" || fields.contains(k));
+                    }
+                    Some((user_id, body))
+                });
+                std::future::ready(matched)
+            })
+            .collect()
+            .await;
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({ "presences": presences })),
+        StatusCode::OK,
+    ))
+}
+
+async fn batch_in_server_handler(
+    request: BatchRequest,
+    authorization: Option<String>,
+    state: AppState,
+) -> Result<impl Reply, Rejection> {
+    if missing_api_key(&state, authorization.as_deref()) {
+        return Ok(unauthorized());
+    }
+    let user_ids = match batch_user_ids(request) {
+        Ok(ids) => ids,
+        Err(reply) => return Ok(reply),
+    };
+    if let Some(reply) = guild_missing(&state) {
+        return Ok(reply);
+    }
+
+    let concurrency = state.config.load().batch_concurrency;
+    let results: serde_json::Map<String, serde_json::Value> = futures_util::stream::iter(user_ids)
+        .map(|user_id| {
+            let state = state.clone();
+            async move {
+                let in_server = match user_id.parse::<u64>() {
+                    Ok(uid) => state.membership.is_member_of_any(
+                        &state.http,
+                        &state.gateway.reachable_guilds(),
+                        uid,
+                    )
+                    .await
+                    .inspect_err(
+                        |e| warn!(user_id = %user_id, error = %e, "batch membership check failed"),
+                    )
+                    .ok(),
+                    Err(_) => None,
+                };
+                (user_id, serde_json::json!(in_server))
+            }
+        })
+        .buffer_unordered(concurrency)
+        .collect()
+        .await;
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({ "in_server": results })),
+        StatusCode::OK,
+    ))
+}
+
+/// Logs `detail` and returns a 500 that only includes it when
+/// `ERROR_VERBOSITY=detailed`.
+fn internal_error(
+    state: &AppState,
+    message: &str,
+    detail: &str,
+) -> warp::reply::WithStatus<warp::reply::Json> {
+    error!(detail, "{}", message);
+
+    let details = match state.config.load().error_verbosity {
+        config::ErrorVerbosity::Minimal => serde_json::Value::Null,
+        config::ErrorVerbosity::Detailed => serde_json::json!({ "detail": detail }),
+    };
+    error_reply_with(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        ErrorCode::InternalError,
+        message,
+        details,
+    )
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Checks an `Authorization: Bearer <key>` header against `API_KEY`. Always
+/// false when no key is configured.
+fn has_api_key(state: &AppState, authorization: Option<&str>) -> bool {
+    let config = state.config.load();
+    match (config.api_key.as_deref(), authorization) {
+        (Some(key), Some(header)) => header
+            .strip_prefix("Bearer ")
+            .is_some_and(|given| constant_time_eq(given.trim().as_bytes(), key.as_bytes())),
+        _ => false,
+    }
+}
+
+/// Whether a request to an endpoint that is open only while `API_KEY` is unset
+/// (the membership lookups) has to be turned away.
+fn missing_api_key(state: &AppState, authorization: Option<&str>) -> bool {
+    state.config.load().api_key.is_some() && !has_api_key(state, authorization)
+}
+
+fn unauthorized() -> warp::reply::WithStatus<warp::reply::Json> {
+    error_reply(
+        StatusCode::UNAUTHORIZED,
+        ErrorCode::Unauthorized,
+        "unauthorized",
+    )
+}
+
+async fn reload_config_handler(
+    authorization: Option<String>,
+    state: AppState,
+) -> Result<impl Reply, Rejection> {
+    if !has_api_key(&state, authorization.as_deref()) {
+        return Ok(unauthorized());
+    }
+
+    match config::Config::reload() {
+        Ok(next) => {
+            let (next, changed) = state.config.load().reloaded(next);
+            state.config.store(Arc::new(next));
+            info!(?changed, "configuration reloaded");
+            Ok(warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({ "changed": changed })),
+                StatusCode::OK,
+            ))
+        }
+        Err(e) => {
+            warn!(error = %e, "configuration reload failed, keeping current config");
+            Ok(error_reply(
+                StatusCode::BAD_REQUEST,
+                ErrorCode::InvalidConfig,
+                &e,
+            ))
+        }
+    }
+}
+
+/// Drops a user's cached presence, from memory and Redis, and closes the
+/// streams watching them, e.g. after a user asked to be forgotten.
+async fn evict_handler(
+    user_id: String,
+    authorization: Option<String>,
+    state: AppState,
+) -> Result<warp::reply::Response, Rejection> {
+    if !has_api_key(&state, authorization.as_deref()) {
+        return Ok(unauthorized().into_response());
+    }
+    let user_id = normalize_user_id(user_id);
+    if let Err(e) = parse_user_id(&user_id) {
+        return Ok(
+            error_reply(StatusCode::BAD_REQUEST, ErrorCode::InvalidUserId, &e).into_response(),
+        );
+    }
+    if state.cache.get(&user_id).await.is_none() {
+        return Ok(error_reply(
+            StatusCode::NOT_FOUND,
+            ErrorCode::UserNotFound,
+            "no cached presence for this user",
+        )
+        .into_response());
+    }
+
+    state.cache.remove(&user_id).await;
+    evict_watcher(&state.watchers, &user_id);
+    redis::publish_eviction(&state.config.load(), &user_id).await;
+    info!(user_id, "presence evicted");
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+/// Ready once the gateway is connected and, when `REDIS_URL` is set, Redis
+/// answers.
+async fn readyz_handler(state: AppState) -> Result<impl Reply, Rejection> {
+    let gateway = state.gateway.connected();
+    let redis = if std::env::var_os("REDIS_URL").is_some() {
+        Some(redis::ping().await)
+    } else {
+        None
+    };
+
+    let ready = gateway && redis.unwrap_or(true);
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    Ok(warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({
+            "ready": ready,
+            "gateway": gateway,
+            "redis": redis,
+        })),
+        status,
+    ))
+}
+
+#[derive(Deserialize)]
+struct TopStatsQuery {
+    days: Option<u32>,
+    limit: Option<usize>,
+}
+
+async fn top_stats_handler(query: TopStatsQuery, state: AppState) -> Result<impl Reply, Rejection> {
+    if !state.config.load().analytics_enabled || !redis::is_redis_available() {
+        return Ok(error_reply(
+            StatusCode::NOT_FOUND,
+            ErrorCode::FeatureDisabled,
+            "analytics not enabled",
+        ));
+    }
+
+    let days = query.days.unwrap_or(7).clamp(1, analytics::MAX_WINDOW_DAYS);
+    let limit = query.limit.unwrap_or(10).clamp(1, analytics::MAX_LIMIT);
+    let max_age = Duration::from_secs(state.config.load().stats_cache_secs);
+
+    match state
+        .top_stats
+        .get(&state.config.load(), days, limit, max_age)
+        .await
+    {
+        Some(stats) => Ok(warp::reply::with_status(
+            warp::reply::json(&stats),
+            StatusCode::OK,
+        )),
+        None => Ok(internal_error(
+            &state,
+            "failed to read stats",
+            "redis unavailable",
+        )),
+    }
+}
+
+/// Any origin without credentials, or exactly `CORS_ORIGINS` with them.
+/// Preflights are answered for the methods and headers the API uses.
+fn cors(config: &config::Config) -> warp::cors::Cors {
+    let cors = warp::cors()
+        .allow_methods(["GET", "POST", "DELETE"])
+        .allow_headers(["content-type", "authorization"]);
+    match &config.cors_origins {
+        Some(origins) => cors
+            .allow_origins(origins.iter().map(String::as_str))
+            .allow_credentials(true),
+        None => cors.allow_any_origin(),
+    }
+    .build()
+}
+
+fn with_state(state: AppState) -> impl Filter<Extract = (AppState,), Error = Infallible> + Clone {
+    warp::any().map(move || state.clone())
+}
+
+/// The client address from `X-Forwarded-For` behind `hops` trusted proxies,
+/// each of which appended the address it received the request from. Entries
+/// further left were sent by the client and can't be trusted.
+fn forwarded_client_ip(forwarded_for: &str, hops: usize) -> Option<IpAddr> {
+    if hops == 0 {
+        return None;
+    }
+    forwarded_for
+        .rsplit(',')
+        .nth(hops - 1)
+        .and_then(|ip| ip.trim().parse().ok())
+}
+
+/// The client address: `CF-Connecting-IP`, else `X-Forwarded-For` behind
+/// `TRUSTED_PROXY_HOPS` proxies, else the address the connection came from.
+fn client_ip(
+    config: &config::Config,
+    cf_ip: Option<&str>,
+    forwarded_for: Option<&str>,
+    peer: Option<IpAddr>,
+) -> IpAddr {
+    cf_ip
+        .and_then(|ip| ip.parse().ok())
+        .or_else(|| {
+            forwarded_for.and_then(|xff| forwarded_client_ip(xff, config.trusted_proxy_hops))
+        })
+        .or(peer)
+        .map(|ip| ip.to_canonical())
+        .unwrap_or_else(|| IpAddr::from([127, 0, 0, 1]))
+}
+
+fn extract_client_ip(
+    config: config::SharedConfig,
+) -> impl Filter<Extract = (IpAddr,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("cf-connecting-ip")
+        .and(warp::header::optional::<String>("x-forwarded-for"))
+        .and(warp::ext::optional::<server::PeerAddr>())
+        .map(
+            move |cf_ip: Option<String>,
+                  forwarded_for: Option<String>,
+                  peer: Option<server::PeerAddr>| {
+                client_ip(
+                    &config.load(),
+                    cf_ip.as_deref(),
+                    forwarded_for.as_deref(),
+                    peer.map(|server::PeerAddr(addr)| addr.ip()),
+                )
+            },
+        )
+}
+
+const MAX_RATE_LIMITED_IPS: usize = 10_000;
+
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+/// A token bucket per client IP for the REST routes, see `RATE_LIMIT_BURST`.
+#[derive(Default)]
+struct RateLimiter(DashMap<IpAddr, Bucket>);
+
+impl RateLimiter {
+    /// Takes a token for `ip`, or returns how long until the next one. IPs
+    /// that would need a new bucket while all of them are still in use are
+    /// turned away like ones that ran out.
+    fn take(&self, ip: IpAddr, burst: u32, per_sec: u32) -> Result<(), Duration> {
+        if burst == 0 {
+            return Ok(());
+        }
+        let (burst, per_sec) = (f64::from(burst), f64::from(per_sec));
+        let now = Instant::now();
+        let refill = |bucket: &Bucket| {
+            let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+            (bucket.tokens + elapsed * per_sec).min(burst)
+        };
+
+        if !self.0.contains_key(&ip) && self.0.len() >= MAX_RATE_LIMITED_IPS {
+            // full buckets are the same as none
+            self.0.retain(|_, bucket| refill(bucket) < burst);
+            if self.0.len() >= MAX_RATE_LIMITED_IPS {
+                return Err(Duration::from_secs_f64(1.0 / per_sec));
+            }
+        }
+        let mut bucket = self.0.entry(ip).or_insert(Bucket {
+            tokens: burst,
+            refilled: now,
+        });
+        bucket.tokens = refill(&bucket);
+        bucket.refilled = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / per_sec))
+        }
+    }
+}
+
+#[derive(Debug)]
+struct RateLimited {
+    retry_after: Duration,
+}
+
+impl warp::reject::Reject for RateLimited {}
+
+/// Rejects with [`RateLimited`] once the client IP used up its bucket, turned
+/// into a 429 by [`rejection_reply`].
+fn rate_limit(state: AppState) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    extract_client_ip(state.config.clone())
+        .and_then(move |ip: IpAddr| {
+            let state = state.clone();
+            async move {
+                let config = state.config.load();
+                state
+                    .rate_limiter
+                    .take(ip, config.rate_limit_burst, config.rate_limit_per_sec)
+                    .map_err(|retry_after| warp::reject::custom(RateLimited { retry_after }))
+            }
+        })
+        .untuple_one()
+}
+
+/// Turns the rejections no route handled into the usual error body.
+async fn rejection_reply(rejection: Rejection) -> Result<warp::reply::Response, Infallible> {
+    if let Some(limited) = rejection.find::<RateLimited>() {
+        let retry_after = limited.retry_after.as_secs_f64().ceil().max(1.0) as u64;
+        let reply = error_reply(
+            StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::RateLimited,
+            "too many requests",
+        );
+        return Ok(
+            warp::reply::with_header(reply, "retry-after", retry_after.to_string()).into_response(),
+        );
+    }
+
+    let (status, code, message) = if rejection.is_not_found() {
+        (
+            StatusCode::NOT_FOUND,
+            ErrorCode::NotFound,
+            "not found".to_string(),
+        )
+    } else if let Some(e) = rejection.find::<warp::filters::body::BodyDeserializeError>() {
+        (
+            StatusCode::BAD_REQUEST,
+            ErrorCode::InvalidBody,
+            e.to_string(),
+        )
+    } else if let Some(e) = rejection.find::<warp::reject::InvalidQuery>() {
+        (
+            StatusCode::BAD_REQUEST,
+            ErrorCode::InvalidQuery,
+            e.to_string(),
+        )
+    } else if rejection.find::<warp::reject::MethodNotAllowed>().is_some() {
+        (
+            StatusCode::METHOD_NOT_ALLOWED,
+            ErrorCode::MethodNotAllowed,
+            "method not allowed".to_string(),
+        )
+    } else if let Some(e) = rejection.find::<warp::reject::MissingHeader>() {
+        (
+            StatusCode::BAD_REQUEST,
+            ErrorCode::InvalidHeader,
+            e.to_string(),
+        )
+    } else if let Some(e) = rejection.find::<warp::reject::InvalidHeader>() {
+        (
+            StatusCode::BAD_REQUEST,
+            ErrorCode::InvalidHeader,
+            e.to_string(),
+        )
+    } else if let Some(e) = rejection.find::<warp::reject::PayloadTooLarge>() {
+        (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorCode::InvalidBody,
+            e.to_string(),
+        )
+    } else if let Some(e) = rejection.find::<warp::reject::UnsupportedMediaType>() {
+        (
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ErrorCode::InvalidBody,
+            e.to_string(),
+        )
+    } else {
+        warn!(?rejection, "unhandled rejection");
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::InternalError,
+            "internal error".to_string(),
+        )
+    };
+    Ok(error_reply(status, code, &message).into_response())
+}
+
+struct ConnectionGuard {
+    connections: ConnectionCounter,
+    ip: IpAddr,
+    /// Counted in Redis too, see `acquire_connection`.
+    mirror: Option<redis::ConnectionMirror>,
+    /// Also holds one of the `MAX_TOTAL_CONNECTIONS` slots.
+    total: Option<Arc<AtomicUsize>>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        if let Some(mirror) = &self.mirror {
+            mirror.add(self.ip, -1);
+        }
+        if let Some(total) = &self.total {
+            total.fetch_sub(1, Ordering::Relaxed);
+        }
+        if let dashmap::mapref::entry::Entry::Occupied(mut entry) = self.connections.entry(self.ip)
+        {
+            *entry.get_mut() -= 1;
+            if *entry.get() == 0 {
+                entry.remove();
+            }
+        }
+    }
+}
+
+fn try_acquire_connection(
+    connections: &ConnectionCounter,
+    ip: IpAddr,
+    max_per_ip: usize,
+) -> Option<ConnectionGuard> {
+    let mut entry = connections.entry(ip).or_insert(0);
+    if *entry >= max_per_ip {
+        return None;
+    }
+    *entry += 1;
+    drop(entry);
+
+    Some(ConnectionGuard {
+        connections: connections.clone(),
+        ip,
+        mirror: None,
+        total: None,
+    })
+}
+
+/// Counts one more connection against `max` (0 for no limit), false when
+/// they're all taken.
+fn try_acquire_total(total: &AtomicUsize, max: usize) -> bool {
+    total
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |open| {
+            (max == 0 || open < max).then_some(open + 1)
+        })
+        .is_ok()
+}
+
+/// Which cap turned a connection away.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ConnectionLimit {
+    /// `MAX_CONNECTIONS_PER_IP` for the client's address.
+    PerIp,
+    /// `MAX_TOTAL_CONNECTIONS` across all clients.
+    Total,
+}
+
+/// How long clients turned away by a connection limit are asked to wait.
+const CONNECTION_RETRY_AFTER: Duration = Duration::from_secs(10);
+
+impl ConnectionLimit {
+    fn reason(self) -> &'static str {
+        match self {
+            ConnectionLimit::PerIp => "too many connections",
+            ConnectionLimit::Total => "server connection limit reached",
+        }
+    }
+
+    /// 429 (per IP) or 503 (in total) with `Retry-After`, so clients back off
+    /// instead of reconnecting right away.
+    fn reply(self) -> warp::reply::Response {
+        let status = match self {
+            ConnectionLimit::PerIp => StatusCode::TOO_MANY_REQUESTS,
+            ConnectionLimit::Total => StatusCode::SERVICE_UNAVAILABLE,
+        };
+        let reply = error_reply(status, ErrorCode::TooManyConnections, self.reason());
+        warp::reply::with_header(
+            reply,
+            "retry-after",
+            CONNECTION_RETRY_AFTER.as_secs().to_string(),
+        )
+        .into_response()
+    }
+
+    /// For a limit only hit after the upgrade, when the checks before it
+    /// raced with other connections: 1013 (try again later).
+    fn close(self) -> Message {
+        Message::close_with(1013u16, self.reason())
+    }
+}
+
+/// Whether a connection from `ip` would be turned away right now, checked
+/// before upgrading a WebSocket so the client gets a proper error response.
+fn connection_limit_reached(state: &AppState, ip: IpAddr) -> Option<ConnectionLimit> {
+    let config = state.config.load();
+    if config.max_total_connections > 0
+        && state.total_connections.load(Ordering::Relaxed) >= config.max_total_connections
+    {
+        return Some(ConnectionLimit::Total);
+    }
+    state
+        .connections
+        .get(&ip)
+        .is_some_and(|open| *open >= config.max_connections_per_ip)
+        .then_some(ConnectionLimit::PerIp)
+}
+
+/// Takes a connection slot for `ip` and one of the `MAX_TOTAL_CONNECTIONS`,
+/// also counting it in Redis with `MIRROR_CONNECTION_COUNTS`. Only the
+/// in-memory counts enforce the limits.
+fn acquire_connection(state: &AppState, ip: IpAddr) -> Result<ConnectionGuard, ConnectionLimit> {
+    let config = state.config.load();
+    let mut guard = try_acquire_connection(&state.connections, ip, config.max_connections_per_ip)
+        .ok_or(ConnectionLimit::PerIp)?;
+    if !try_acquire_total(&state.total_connections, config.max_total_connections) {
+        state.metrics.total_connection_rejections.inc();
+        return Err(ConnectionLimit::Total);
+    }
+    guard.total = Some(state.total_connections.clone());
+    if config.mirror_connection_counts
+        && let Some(mirror) = &state.connection_mirror
+    {
+        mirror.add(ip, 1);
+        guard.mirror = Some(mirror.clone());
+    }
+    Ok(guard)
+}
+
+struct WatcherGuard {
+    watchers: UserWatchers,
+    memory_cache: Arc<DashMap<String, PresenceData>>,
+    interest: Arc<Interest>,
+    config: config::SharedConfig,
+    user_id: String,
+}
+
+impl WatcherGuard {
+    /// Hands out a receiver for the user's current sender, recreating it if it
+    /// was torn down while this connection was still alive.
+    fn resubscribe(&self) -> PresenceReceiver {
+        watch_receiver(&self.watchers, &self.user_id)
+    }
+}
+
+impl Drop for WatcherGuard {
+    fn drop(&mut self) {
+        // check and remove under the same shard lock `watch_receiver` takes, so a
+        // connection subscribing concurrently can't have its sender pulled away
+        let removed = self
+            .watchers
+            .remove_if(&self.user_id, |_, watcher| watcher.receiver_count() == 0);
+        // users still polled over HTTP keep being tracked, so their cached
+        // presence stays until the interest runs out
+        let ttl = Duration::from_secs(self.config.load().interest_ttl_secs);
+        if removed.is_some() && !self.interest.contains(&self.user_id, ttl) {
+            self.memory_cache.remove(&self.user_id);
+        }
+    }
+}
+
+/// A receiver for a user's watcher along with its guard. Fields drop in
+/// order, so the receiver is gone before the guard checks for remaining ones,
+/// whichever way the holder is dropped.
+struct Watching {
+    rx: PresenceReceiver,
+    guard: WatcherGuard,
+}
+
+impl Watching {
+    /// Swaps in a receiver for the user's current sender, see
+    /// [`WatcherGuard::resubscribe`].
+    fn resubscribe(&mut self) {
+        self.rx = self.guard.resubscribe();
+    }
+}
+
+async fn ws_send_with_timeout(ws_tx: &mut (impl Sink<Message> + Unpin), msg: Message) -> bool {
+    matches!(timeout(WS_SEND_TIMEOUT, ws_tx.send(msg)).await, Ok(Ok(_)))
+}
+
+fn watch_receiver(watchers: &UserWatchers, user_id: &str) -> PresenceReceiver {
+    watchers
+        .entry(user_id.to_string())
+        .or_insert_with(|| watch::channel(Watched::Nothing).0)
+        .subscribe()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SubscribeError {
+    /// `MAX_WATCHED_USERS` distinct users are already watched.
+    WatcherLimit,
+    /// `MAX_SUBSCRIBERS_PER_USER` streams already watch this user.
+    SubscriberLimit,
+}
+
+impl SubscribeError {
+    fn reason(self) -> &'static str {
+        match self {
+            SubscribeError::WatcherLimit => "too many watched users",
+            SubscribeError::SubscriberLimit => "too many subscribers for this user",
+        }
+    }
+
+    fn code(self) -> ErrorCode {
+        match self {
+            SubscribeError::WatcherLimit => ErrorCode::WatcherLimit,
+            SubscribeError::SubscriberLimit => ErrorCode::SubscriberLimit,
+        }
+    }
+}
+
+/// Whether `user_id` can be watched: it already is, or there is room for one
+/// more watched user.
+fn watcher_available(state: &AppState, user_id: &str) -> bool {
+    let max = state.config.load().max_watched_users;
+    max == 0 || state.watchers.contains_key(user_id) || state.watchers.len() < max
+}
+
+fn subscribe(state: &AppState, user_id: &str) -> Result<Watching, SubscribeError> {
+    // concurrent first subscriptions can overshoot the cap by a few, which is
+    // fine for bounding the map
+    if !watcher_available(state, user_id) {
+        state.metrics.watcher_limit_rejections.inc();
+        return Err(SubscribeError::WatcherLimit);
+    }
+    let max = state.config.load().max_subscribers_per_user;
+    if max > 0
+        && state
+            .watchers
+            .get(user_id)
+            .is_some_and(|watcher| watcher.receiver_count() >= max)
+    {
+        state.metrics.subscriber_limit_rejections.inc();
+        return Err(SubscribeError::SubscriberLimit);
+    }
+    let rx = watch_receiver(&state.watchers, user_id);
+
+    let guard = WatcherGuard {
+        watchers: state.watchers.clone(),
+        memory_cache: state.cache.get_memory(),
+        interest: state.interest.clone(),
+        config: state.config.clone(),
+        user_id: user_id.to_string(),
+    };
+
+    Ok(Watching { rx, guard })
+}
+
+fn subscribe_error_reply(error: SubscribeError) -> warp::reply::WithStatus<warp::reply::Json> {
+    error_reply(
+        StatusCode::SERVICE_UNAVAILABLE,
+        error.code(),
+        error.reason(),
+    )
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum ClientOp {
+    Resume { user_id: String, last_seq: u64 },
+}
+
+/// Waits briefly for a `resume` op as the client's first frame. Any other
+/// frame is handed back for the connection loop to handle as usual. Returns
+/// `Err(())` if the client went away while we were waiting.
+async fn read_resume<E>(
+    ws_rx: &mut (impl Stream<Item = Result<Message, E>> + Unpin),
+    user_id: &str,
+) -> Result<(Option<u64>, Option<Message>), ()> {
+    let msg = match timeout(WS_RESUME_WINDOW, ws_rx.next()).await {
+        Err(_) => return Ok((None, None)),
+        Ok(Some(Ok(msg))) => msg,
+        Ok(Some(Err(_)) | None) => return Err(()),
+    };
+    if ws_policy_violation(&msg).is_some() {
+        return Ok((None, Some(msg)));
+    }
+    let last_seq = msg
+        .to_str()
+        .ok()
+        .and_then(|text| serde_json::from_str::<ClientOp>(text).ok())
+        .and_then(
+            |ClientOp::Resume {
+                 user_id: uid,
+                 last_seq,
+             }| { (uid == user_id).then_some(last_seq) },
+        );
+    match last_seq {
+        Some(seq) => Ok((Some(seq), None)),
+        None => Ok((None, Some(msg))),
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct WsQuery {
+    /// `0` drops updates where only the Spotify progress changed.
+    progress_updates: Option<String>,
+    /// Overrides `FLATTEN_SPOTIFY` for this connection.
+    flatten: Option<String>,
+    /// `2` wraps every message in a versioned `WsEnvelope`.
+    v: Option<String>,
+    /// `1` sends the current presence and closes, see [`ws_once`].
+    once: Option<String>,
+    /// `1` waits briefly for a `resume` op before sending the snapshot.
+    resume: Option<String>,
+}
+
+impl WsQuery {
+    fn progress_updates(&self) -> bool {
+        query_flag(self.progress_updates.as_deref()).unwrap_or(true)
+    }
+
+    fn once(&self) -> bool {
+        query_flag(self.once.as_deref()).unwrap_or(false)
+    }
+
+    fn resume(&self) -> bool {
+        query_flag(self.resume.as_deref()).unwrap_or(false)
+    }
+}
+
+/// `v` of `WsEnvelope`, the only version besides the unversioned messages.
+const WS_PROTOCOL_VERSION: u8 = 2;
+
+/// A server message on a WebSocket, other than pings and close frames.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+enum WsEvent<'a> {
+    /// Sent as soon as a WebSocket is subscribed, so clients can tell a
+    /// connected socket without a presence apart from one still connecting.
+    Ready {
+        user_id: &'a str,
+    },
+    /// The resume op matched, no snapshot follows.
+    Resumed,
+    Presence(Box<RawValue>),
+    /// The presence last sent expired without a newer one, so the client can
+    /// stop showing it.
+    Cleared {
+        user_id: &'a str,
+    },
+    /// The Discord gateway dropped or came back. While it's down presence
+    /// doesn't update, so what the client shows may be out of date.
+    Gateway {
+        connected: bool,
+    },
+    Error(serde_json::Value),
+}
+
+/// `{"v": 2, "type": ..., "data": ...}`, what `?v=2` connections get.
+#[derive(Serialize)]
+struct WsEnvelope<'a> {
+    v: u8,
+    #[serde(flatten)]
+    event: WsEvent<'a>,
+}
+
+/// How a WebSocket connection wants its messages encoded.
+#[derive(Debug, Clone, Copy)]
+struct WsFormat {
+    flatten: bool,
+    enveloped: bool,
+}
+
+impl WsFormat {
+    fn new(query: &WsQuery, state: &AppState) -> Self {
+        Self {
+            flatten: query_flag(query.flatten.as_deref())
+                .unwrap_or_else(|| state.config.load().flatten_spotify),
+            enveloped: query.v.as_deref() == Some("2"),
+        }
+    }
+
+    fn encode(self, event: WsEvent) -> String {
+        if self.enveloped {
+            let envelope = WsEnvelope {
+                v: WS_PROTOCOL_VERSION,
+                event,
+            };
+            return serde_json::to_string(&envelope).unwrap_or_default();
+        }
+        match event {
+            WsEvent::Ready { user_id } => {
+                serde_json::json!({"type": "ready", "user_id": user_id}).to_string()
+            }
+            WsEvent::Resumed => serde_json::json!({"type": "resumed"}).to_string(),
+            WsEvent::Presence(body) => body.get().to_string(),
+            WsEvent::Cleared { user_id } => {
+                serde_json::json!({"user_id": user_id, "cleared": true}).to_string()
+            }
+            WsEvent::Gateway { connected } => {
+                serde_json::json!({"type": "gateway", "connected": connected}).to_string()
+            }
+            WsEvent::Error(error) => {
+                serde_json::json!({"type": "error", "error": error}).to_string()
+            }
+        }
+    }
+
+    fn message(self, event: WsEvent) -> Message {
+        Message::text(self.encode(event))
+    }
+
+    fn presence(self, presence: &PresenceData, shared: Option<&SharedPresence>) -> String {
+        let body = ws_payload(presence, shared, self.flatten);
+        if !self.enveloped {
+            return body;
+        }
+        let body = RawValue::from_string(body).expect("ws_payload emits JSON");
+        self.encode(WsEvent::Presence(body))
+    }
+}
+
+/// Serializes a presence for a WebSocket, reusing the shared encoding unless
+/// the connection asked for the flattened shape.
+fn ws_payload(presence: &PresenceData, shared: Option<&SharedPresence>, flatten: bool) -> String {
+    if flatten {
+        let mut body = serde_json::to_value(presence).unwrap_or_default();
+        flatten_spotify(&mut body);
+        return body.to_string();
+    }
+    match shared {
+        Some(shared) => shared.json().to_string(),
+        None => serde_json::to_string(presence).unwrap_or_default(),
+    }
+}
+
+/// True when `next` differs from `prev` only in Spotify progress, i.e. the same
+/// track is still playing and nothing else a client shows has changed.
+fn only_progress_changed(prev: &PresenceData, next: &PresenceData) -> bool {
+    let (Some(a), Some(b)) = (&prev.spotify, &next.spotify) else {
+        return false;
+    };
+    a.track == b.track
+        && a.artist == b.artist
+        && a.album == b.album
+        && a.album_art_url == b.album_art_url
+        && a.track_url == b.track_url
+        && prev.status == next.status
+        && prev.game == next.game
+        && prev.rich_presence == next.rich_presence
+        && prev.custom_status == next.custom_status
+        && prev.stage == next.stage
+        && prev.client_status == next.client_status
+        && prev.primary_platform == next.primary_platform
+        && prev.username == next.username
+        && prev.display_name == next.display_name
+        && prev.avatar_url == next.avatar_url
+}
+
+async fn ws_upgrade_handler(
+    user_id: String,
+    ws: Ws,
+    query: WsQuery,
+    state: AppState,
+    ip: IpAddr,
+) -> Result<warp::reply::Response, Rejection> {
+    let user_id = normalize_user_id(user_id);
+    if let Err(e) = parse_user_id(&user_id) {
+        return Ok(
+            error_reply(StatusCode::BAD_REQUEST, ErrorCode::InvalidUserId, &e).into_response(),
+        );
+    }
+    let span = info_span!("ws", user_id = %user_id, client_ip = %ip);
+    if let Some(reply) = membership_gate(&state, &user_id)
+        .instrument(span.clone())
+        .await
+    {
+        return Ok(reply.into_response());
+    }
+    let once = query.once();
+    if once {
+        // these take no connection slot, so they count like REST requests
+        let config = state.config.load();
+        if let Err(retry_after) =
+            state
+                .rate_limiter
+                .take(ip, config.rate_limit_burst, config.rate_limit_per_sec)
+        {
+            return Err(warp::reject::custom(RateLimited { retry_after }));
+        }
+    }
+    if !once {
+        if let Some(limit) = connection_limit_reached(&state, ip) {
+            return Ok(limit.reply());
+        }
+        if !watcher_available(&state, &user_id) {
+            return Ok(subscribe_error_reply(SubscribeError::WatcherLimit).into_response());
+        }
+    }
+
+    Ok(ws_limits(ws)
+        .on_upgrade(move |socket| {
+            async move {
+                if once {
+                    ws_once(socket, &user_id, &state, &query).await;
+                    return;
+                }
+                match acquire_connection(&state, ip) {
+                    Ok(guard) => ws_handler(socket, user_id, state, guard, query).await,
+                    Err(limit) => {
+                        warn!(ip = %ip, ?limit, "connection limit exceeded");
+                        ws_reject(socket, limit).await;
+                    }
+                }
+            }
+            .instrument(span)
+        })
+        .into_response())
+}
+
+/// The cached presence a WebSocket starts out with, if it's still current.
+async fn ws_snapshot(state: &AppState, user_id: &str) -> Option<PresenceData> {
+    state.cache.get(user_id).await.filter(|p| {
+        let config = state.config.load();
+        !is_presence_stale(&config, p) && !idle_as_offline(&config, p)
+    })
+}
+
+/// `?once=1`: sends the current presence, or `cleared` without one, and closes
+/// normally. Takes no connection slot and watches nobody, so it works like a
+/// `GET /v1/{id}` for clients that only speak WebSocket.
+async fn ws_once(mut ws: WebSocket, user_id: &str, state: &AppState, query: &WsQuery) {
+    state.register_interest(user_id);
+    let format = WsFormat::new(query, state);
+    let payload = match ws_snapshot(state, user_id).await {
+        Some(presence) => format.presence(&presence, None),
+        None => format.encode(WsEvent::Cleared { user_id }),
+    };
+    if matches!(
+        timeout(WS_SEND_TIMEOUT, ws.send(Message::text(payload))).await,
+        Ok(Ok(_))
+    ) {
+        let _ = timeout(
+            WS_SEND_TIMEOUT,
+            ws.send(Message::close_with(1000u16, "done")),
+        )
+        .await;
+    }
+    let _ = timeout(WS_SEND_TIMEOUT, ws.close()).await;
+}
+
+/// What a WebSocket starts out with: `resumed` when the client already shows
+/// the current presence, else the presence, or `cleared` for a client that
+/// resumed a presence which is gone by now.
+fn ws_first_payload(
+    format: WsFormat,
+    user_id: &str,
+    snapshot: Option<&PresenceData>,
+    last_seq: Option<u64>,
+) -> Option<String> {
+    match (snapshot, last_seq) {
+        (Some(presence), Some(seq)) if presence.seq == seq => Some(format.encode(WsEvent::Resumed)),
+        (Some(presence), _) => Some(format.presence(presence, None)),
+        (None, Some(_)) => Some(format.encode(WsEvent::Cleared { user_id })),
+        (None, None) => None,
+    }
+}
+
+async fn ws_handler<S, E>(
+    ws: S,
+    user_id: String,
+    state: AppState,
+    conn_guard: ConnectionGuard,
+    query: WsQuery,
+) where
+    S: Stream<Item = Result<Message, E>> + Sink<Message> + Send + 'static,
+{
+    let (mut ws_tx, mut ws_rx) = ws.split();
+
+    let watching = match subscribe(&state, &user_id) {
+        Ok(watching) => watching,
+        Err(e) => {
+            let close = Message::close_with(1013u16, e.reason());
+            let _ = ws_send_with_timeout(&mut ws_tx, close).await;
+            return;
+        }
+    };
+
+    let format = WsFormat::new(&query, &state);
+    if !ws_send_with_timeout(
+        &mut ws_tx,
+        format.message(WsEvent::Ready { user_id: &user_id }),
+    )
+    .await
+    {
+        return;
+    }
+
+    let (last_seq, first_frame) = if query.resume() {
+        match read_resume(&mut ws_rx, &user_id).await {
+            Ok(resume) => resume,
+            Err(()) => return,
+        }
+    } else {
+        (None, None)
+    };
+
+    let snapshot = ws_snapshot(&state, &user_id).await;
+
+    if let Some(payload) = ws_first_payload(format, &user_id, snapshot.as_ref(), last_seq) {
+        let msg = Message::text(payload);
+        if !ws_send_with_timeout(&mut ws_tx, msg.clone()).await
+            && !ws_send_with_timeout(&mut ws_tx, msg).await
+        {
+            warn!(user_id = %user_id, "failed to send ws snapshot, closing connection");
+            let _ = timeout(WS_SEND_TIMEOUT, ws_tx.close()).await;
+            return;
+        }
+    }
+
+    let (queue_tx, queue_rx) = mpsc::channel(state.config.load().ws_send_queue_depth);
+    let (slow_tx, slow_rx) = oneshot::channel();
+    tokio::spawn(ws_writer(ws_tx, queue_rx, slow_rx));
+
+    let mut outbox = WsOutbox {
+        queue: queue_tx,
+        slow: Some(slow_tx),
+        metrics: state.metrics.clone(),
+    };
+    let filter = ProgressFilter {
+        enabled: !query.progress_updates(),
+        last_sent: snapshot,
+    };
+    // a frame that came in during the resume window isn't lost
+    let mut incoming = futures_util::stream::iter(first_frame.map(Ok)).chain(&mut ws_rx);
+    ws_loop(
+        &mut outbox,
+        &mut incoming,
+        (watching, conn_guard),
+        filter,
+        format,
+        &state,
+    )
+    .await;
+}
+
+/// Drops progress-only updates for clients that opted out of them.
+struct ProgressFilter {
+    enabled: bool,
+    last_sent: Option<PresenceData>,
+}
+
+impl ProgressFilter {
+    fn should_send(&mut self, next: &PresenceData) -> bool {
+        if !self.enabled {
+            return true;
+        }
+        if self
+            .last_sent
+            .as_ref()
+            .is_some_and(|prev| only_progress_changed(prev, next))
+        {
+            return false;
+        }
+        self.last_sent = Some(next.clone());
+        true
+    }
+}
+
+/// Outbound side of a WebSocket. Messages go through a bounded queue drained
+/// by `ws_writer`, so a client that stops reading can't make us buffer
+/// without limit.
+struct WsOutbox {
+    queue: mpsc::Sender<Message>,
+    slow: Option<oneshot::Sender<()>>,
+    metrics: Arc<metrics::Metrics>,
+}
+
+impl WsOutbox {
+    /// Queues `msg`, returning false once the connection should be dropped. A
+    /// queue that stays full for `WS_SEND_TIMEOUT` marks the client as too slow.
+    async fn send(&mut self, msg: Message) -> bool {
+        match self.queue.send_timeout(msg, WS_SEND_TIMEOUT).await {
+            Ok(()) => true,
+            Err(mpsc::error::SendTimeoutError::Timeout(_)) => {
+                warn!("ws client too slow, disconnecting");
+                self.metrics.slow_clients.inc();
+                if let Some(slow) = self.slow.take() {
+                    let _ = slow.send(());
+                }
+                false
+            }
+            Err(mpsc::error::SendTimeoutError::Closed(_)) => false,
+        }
+    }
+}
+
+async fn ws_writer(
+    mut ws_tx: impl Sink<Message> + Unpin,
+    mut queue: mpsc::Receiver<Message>,
+    mut slow: oneshot::Receiver<()>,
+) {
+    loop {
+        tokio::select! {
+            biased;
+
+            Ok(()) = &mut slow => {
+                let close = Message::close_with(1011u16, "client too slow");
+                let _ = ws_send_with_timeout(&mut ws_tx, close).await;
+                break;
+            }
+
+            msg = queue.recv() => match msg {
+                Some(msg) => {
+                    if !ws_send_with_timeout(&mut ws_tx, msg).await {
+                        break;
+                    }
+                }
+                None => break,
+            },
+        }
+    }
+}
+
+/// How often WebSockets check whether the presence they last sent expired.
+const WS_EXPIRY_CHECK: Duration = Duration::from_secs(15);
+
+/// Whether the presence last sent, updated at `shown_ms`, has expired.
+fn shown_presence_expired(state: &AppState, shown_ms: Option<i64>) -> bool {
+    shown_ms.is_some_and(|shown| is_timestamp_stale(&state.config.load(), shown))
+}
+
+/// Closes a socket that upgraded but couldn't get a connection slot.
+async fn ws_reject(mut ws: WebSocket, limit: ConnectionLimit) {
+    let _ = timeout(WS_SEND_TIMEOUT, ws.send(limit.close())).await;
+    let _ = timeout(WS_SEND_TIMEOUT, ws.close()).await;
+}
+
+fn ws_limits(ws: Ws) -> Ws {
+    ws.max_message_size(WS_MAX_FRAME_BUFFER)
+        .max_frame_size(WS_MAX_FRAME_BUFFER)
+}
+
+/// Why a client message gets its connection closed, if it does: binary
+/// frames, which no client op uses, and anything past `WS_MAX_MESSAGE_SIZE`.
+fn ws_policy_violation(msg: &Message) -> Option<&'static str> {
+    if msg.is_binary() {
+        Some("binary messages aren't supported")
+    } else if msg.as_bytes().len() > WS_MAX_MESSAGE_SIZE {
+        Some("message too large")
+    } else {
+        None
+    }
+}
+
+/// Close frame (1008, policy violation) for a message `ws_policy_violation`
+/// refuses. Logged so abusive clients can be traced by the span's IP.
+fn policy_violation_close(msg: &Message) -> Message {
+    let reason = ws_policy_violation(msg).unwrap_or_default();
+    debug!(
+        reason,
+        len = msg.as_bytes().len(),
+        "closing ws after a policy violation"
+    );
+    Message::close_with(1008u16, reason)
+}
+
+/// Close frame for a WebSocket whose user was evicted with `DELETE /v1/{id}`.
+fn evicted_close() -> Message {
+    Message::close_with(1000u16, "presence evicted")
+}
+
+/// Close frame for open WebSockets when the server shuts down, so clients
+/// reconnect (to another instance) right away.
+fn shutdown_close() -> Message {
+    Message::close_with(1001u16, "server shutting down")
+}
+
+/// Runs a connection until the client goes away or the server shuts down.
+/// Takes the guards so they are released the moment the loop exits, whichever
+/// way it does.
+async fn ws_loop<E>(
+    outbox: &mut WsOutbox,
+    ws_rx: &mut (impl Stream<Item = Result<Message, E>> + Unpin),
+    (mut watching, _conn_guard): (Watching, ConnectionGuard),
+    mut filter: ProgressFilter,
+    format: WsFormat,
+    state: &AppState,
+) {
+    let mut shutdown = state.shutdown.subscribe();
+    let mut gateway = state.gateway.subscribe();
+    let mut ping_interval = interval_at(
+        Instant::now() + Duration::from_secs(25),
+        Duration::from_secs(25),
+    );
+    let mut expiry_check = interval_at(Instant::now() + WS_EXPIRY_CHECK, WS_EXPIRY_CHECK);
+    // `timestamp_ms` of the presence the client is showing
+    let mut shown_ms = filter.last_sent.as_ref().map(|p| p.timestamp_ms);
+
+    loop {
+        tokio::select! {
+            _ = wait_for_shutdown(&mut shutdown) => {
+                let _ = outbox.send(shutdown_close()).await;
+                break;
+            }
+
+            _ = ping_interval.tick() => {
+                if !outbox.send(Message::ping(Vec::new())).await {
+                    break;
+                }
+            }
+
+            Ok(()) = gateway.changed() => {
+                let connected = *gateway.borrow_and_update();
+                if !outbox.send(format.message(WsEvent::Gateway { connected })).await {
+                    break;
+                }
+            }
+
+            _ = expiry_check.tick() => {
+                if shown_presence_expired(state, shown_ms) {
+                    shown_ms = None;
+                    let cleared = WsEvent::Cleared { user_id: &watching.guard.user_id };
+                    if !outbox.send(format.message(cleared)).await {
+                        break;
+                    }
+                }
+            }
+
+            incoming = ws_rx.next() => {
+                match incoming {
+                    Some(Ok(msg)) if msg.is_close() => break,
+                    Some(Ok(msg)) if ws_policy_violation(&msg).is_some() => {
+                        let _ = outbox.send(policy_violation_close(&msg)).await;
+                        break;
+                    }
+                    Some(Ok(msg)) if msg.is_ping() => {
+                        if !outbox.send(Message::pong(msg.into_bytes())).await {
+                            break;
+                        }
+                    }
+                    Some(Err(_)) | None => break,
+                    _ => {}
+                }
+            }
+
+            result = watching.rx.changed() => {
+                if result.is_err() {
+                    watching.resubscribe();
+                    continue;
+                }
+                let watched = watching.rx.borrow_and_update().clone();
+                if matches!(watched, Watched::Evicted) {
+                    let _ = outbox.send(evicted_close()).await;
+                    break;
+                }
+                if let Some(shared) = watched.presence()
+                    && !is_presence_stale(&state.config.load(), &shared.presence)
+                    && !idle_as_offline(&state.config.load(), &shared.presence)
+                    && filter.should_send(&shared.presence)
+                {
+                    shown_ms = Some(shared.presence.timestamp_ms);
+                    let payload = format.presence(&shared.presence, Some(&shared));
+                    if !outbox.send(Message::text(payload)).await {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Most users one multiplexed WebSocket can subscribe to.
+const MAX_WS_SUBSCRIPTIONS: usize = MAX_BATCH_SIZE;
+
+/// A client message on `/ws/v1`, e.g. `{"subscribe": ["123"]}`. Both lists may
+/// be given at once, unsubscribes are applied first.
+#[derive(Debug, Default, Deserialize)]
+struct SubscriptionOp {
+    #[serde(default)]
+    subscribe: Vec<String>,
+    #[serde(default)]
+    unsubscribe: Vec<String>,
+}
+
+/// One user watched over a multiplexed WebSocket.
+struct Subscription {
+    watching: Watching,
+    filter: ProgressFilter,
+    /// `timestamp_ms` of the presence the client is showing.
+    shown_ms: Option<i64>,
+}
+
+fn ws_error(code: ErrorCode, message: &str, details: serde_json::Value) -> WsEvent<'static> {
+    WsEvent::Error(error_object(code, message, details))
+}
+
+async fn ws_multi_upgrade_handler(
+    ws: Ws,
+    query: WsQuery,
+    state: AppState,
+    ip: IpAddr,
+) -> Result<warp::reply::Response, Rejection> {
+    if let Some(limit) = connection_limit_reached(&state, ip) {
+        return Ok(limit.reply());
+    }
+    let span = info_span!("ws", client_ip = %ip);
+    Ok(ws_limits(ws)
+        .on_upgrade(move |socket| {
+            async move {
+                let _conn_guard = match acquire_connection(&state, ip) {
+                    Ok(guard) => guard,
+                    Err(limit) => {
+                        warn!(ip = %ip, ?limit, "connection limit exceeded");
+                        ws_reject(socket, limit).await;
+                        return;
+                    }
+                };
+                let (ws_tx, mut ws_rx) = socket.split();
+                let (queue_tx, queue_rx) = mpsc::channel(state.config.load().ws_send_queue_depth);
+                let (slow_tx, slow_rx) = oneshot::channel();
+                tokio::spawn(ws_writer(ws_tx, queue_rx, slow_rx));
+
+                let mut outbox = WsOutbox {
+                    queue: queue_tx,
+                    slow: Some(slow_tx),
+                    metrics: state.metrics.clone(),
+                };
+                ws_multi_loop(&mut outbox, &mut ws_rx, &state, &query).await;
+            }
+            .instrument(span)
+        })
+        .into_response())
+}
+
+/// Applies `op`, returning the messages to answer with: the current presence
+/// of every newly subscribed user, then an error per kind of rejected id.
+async fn apply_subscription_op(
+    state: &AppState,
+    subscriptions: &mut HashMap<String, Subscription>,
+    op: SubscriptionOp,
+    query: &WsQuery,
+    format: WsFormat,
+) -> Vec<String> {
+    for user_id in op.unsubscribe {
+        subscriptions.remove(&normalize_user_id(user_id));
+    }
+
+    let config = state.config.load();
+    let mut replies = Vec::new();
+    let (mut invalid, mut not_allowed, mut over_limit) = (Vec::new(), Vec::new(), Vec::new());
+    let (mut watcher_limit, mut subscriber_limit) = (Vec::new(), Vec::new());
+
+    for raw in op.subscribe {
+        let user_id = normalize_user_id(raw.clone());
+        if !validate_user_id(&user_id) {
+            invalid.push(raw);
+            continue;
+        }
+        if subscriptions.contains_key(&user_id) {
+            continue;
+        }
+        if subscriptions.len() >= MAX_WS_SUBSCRIPTIONS {
+            over_limit.push(user_id);
+            continue;
+        }
+        if membership_gate(state, &user_id).await.is_some() {
+            not_allowed.push(user_id);
+            continue;
+        }
+        let watching = match subscribe(state, &user_id) {
+            Ok(watching) => watching,
+            Err(SubscribeError::WatcherLimit) => {
+                watcher_limit.push(user_id);
+                continue;
+            }
+            Err(SubscribeError::SubscriberLimit) => {
+                subscriber_limit.push(user_id);
+                continue;
+            }
+        };
+
+        let snapshot = state
+            .cache
+            .get(&user_id)
+            .await
+            .filter(|p| !is_presence_stale(&config, p) && !idle_as_offline(&config, p));
+        if let Some(presence) = &snapshot {
+            replies.push(format.presence(presence, None));
+        }
+        let shown_ms = snapshot.as_ref().map(|p| p.timestamp_ms);
+        let filter = ProgressFilter {
+            enabled: !query.progress_updates(),
+            last_sent: snapshot,
+        };
+        subscriptions.insert(
+            user_id,
+            Subscription {
+                watching,
+                filter,
+                shown_ms,
+            },
+        );
+    }
+
+    if !invalid.is_empty() {
+        replies.push(format.encode(ws_error(
+            ErrorCode::InvalidUserIds,
+            "invalid user ids",
+            serde_json::json!({"invalid": invalid}),
+        )));
+    }
+    if !not_allowed.is_empty() {
+        replies.push(format.encode(ws_error(
+            ErrorCode::NotAMember,
+            "not members of the guild",
+            serde_json::json!({"user_ids": not_allowed}),
+        )));
+    }
+    if !over_limit.is_empty() {
+        replies.push(format.encode(ws_error(
+            ErrorCode::TooManySubscriptions,
+            &format!("at most {MAX_WS_SUBSCRIPTIONS} subscriptions per connection"),
+            serde_json::json!({"max": MAX_WS_SUBSCRIPTIONS, "user_ids": over_limit}),
+        )));
+    }
+    if !watcher_limit.is_empty() {
+        replies.push(format.encode(ws_error(
+            ErrorCode::WatcherLimit,
+            SubscribeError::WatcherLimit.reason(),
+            serde_json::json!({"user_ids": watcher_limit}),
+        )));
+    }
+    if !subscriber_limit.is_empty() {
+        replies.push(format.encode(ws_error(
+            ErrorCode::SubscriberLimit,
+            SubscribeError::SubscriberLimit.reason(),
+            serde_json::json!({"user_ids": subscriber_limit}),
+        )));
+    }
+    replies
+}
+
+/// Resolves with the next subscribed user whose presence changed. Never
+/// resolves without subscriptions.
+async fn next_change(
+    subscriptions: &mut HashMap<String, Subscription>,
+) -> (String, Result<(), watch::error::RecvError>) {
+    if subscriptions.is_empty() {
+        return std::future::pending().await;
+    }
+    let changes = subscriptions.iter_mut().map(|(user_id, subscription)| {
+        Box::pin(async move { (user_id.clone(), subscription.watching.rx.changed().await) })
+    });
+    futures_util::future::select_all(changes).await.0
+}
+
+/// `ws_loop` for `/ws/v1`, where the client picks the users with
+/// `subscribe`/`unsubscribe` messages. Every presence carries its `user_id`.
+async fn ws_multi_loop<E>(
+    outbox: &mut WsOutbox,
+    ws_rx: &mut (impl Stream<Item = Result<Message, E>> + Unpin),
+    state: &AppState,
+    query: &WsQuery,
+) {
+    let format = WsFormat::new(query, state);
+    let mut subscriptions: HashMap<String, Subscription> = HashMap::new();
+    let mut shutdown = state.shutdown.subscribe();
+    let mut gateway = state.gateway.subscribe();
+    let mut ping_interval = interval_at(
+        Instant::now() + Duration::from_secs(25),
+        Duration::from_secs(25),
+    );
+    let mut expiry_check = interval_at(Instant::now() + WS_EXPIRY_CHECK, WS_EXPIRY_CHECK);
+
+    'connection: loop {
+        tokio::select! {
+            _ = wait_for_shutdown(&mut shutdown) => {
+                let _ = outbox.send(shutdown_close()).await;
+                break;
+            }
+
+            _ = ping_interval.tick() => {
+                if !outbox.send(Message::ping(Vec::new())).await {
+                    break;
+                }
+            }
+
+            Ok(()) = gateway.changed() => {
+                let connected = *gateway.borrow_and_update();
+                if !outbox.send(format.message(WsEvent::Gateway { connected })).await {
+                    break;
+                }
+            }
+
+            _ = expiry_check.tick() => {
+                for (user_id, subscription) in &mut subscriptions {
+                    if shown_presence_expired(state, subscription.shown_ms) {
+                        subscription.shown_ms = None;
+                        if !outbox.send(format.message(WsEvent::Cleared { user_id })).await {
+                            break 'connection;
+                        }
+                    }
+                }
+            }
+
+            incoming = ws_rx.next() => {
+                match incoming {
+                    Some(Ok(msg)) if msg.is_close() => break,
+                    Some(Ok(msg)) if ws_policy_violation(&msg).is_some() => {
+                        let _ = outbox.send(policy_violation_close(&msg)).await;
+                        break;
+                    }
+                    Some(Ok(msg)) if msg.is_ping() => {
+                        if !outbox.send(Message::pong(msg.into_bytes())).await {
+                            break;
+                        }
+                    }
+                    Some(Ok(msg)) if msg.is_text() => {
+                        let op = msg
+                            .to_str()
+                            .ok()
+                            .and_then(|text| serde_json::from_str::<SubscriptionOp>(text).ok());
+                        let replies = match op {
+                            Some(op) => {
+                                apply_subscription_op(state, &mut subscriptions, op, query, format)
+                                    .await
+                            }
+                            None => {
+                                vec![format.encode(ws_error(
+                                    ErrorCode::InvalidMessage,
+                                    "expected a subscribe or unsubscribe message",
+                                    serde_json::Value::Null,
+                                ))]
+                            }
+                        };
+                        for reply in replies {
+                            if !outbox.send(Message::text(reply)).await {
+                                break 'connection;
+                            }
+                        }
+                    }
+                    Some(Err(_)) | None => break,
+                    _ => {}
+                }
+            }
+
+            (user_id, result) = next_change(&mut subscriptions) => {
+                let Some(subscription) = subscriptions.get_mut(&user_id) else {
+                    continue;
+                };
+                if result.is_err() {
+                    subscription.watching.resubscribe();
+                    continue;
+                }
+                let watched = subscription.watching.rx.borrow_and_update().clone();
+                if matches!(watched, Watched::Evicted) {
+                    subscriptions.remove(&user_id);
+                    let cleared = WsEvent::Cleared { user_id: &user_id };
+                    if !outbox.send(format.message(cleared)).await {
+                        break;
+                    }
+                    continue;
+                }
+                if let Some(shared) = watched.presence()
+                    && !is_presence_stale(&state.config.load(), &shared.presence)
+                    && !idle_as_offline(&state.config.load(), &shared.presence)
+                    && subscription.filter.should_send(&shared.presence)
+                {
+                    subscription.shown_ms = Some(shared.presence.timestamp_ms);
+                    let payload = format.presence(&shared.presence, Some(&shared));
+                    if !outbox.send(Message::text(payload)).await {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+struct NdjsonStream {
+    watching: Watching,
+    config: config::SharedConfig,
+    keepalive: tokio::time::Interval,
+    snapshot: Option<PresenceData>,
+    shutdown: watch::Receiver<bool>,
+    _conn_guard: ConnectionGuard,
+}
+
+fn ndjson_line(presence: &PresenceData) -> Option<Bytes> {
+    let mut line = serde_json::to_vec(presence).ok()?;
+    line.push(b'\n');
+    Some(Bytes::from(line))
+}
+
+async fn stream_handler(
+    user_id: String,
+    state: AppState,
+    ip: IpAddr,
+) -> Result<warp::reply::Response, Rejection> {
+    let user_id = normalize_user_id(user_id);
+    if let Err(e) = parse_user_id(&user_id) {
+        return Ok(
+            error_reply(StatusCode::BAD_REQUEST, ErrorCode::InvalidUserId, &e).into_response(),
+        );
+    }
+    if let Some(reply) = membership_gate(&state, &user_id).await {
+        return Ok(reply.into_response());
+    }
+
+    let conn_guard = match acquire_connection(&state, ip) {
+        Ok(guard) => guard,
+        Err(limit) => {
+            warn!(ip = %ip, ?limit, "connection limit exceeded");
+            return Ok(limit.reply());
+        }
+    };
+
+    let watching = match subscribe(&state, &user_id) {
+        Ok(watching) => watching,
+        Err(e) => return Ok(subscribe_error_reply(e).into_response()),
+    };
+    let snapshot = state.cache.get(&user_id).await.filter(|p| {
+        let config = state.config.load();
+        !is_presence_stale(&config, p) && !idle_as_offline(&config, p)
+    });
+
+    let initial = NdjsonStream {
+        watching,
+        config: state.config.clone(),
+        keepalive: interval_at(Instant::now() + NDJSON_KEEPALIVE, NDJSON_KEEPALIVE),
+        snapshot,
+        shutdown: state.shutdown.subscribe(),
+        _conn_guard: conn_guard,
+    };
+
+    let stream = futures_util::stream::unfold(initial, |mut st| async move {
+        if let Some(line) = st.snapshot.take().as_ref().and_then(ndjson_line) {
+            return Some((line, st));
+        }
+
+        loop {
+            tokio::select! {
+                // ending the stream lets the connection drain on shutdown
+                _ = wait_for_shutdown(&mut st.shutdown) => return None,
+
+                _ = st.keepalive.tick() => return Some((Bytes::from_static(b"\n"), st)),
+
+                result = st.watching.rx.changed() => {
+                    if result.is_err() {
+                        st.watching.resubscribe();
+                        continue;
+                    }
+                    let watched = st.watching.rx.borrow_and_update().clone();
+                    if matches!(watched, Watched::Evicted) {
+                        return None;
+                    }
+                    let config = st.config.load();
+                    if let Some(line) = watched
+                        .presence()
+                        .filter(|shared| {
+                            !is_presence_stale(&config, &shared.presence)
+                                && !idle_as_offline(&config, &shared.presence)
+                        })
+                        .map(|shared| shared.ndjson_line())
+                    {
+                        st.keepalive.reset();
+                        return Some((line, st));
+                    }
+                }
+            }
+        }
+    });
+
+    Ok(server::streaming_reply(
+        "application/x-ndjson",
+        stream.boxed(),
+    ))
+}
+
+/// A presence as an SSE event, `snapshot` for the one sent on connect and
+/// `presence` for updates.
+fn sse_event(kind: &'static str, json: &str) -> warp::sse::Event {
+    warp::sse::Event::default().event(kind).data(json)
+}
+
+struct SseStream {
+    watching: Watching,
+    snapshot: Option<PresenceData>,
+    config: config::SharedConfig,
+    shutdown: watch::Receiver<bool>,
+    _conn_guard: ConnectionGuard,
+}
+
+async fn sse_handler(
+    user_id: String,
+    state: AppState,
+    ip: IpAddr,
+) -> Result<warp::reply::Response, Rejection> {
+    let user_id = normalize_user_id(user_id);
+    if let Err(e) = parse_user_id(&user_id) {
+        return Ok(
+            error_reply(StatusCode::BAD_REQUEST, ErrorCode::InvalidUserId, &e).into_response(),
+        );
+    }
+    if let Some(reply) = membership_gate(&state, &user_id).await {
+        return Ok(reply.into_response());
+    }
+
+    let conn_guard = match acquire_connection(&state, ip) {
+        Ok(guard) => guard,
+        Err(limit) => {
+            warn!(ip = %ip, ?limit, "connection limit exceeded");
+            return Ok(limit.reply());
+        }
+    };
+
+    let watching = match subscribe(&state, &user_id) {
+        Ok(watching) => watching,
+        Err(e) => return Ok(subscribe_error_reply(e).into_response()),
+    };
+    let snapshot = ws_snapshot(&state, &user_id).await;
+
+    let initial = SseStream {
+        watching,
+        snapshot,
+        config: state.config.clone(),
+        shutdown: state.shutdown.subscribe(),
+        _conn_guard: conn_guard,
+    };
+    let events = futures_util::stream::unfold(initial, |mut st| async move {
+        if let Some(presence) = st.snapshot.take() {
+            let json = serde_json::to_string(&presence).ok()?;
+            let event = sse_event("snapshot", &json);
+            return Some((Ok::<_, Infallible>(event), st));
+        }
+
+        loop {
+            tokio::select! {
+                // ending the stream lets the connection drain on shutdown
+                _ = wait_for_shutdown(&mut st.shutdown) => return None,
+
+                result = st.watching.rx.changed() => {
+                    if result.is_err() {
+                        st.watching.resubscribe();
+                        continue;
+                    }
+                    let watched = st.watching.rx.borrow_and_update().clone();
+                    if matches!(watched, Watched::Evicted) {
+                        return None;
+                    }
+                    let config = st.config.load();
+                    if let Some(shared) = watched.presence().filter(|shared| {
+                        !is_presence_stale(&config, &shared.presence)
+                            && !idle_as_offline(&config, &shared.presence)
+                    }) {
+                        let event = sse_event("presence", shared.json());
+                        return Some((Ok(event), st));
+                    }
+                }
+            }
+        }
+    });
+
+    let events = warp::sse::keep_alive()
+        .interval(SSE_KEEPALIVE)
+        .stream(events);
+    Ok(warp::sse::reply(events).into_response())
+}
+
+mod art;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod openapi;
+mod server;
+
+#[tokio::main]
+async fn main() {
+    config::load_dotenv();
+    let env_filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info"));
+    tracing_subscriber::fmt().with_env_filter(env_filter).init();
+
+    let config: config::SharedConfig = Arc::new(ArcSwap::from_pointee(config::Config::from_env()));
+
+    let redis_available = redis::wait_for_redis(Duration::from_secs(10)).await;
+    if !redis_available {
+        warn!("redis not available after 10s, using in-memory cache");
+    }
+
+    let token = std::env::var("DISCORD_BOT_TOKEN").expect("DISCORD_BOT_TOKEN not set");
+    let guild_ids = std::env::var("GUILD_IDS")
+        .or_else(|_| std::env::var("GUILD_ID"))
+        .expect("GUILD_IDS not set");
+    let guild_ids =
+        discord::parse_guild_ids(&guild_ids).expect("GUILD_IDS must be comma separated guild ids");
+
+    let http = Arc::new(SerenityHttp::new(&token));
+    let cache = Arc::new(redis::Cache::new(config.clone()));
+    let watchers: UserWatchers = Arc::new(DashMap::new());
+    let connections: ConnectionCounter = Arc::new(DashMap::new());
+
+    let state = AppState {
+        cache,
+        watchers,
+        connections,
+        total_connections: Arc::new(AtomicUsize::new(0)),
+        connection_mirror: redis_available.then(|| redis::ConnectionMirror::start(&config.load())),
+        http,
+        metrics: Arc::new(metrics::Metrics::default()),
+        gateway: Arc::new(discord::GatewayStatus::new(guild_ids)),
+        membership: Arc::new(discord::MembershipCache::default()),
+        oauth_users: Arc::new(discord::OAuthUsers::default()),
+        top_stats: Arc::new(analytics::TopCache::default()),
+        interest: Arc::new(Interest::default()),
+        rate_limiter: Arc::new(RateLimiter::default()),
+        art: Arc::new(art::ArtProxy::default()),
+        shutdown: Arc::new(watch::channel(false).0),
+        config: config.clone(),
+    };
+
+    let me_route = warp::path!("v1" / "me")
+        .and(warp::get())
+        .and(rate_limit(state.clone()))
+        .and(warp::header::optional::<String>("authorization"))
+        .and(with_state(state.clone()))
+        .and_then(me_handler);
+
+    let get_route = warp::path!("v1" / String)
+        .and(warp::get())
+        .and(rate_limit(state.clone()))
+        .and(warp::query::<PresenceQuery>())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(with_state(state.clone()))
+        .and(extract_client_ip(config.clone()))
+        .and_then(
+            |user_id: String,
+             query: PresenceQuery,
+             authorization: Option<String>,
+             state: AppState,
+             ip: IpAddr| {
+                let span = info_span!("get_presence", user_id = %user_id, client_ip = %ip);
+                get_presence_handler(user_id, query, authorization, state).instrument(span)
+            },
+        );
+
+    let text_route = warp::path!("v1" / String / "text")
+        .and(warp::get())
+        .and(rate_limit(state.clone()))
+        .and(warp::query::<TextQuery>())
+        .and(with_state(state.clone()))
+        .and(extract_client_ip(config.clone()))
+        .and_then(
+            |user_id: String, query: TextQuery, state: AppState, ip: IpAddr| {
+                let span = info_span!("text_presence", user_id = %user_id, client_ip = %ip);
+                text_presence_handler(user_id, query, state).instrument(span)
+            },
+        );
+
+    let qr_route = warp::path!("v1" / String / "qr")
+        .and(warp::get())
+        .and(rate_limit(state.clone()))
+        .and(with_state(state.clone()))
+        .and_then(qr_handler);
+
+    let spotify_route = warp::path!("v1" / String / "spotify")
+        .and(warp::get())
+        .and(rate_limit(state.clone()))
+        .and(with_state(state.clone()))
+        .and_then(spotify_handler);
+
+    let art_route = warp::path!("v1" / "art" / String)
+        .and(warp::get())
+        .and(rate_limit(state.clone()))
+        .and(with_state(state.clone()))
+        .and_then(art_handler);
+
+    let in_server_route = warp::path!("v1" / String / "in_server")
+        .and(warp::get())
+        .and(rate_limit(state.clone()))
+        .and(warp::header::optional::<String>("authorization"))
+        .and(with_state(state.clone()))
+        .and(extract_client_ip(config.clone()))
+        .and_then(
+            |user_id: String, authorization: Option<String>, state: AppState, ip: IpAddr| {
+                let span = info_span!("user_in_server", user_id = %user_id, client_ip = %ip);
+                user_in_server_handler(user_id, authorization, state).instrument(span)
+            },
+        );
+
+    let batch_route = warp::path!("v1" / "batch")
+        .and(warp::post())
+        .and(rate_limit(state.clone()))
+        .and(warp::body::content_length_limit(16 * 1024))
+        .and(warp::body::json())
+        .and(with_state(state.clone()))
+        .and_then(batch_presence_handler);
+
+    let query_route = warp::path!("v1" / "query")
+        .and(warp::post())
+        .and(rate_limit(state.clone()))
+        .and(warp::body::content_length_limit(16 * 1024))
+        .and(warp::body::json())
+        .and(with_state(state.clone()))
+        .and_then(query_handler);
+
+    let batch_in_server_route = warp::path!("v1" / "batch" / "in_server")
+        .and(warp::post())
+        .and(rate_limit(state.clone()))
+        .and(warp::body::content_length_limit(16 * 1024))
+        .and(warp::body::json())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(with_state(state.clone()))
+        .and_then(batch_in_server_handler);
+
+    let stream_route = warp::path!("v1" / String / "stream")
+        .and(warp::get())
+        .and(with_state(state.clone()))
+        .and(extract_client_ip(config.clone()))
+        .and_then(stream_handler);
+
+    let sse_route = warp::path!("sse" / "v1" / String)
+        .and(warp::get())
+        .and(with_state(state.clone()))
+        .and(extract_client_ip(config.clone()))
+        .and_then(sse_handler);
+
+    let ws_multi_route = warp::path!("ws" / "v1")
+        .and(warp::ws())
+        .and(warp::query::<WsQuery>())
+        .and(with_state(state.clone()))
+        .and(extract_client_ip(config.clone()))
+        .and_then(ws_multi_upgrade_handler);
+
+    let ws_route = warp::path!("ws" / "v1" / String)
+        .and(warp::ws())
+        .and(warp::query::<WsQuery>())
+        .and(with_state(state.clone()))
+        .and(extract_client_ip(config.clone()))
+        .and_then(ws_upgrade_handler);
+
+    let top_stats_route = warp::path!("v1" / "stats" / "top")
+        .and(warp::get())
+        .and(rate_limit(state.clone()))
+        .and(warp::query::<TopStatsQuery>())
+        .and(with_state(state.clone()))
+        .and_then(top_stats_handler);
+
+    let evict_route = warp::path!("v1" / String)
+        .and(warp::delete())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(with_state(state.clone()))
+        .and_then(evict_handler);
+
+    let reload_route = warp::path!("admin" / "reload")
+        .and(warp::post())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(with_state(state.clone()))
+        .and_then(reload_config_handler);
+
+    let root = warp::path::end()
+        .and(warp::get())
+        .and(with_state(state.clone()))
+        .map(|state: AppState| {
+            let mut body = serde_json::json!({
+                "endpoints": [
+                    {"method": "GET", "path": "/v1/{userid}"},
+                    {"method": "GET", "path": "/v1/me"},
+                    {"method": "DELETE", "path": "/v1/{userid}"},
+                    {"method": "WS",  "path": "/ws/v1/{userid}"},
+                    {"method": "WS",  "path": "/ws/v1"},
+                    {"method": "GET", "path": "/v1/{userid}/in_server"},
+                    {"method": "GET", "path": "/v1/{userid}/stream"},
+                    {"method": "GET", "path": "/sse/v1/{userid}"},
+                    {"method": "GET", "path": "/v1/{userid}/text"},
+                    {"method": "GET", "path": "/v1/{userid}/qr"},
+                    {"method": "GET", "path": "/v1/{userid}/spotify"},
+                    {"method": "GET", "path": "/v1/art/{hash}"},
+                    {"method": "POST", "path": "/v1/batch"},
+                    {"method": "POST", "path": "/v1/batch/in_server"},
+                    {"method": "POST", "path": "/v1/query"},
+                    {"method": "GET", "path": "/v1/stats/top"},
+                    {"method": "GET", "path": "/health"},
+                    {"method": "GET", "path": "/metrics"},
+                    {"method": "GET", "path": "/openapi.json"},
+                    {"method": "GET", "path": "/healthz"},
+                    {"method": "GET", "path": "/readyz"}
+                ]
+            });
+            if let Some(motd) = &state.config.load().motd {
+                body["message"] = motd.as_str().into();
+            }
+            warp::reply::json(&body)
+        });
+
+    let health_route = warp::path!("health")
+        .and(warp::get())
+        .and(with_state(state.clone()))
+        .map(|state: AppState| {
+            #[allow(unused_mut)]
+            let mut body = serde_json::json!({
+                "status": "ok",
+                "redis": redis::is_redis_available(),
+                "gateway_connected": state.gateway.connected(),
+                "dropped_presence_updates": state.metrics.dropped_presence_updates.get(),
+                "slow_clients": state.metrics.slow_clients.get(),
+                "watched_users": state.watchers.len(),
+                "watcher_limit_rejections": state.metrics.watcher_limit_rejections.get(),
+                "subscriber_limit_rejections": state.metrics.subscriber_limit_rejections.get(),
+                "total_connection_rejections": state.metrics.total_connection_rejections.get(),
+                "gateway_events": state.metrics.gateway_events()
+            });
+            #[cfg(feature = "nats")]
+            {
+                body["dropped_nats_publishes"] = state.metrics.dropped_nats_publishes.get().into();
+            }
+            warp::reply::json(&body)
+        });
+
+    let openapi = openapi::document();
+    let openapi_route = warp::path!("openapi.json")
+        .and(warp::get())
+        .map(move || warp::reply::json(&openapi));
+
+    let metrics_route = warp::path!("metrics")
+        .and(warp::get())
+        .and(with_state(state.clone()))
+        .map(|state: AppState| {
+            let gauges = metrics::Gauges {
+                active_connections: state.connections.iter().map(|c| *c.value()).sum(),
+                max_total_connections: state.config.load().max_total_connections,
+                watched_users: state.watchers.len(),
+                gateway_connected: state.gateway.connected(),
+            };
+            warp::reply::with_header(
+                state.metrics.prometheus(&gauges),
+                "content-type",
+                "text/plain; version=0.0.4",
+            )
+        });
+
+    // liveness: answering at all means the runtime isn't wedged
+    let healthz_route = warp::path!("healthz")
+        .and(warp::get())
+        .and(with_state(state.clone()))
+        .map(|state: AppState| {
+            warp::reply::json(&serde_json::json!({
+                "status": "ok",
+                "last_gateway_event_age_secs": state.gateway.last_event_age().map(|a| a.as_secs()),
+                "in_guild": state.gateway.in_guild(),
+            }))
+        });
+
+    let readyz_route = warp::path!("readyz")
+        .and(warp::get())
+        .and(with_state(state.clone()))
+        .and_then(readyz_handler);
+
+    let routes = root
+        .or(health_route)
+        .or(healthz_route)
+        .or(metrics_route)
+        .or(openapi_route)
+        .or(readyz_route)
+        .or(top_stats_route)
+        .or(art_route)
+        .or(batch_route)
+        .or(batch_in_server_route)
+        .or(query_route)
+        .or(me_route)
+        .or(get_route)
+        .or(evict_route)
         .or(in_server_route)
+        .or(text_route)
+        .or(qr_route)
+        .or(spotify_route)
+        .or(stream_route)
+        .or(sse_route)
+        .or(reload_route)
         .or(ws_route)
-        .with(warp::cors().allow_any_origin());
+        .or(ws_multi_route)
+        .recover(rejection_reply)
+        .with(cors(&config.load()));
+
+    let shutdown_rx = state.shutdown.subscribe();
+    let shutdown_tx = state.shutdown.clone();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        info!("shutdown signal received");
+        shutdown_tx.send_replace(true);
+    });
+
+    if config.load().redis_pubsub {
+        tokio::spawn(redis::relay_presence_updates(
+            config.clone(),
+            state.watchers.clone(),
+        ));
+    }
+    if redis_available {
+        tokio::spawn(redis::relay_evictions(
+            config.clone(),
+            state.cache.clone(),
+            state.watchers.clone(),
+        ));
+    }
+
+    #[cfg(feature = "grpc")]
+    tokio::spawn(grpc::serve(
+        state.clone(),
+        (config.load().bind_addr.ip(), config.load().grpc_port).into(),
+        shutdown_rx.clone(),
+    ));
 
-    info!("starting http server on 0.0.0.0:8787");
-    tokio::spawn(discord::start_discord(
+    let server_options = {
+        let config = config.load();
+        let tls = match (&config.tls_cert, &config.tls_key) {
+            (Some(cert), Some(key)) => {
+                Some(server::tls_acceptor(cert, key).unwrap_or_else(|e| panic!("{e}")))
+            }
+            _ => None,
+        };
+        server::Options {
+            tls,
+            h2c: config.h2c,
+        }
+    };
+    let bind_addr = config.load().bind_addr;
+    info!(
+        tls = server_options.tls.is_some(),
+        h2c = server_options.h2c,
+        "starting http server on {}",
+        bind_addr
+    );
+    let discord = tokio::spawn(discord::start_discord(
+        state.http.clone(),
         state.cache.clone(),
         state.watchers.clone(),
+        config,
+        state.metrics.clone(),
+        state.gateway.clone(),
+        state.interest.clone(),
+        shutdown_rx.clone(),
     ));
-    warp::serve(routes).run(([0, 0, 0, 0], 8787)).await;
+    server::serve(
+        warp::service(routes),
+        bind_addr,
+        server_options,
+        shutdown_rx,
+    )
+    .await;
+
+    let _ = discord.await;
+    info!("shutdown complete");
+}
+
+/// Resolves on SIGTERM or Ctrl+C.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(err) => {
+                warn!(?err, "failed to install SIGTERM handler");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use presence::SpotifyActivity;
+
+    fn test_state() -> AppState {
+        let config: config::SharedConfig =
+            Arc::new(ArcSwap::from_pointee(config::Config::load().unwrap()));
+        AppState {
+            cache: Arc::new(redis::Cache::new(config.clone())),
+            watchers: Arc::new(DashMap::new()),
+            connections: Arc::new(DashMap::new()),
+            total_connections: Arc::new(AtomicUsize::new(0)),
+            connection_mirror: None,
+            http: Arc::new(SerenityHttp::new("test")),
+            metrics: Arc::new(metrics::Metrics::default()),
+            gateway: Arc::new(discord::GatewayStatus::new(vec![
+                serenity::model::id::GuildId::new(1),
+            ])),
+            membership: Arc::new(discord::MembershipCache::default()),
+            oauth_users: Arc::new(discord::OAuthUsers::default()),
+            top_stats: Arc::new(analytics::TopCache::default()),
+            interest: Arc::new(Interest::default()),
+            rate_limiter: Arc::new(RateLimiter::default()),
+            art: Arc::new(art::ArtProxy::default()),
+            shutdown: Arc::new(watch::channel(false).0),
+            config,
+        }
+    }
+
+    fn presence(user_id: &str, seq: u64) -> PresenceData {
+        PresenceData {
+            user_id: user_id.to_string(),
+            status: OnlineStatus::Online,
+            spotify: None,
+            game: None,
+            rich_presence: None,
+            custom_status: None,
+            stage: None,
+            client_status: None,
+            primary_platform: None,
+            username: None,
+            display_name: None,
+            avatar_url: None,
+            idle_since_ms: None,
+            first_seen_ms: None,
+            timestamp_ms: chrono::Utc::now().timestamp_millis(),
+            seq,
+        }
+    }
+
+    #[tokio::test]
+    async fn spotify_route_tells_no_music_from_no_presence() {
+        let state = test_state();
+        let status = |user_id: &str| {
+            let state = state.clone();
+            let user_id = user_id.to_string();
+            async move { spotify_handler(user_id, state).await.unwrap().status() }
+        };
+
+        assert_eq!(status("1").await, StatusCode::NOT_FOUND);
+        state.cache.set("1", &presence("1", 1)).await;
+        assert_eq!(status("1").await, StatusCode::NO_CONTENT);
+
+        let mut listening = presence("1", 2);
+        listening.spotify = Some(SpotifyActivity {
+            track: Some("Pantheon".to_string()),
+            artist: None,
+            album: None,
+            album_art_url: None,
+            album_art_hash: None,
+            album_art: None,
+            track_url: None,
+            started_at_ms: None,
+            ends_at_ms: None,
+        });
+        state.cache.set("1", &listening).await;
+        let reply = spotify_handler("1".to_string(), state.clone())
+            .await
+            .unwrap();
+        assert_eq!(reply.status(), StatusCode::OK);
+        let body = http_body_util::BodyExt::collect(reply.into_body())
+            .await
+            .unwrap();
+        let spotify: serde_json::Value = serde_json::from_slice(&body.to_bytes()).unwrap();
+        assert_eq!(spotify["track"], "Pantheon");
+    }
+
+    #[tokio::test]
+    async fn errors_carry_a_code_and_a_message() {
+        let body = |reply: warp::reply::Response| async move {
+            let body = http_body_util::BodyExt::collect(reply.into_body())
+                .await
+                .unwrap();
+            serde_json::from_slice::<serde_json::Value>(&body.to_bytes()).unwrap()
+        };
+
+        let reply = get_presence_handler(
+            "01".to_string(),
+            PresenceQuery::default(),
+            None,
+            test_state(),
+        )
+        .await
+        .unwrap()
+        .into_response();
+        assert_eq!(reply.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            body(reply).await,
+            serde_json::json!({"error": {
+                "code": "INVALID_USER_ID",
+                "message": "invalid user id: leading zeros aren't allowed"
+            }})
+        );
+
+        let reply = rejection_reply(warp::reject::not_found()).await.unwrap();
+        assert_eq!(reply.status(), StatusCode::NOT_FOUND);
+        assert_eq!(body(reply).await["error"]["code"], "NOT_FOUND");
+    }
+
+    #[tokio::test]
+    async fn anonymous_misses_dont_reveal_membership() {
+        // answering without a Discord lookup, which would fail with the test token
+        let reply = get_presence_handler(
+            "1".to_string(),
+            PresenceQuery::default(),
+            None,
+            test_state(),
+        )
+        .await
+        .unwrap()
+        .into_response();
+        assert_eq!(reply.status(), StatusCode::OK);
+        let body = http_body_util::BodyExt::collect(reply.into_body())
+            .await
+            .unwrap();
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&body.to_bytes()).unwrap(),
+            serde_json::json!({"user_id": "1", "online": false, "spotify": null})
+        );
+    }
+
+    #[test]
+    fn remaining_time_bottoms_out_at_zero() {
+        let mut listening = presence("1", 1);
+        listening.spotify = Some(SpotifyActivity {
+            track: None,
+            artist: None,
+            album: None,
+            album_art_url: None,
+            album_art_hash: None,
+            album_art: None,
+            track_url: None,
+            started_at_ms: Some(1000),
+            ends_at_ms: Some(2000),
+        });
+        let at = |presence: &PresenceData, now_ms: i64| {
+            let mut body = serde_json::to_value(presence).unwrap();
+            add_progress(&mut body, presence, now_ms);
+            body["spotify"].clone()
+        };
+
+        assert_eq!(at(&listening, 1500)["remaining_ms"], 500);
+        assert_eq!(at(&listening, 1500)["should_refresh"], false);
+        assert_eq!(at(&listening, 2500)["remaining_ms"], 0);
+        assert_eq!(at(&listening, 2500)["should_refresh"], true);
+
+        listening.spotify.as_mut().unwrap().ends_at_ms = None;
+        let body = at(&listening, 2500);
+        assert!(body["remaining_ms"].is_null());
+        assert_eq!(body["should_refresh"], false);
+    }
+
+    #[test]
+    fn progress_only_changes_are_detected() {
+        let spotify = SpotifyActivity {
+            track: Some("A Shoulder to Cry On".to_string()),
+            artist: Some("Dance Gavin Dance".to_string()),
+            album: Some("Pantheon".to_string()),
+            album_art_url: None,
+            album_art_hash: None,
+            album_art: None,
+            track_url: None,
+            started_at_ms: Some(1000),
+            ends_at_ms: Some(2000),
+        };
+        let prev = PresenceData {
+            spotify: Some(spotify.clone()),
+            ..presence("1", 1)
+        };
+
+        let seeked = PresenceData {
+            spotify: Some(SpotifyActivity {
+                started_at_ms: Some(1500),
+                ends_at_ms: Some(2500),
+                ..spotify.clone()
+            }),
+            ..presence("1", 2)
+        };
+        assert!(only_progress_changed(&prev, &seeked));
+
+        let next_track = PresenceData {
+            spotify: Some(SpotifyActivity {
+                track: Some("Son of Robot".to_string()),
+                ..spotify.clone()
+            }),
+            ..presence("1", 3)
+        };
+        assert!(!only_progress_changed(&prev, &next_track));
+
+        let stopped = presence("1", 4);
+        assert!(!only_progress_changed(&prev, &stopped));
+    }
+
+    #[tokio::test]
+    async fn surviving_watcher_keeps_receiving_after_other_disconnects() {
+        let state = test_state();
+        let Watching {
+            rx: mut rx_a,
+            guard: guard_a,
+        } = subscribe(&state, "1").unwrap();
+        let watching_b = subscribe(&state, "1").unwrap();
+
+        drop(watching_b);
+        assert!(state.watchers.contains_key("1"));
+
+        state
+            .watchers
+            .get("1")
+            .unwrap()
+            .send(Watched::Presence(SharedPresence::new(presence("1", 1))))
+            .unwrap();
+        timeout(Duration::from_secs(1), rx_a.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(rx_a.borrow_and_update().presence().unwrap().presence.seq, 1);
+
+        drop(rx_a);
+        drop(guard_a);
+        assert!(!state.watchers.contains_key("1"));
+    }
+
+    #[test]
+    fn idle_past_threshold_counts_as_offline() {
+        let mut config = (**test_state().config.load()).clone();
+        let now = chrono::Utc::now().timestamp_millis();
+        let idle_since = |ms| PresenceData {
+            idle_since_ms: Some(ms),
+            ..presence("1", 1)
+        };
+
+        config.idle_as_offline_secs = 0;
+        assert!(!idle_as_offline(&config, &idle_since(0)));
+
+        config.idle_as_offline_secs = 60;
+        assert!(!idle_as_offline(&config, &presence("1", 1)));
+        assert!(!idle_as_offline(&config, &idle_since(now - 30_000)));
+        assert!(idle_as_offline(&config, &idle_since(now - 90_000)));
+    }
+
+    #[tokio::test]
+    async fn query_serves_users_like_the_single_user_route() {
+        let state = test_state();
+        let mut config = (**state.config.load()).clone();
+        config.idle_as_offline_secs = 60;
+        state.config.store(Arc::new(config));
+
+        let idle = PresenceData {
+            status: OnlineStatus::Idle,
+            idle_since_ms: Some(chrono::Utc::now().timestamp_millis() - 90_000),
+            ..presence("1", 1)
+        };
+        state.cache.set("1", &idle).await;
+        let idle_since_ms = chrono::Utc::now().timestamp_millis() - 10_000;
+        let briefly_idle = PresenceData {
+            status: OnlineStatus::Idle,
+            idle_since_ms: Some(idle_since_ms),
+            ..presence("2", 1)
+        };
+        state.cache.set("2", &briefly_idle).await;
+
+        let request = QueryRequest {
+            user_ids: vec!["1".to_string(), "2".to_string()],
+            require_listening: false,
+            online_only: false,
+            fields: Some(vec!["user_id".to_string(), "idle_since_ms".to_string()]),
+        };
+        let reply = query_handler(request, state.clone())
+            .await
+            .unwrap()
+            .into_response();
+        let body = http_body_util::BodyExt::collect(reply.into_body())
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body.to_bytes()).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({"presences": {"2": {"user_id": "2", "idle_since_ms": idle_since_ms}}})
+        );
+
+        let ttl = Duration::from_secs(state.config.load().interest_ttl_secs);
+        assert!(state.interest.contains("1", ttl) && state.interest.contains("2", ttl));
+    }
+
+    #[tokio::test]
+    async fn text_route_shows_long_idle_users_as_offline() {
+        let state = test_state();
+        let mut config = (**state.config.load()).clone();
+        config.idle_as_offline_secs = 60;
+        state.config.store(Arc::new(config));
+        let idle = PresenceData {
+            status: OnlineStatus::Idle,
+            idle_since_ms: Some(chrono::Utc::now().timestamp_millis() - 90_000),
+            ..presence("1", 1)
+        };
+        state.cache.set("1", &idle).await;
+
+        let reply = text_presence_handler("1".to_string(), TextQuery::default(), state.clone())
+            .await
+            .unwrap();
+        let body = http_body_util::BodyExt::collect(reply.into_body())
+            .await
+            .unwrap();
+        assert_eq!(
+            body.to_bytes(),
+            format!("{}\n", state.config.load().text_no_presence)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn min_seq_waits_for_the_cache_to_catch_up() {
+        let state = test_state();
+        let _watching = subscribe(&state, "1").unwrap();
+        state.cache.set("1", &presence("1", 1)).await;
+
+        let writer = state.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            let next = presence("1", 3);
+            writer.cache.set("1", &next).await;
+            let _ = writer
+                .watchers
+                .get("1")
+                .unwrap()
+                .send(Watched::Presence(SharedPresence::new(next)));
+        });
+
+        assert!(wait_for_seq(&state, "1", 1).await);
+        assert!(wait_for_seq(&state, "1", 3).await);
+        assert!(!wait_for_seq(&state, "1", 4).await);
+        assert!(!wait_for_seq(&state, "2", 1).await);
+    }
+
+    #[test]
+    fn watcher_limit_rejects_new_users_only() {
+        let state = test_state();
+        let config = config::Config {
+            max_watched_users: 1,
+            ..(**state.config.load()).clone()
+        };
+        state.config.store(Arc::new(config));
+
+        let watching = subscribe(&state, "1").unwrap();
+        assert!(subscribe(&state, "1").is_ok());
+        assert_eq!(
+            subscribe(&state, "2").err(),
+            Some(SubscribeError::WatcherLimit)
+        );
+
+        drop(watching);
+        assert!(subscribe(&state, "2").is_ok());
+    }
+
+    #[test]
+    fn subscriber_limit_caps_streams_per_user() {
+        let state = test_state();
+        let config = config::Config {
+            max_subscribers_per_user: 2,
+            ..(**state.config.load()).clone()
+        };
+        state.config.store(Arc::new(config));
+
+        let first = subscribe(&state, "1").unwrap();
+        let _second = subscribe(&state, "1").unwrap();
+        assert_eq!(
+            subscribe(&state, "1").err(),
+            Some(SubscribeError::SubscriberLimit)
+        );
+        assert!(subscribe(&state, "2").is_ok(), "other users are unaffected");
+        assert_eq!(state.metrics.subscriber_limit_rejections.get(), 1);
+
+        drop(first);
+        assert!(subscribe(&state, "1").is_ok());
+    }
+
+    const RAW: WsFormat = WsFormat {
+        flatten: false,
+        enveloped: false,
+    };
+
+    fn ws_session(
+        state: &AppState,
+        queue_depth: usize,
+    ) -> (
+        WsOutbox,
+        mpsc::Receiver<Message>,
+        (Watching, ConnectionGuard),
+    ) {
+        let watching = subscribe(state, "1").unwrap();
+        let conn = try_acquire_connection(
+            &state.connections,
+            "127.0.0.1".parse().unwrap(),
+            state.config.load().max_connections_per_ip,
+        )
+        .unwrap();
+        let (queue, queue_rx) = mpsc::channel(queue_depth);
+        let outbox = WsOutbox {
+            queue,
+            slow: None,
+            metrics: state.metrics.clone(),
+        };
+        (outbox, queue_rx, (watching, conn))
+    }
+
+    /// Runs `ws_loop` against `incoming` and checks both guards are released.
+    async fn assert_ws_loop_releases(
+        state: &AppState,
+        mut outbox: WsOutbox,
+        guards: (Watching, ConnectionGuard),
+        mut incoming: impl Stream<Item = Result<Message, ()>> + Unpin,
+    ) {
+        let filter = ProgressFilter {
+            enabled: false,
+            last_sent: None,
+        };
+        let exited = timeout(
+            Duration::from_secs(60),
+            ws_loop(&mut outbox, &mut incoming, guards, filter, RAW, state),
+        )
+        .await;
+
+        assert!(exited.is_ok(), "ws_loop didn't exit");
+        assert!(state.watchers.is_empty(), "watcher leaked");
+        assert!(state.connections.is_empty(), "connection slot leaked");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ws_loop_releases_guards_on_close_frame() {
+        let state = test_state();
+        let (outbox, _queue, guards) = ws_session(&state, 16);
+        let incoming = futures_util::stream::iter([Ok(Message::close())])
+            .chain(futures_util::stream::pending());
+        assert_ws_loop_releases(&state, outbox, guards, incoming).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ws_loop_releases_guards_on_read_error() {
+        let state = test_state();
+        let (outbox, _queue, guards) = ws_session(&state, 16);
+        let incoming = futures_util::stream::iter([Err(())]).chain(futures_util::stream::pending());
+        assert_ws_loop_releases(&state, outbox, guards, incoming).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ws_loop_releases_guards_on_stream_end() {
+        let state = test_state();
+        let (outbox, _queue, guards) = ws_session(&state, 16);
+        assert_ws_loop_releases(&state, outbox, guards, futures_util::stream::empty()).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ws_loop_releases_guards_on_send_timeout() {
+        let state = test_state();
+        // a full queue nobody drains: the next ping times out
+        let (outbox, _queue, guards) = ws_session(&state, 1);
+        outbox.queue.try_send(Message::text("unread")).unwrap();
+        assert_ws_loop_releases(&state, outbox, guards, futures_util::stream::pending()).await;
+        assert_eq!(state.metrics.slow_clients.get(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_limiter_refills_over_time() {
+        let limiter = RateLimiter::default();
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+
+        assert_eq!(limiter.take(ip, 2, 1), Ok(()));
+        assert_eq!(limiter.take(ip, 2, 1), Ok(()));
+        assert_eq!(limiter.take(ip, 2, 1), Err(Duration::from_secs(1)));
+        assert_eq!(limiter.take("127.0.0.2".parse().unwrap(), 2, 1), Ok(()));
+
+        tokio::time::advance(Duration::from_millis(1500)).await;
+        assert_eq!(limiter.take(ip, 2, 1), Ok(()));
+        assert_eq!(limiter.take(ip, 2, 1), Err(Duration::from_millis(500)));
+        assert_eq!(limiter.take(ip, 0, 1), Ok(()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn full_rate_limiter_turns_new_ips_away() {
+        let limiter = RateLimiter::default();
+        for n in 0..MAX_RATE_LIMITED_IPS as u32 {
+            let ip = IpAddr::from(n.to_be_bytes());
+            assert_eq!(limiter.take(ip, 1, 1), Ok(()));
+        }
+
+        let newcomer: IpAddr = "203.0.113.7".parse().unwrap();
+        assert_eq!(limiter.take(newcomer, 1, 1), Err(Duration::from_secs(1)));
+        assert_eq!(limiter.0.len(), MAX_RATE_LIMITED_IPS);
+        let known = IpAddr::from(0u32.to_be_bytes());
+        assert_eq!(limiter.take(known, 1, 1), Err(Duration::from_secs(1)));
+
+        // buckets that refilled make room again
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert_eq!(limiter.take(newcomer, 1, 1), Ok(()));
+        assert_eq!(limiter.0.len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ws_loop_closes_on_shutdown() {
+        let state = test_state();
+        let (outbox, mut queue, guards) = ws_session(&state, 16);
+        state.shutdown.send_replace(true);
+        assert_ws_loop_releases(&state, outbox, guards, futures_util::stream::pending()).await;
+
+        let close = queue.recv().await.unwrap();
+        assert_eq!(close.close_frame().map(|(code, _)| code), Some(1001));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ws_loop_sends_cleared_once_when_presence_expires() {
+        let state = test_state();
+        let (mut outbox, mut queue, guards) = ws_session(&state, 16);
+        let mut expired = presence("1", 1);
+        expired.timestamp_ms = 0;
+        let filter = ProgressFilter {
+            enabled: false,
+            last_sent: Some(expired),
+        };
+        tokio::spawn(async move {
+            let mut incoming = futures_util::stream::pending::<Result<Message, ()>>();
+            ws_loop(&mut outbox, &mut incoming, guards, filter, RAW, &state).await;
+        });
+
+        let mut cleared = Vec::new();
+        for _ in 0..6 {
+            let message = queue.recv().await.unwrap();
+            if let Ok(text) = message.to_str() {
+                cleared.push(text.to_string());
+            }
+        }
+        assert_eq!(cleared, [r#"{"cleared":true,"user_id":"1"}"#]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ws_loop_tells_clients_when_the_gateway_drops() {
+        let state = test_state();
+        state.gateway.set_connected(true);
+        let (mut outbox, mut queue, guards) = ws_session(&state, 16);
+        let filter = ProgressFilter {
+            enabled: false,
+            last_sent: None,
+        };
+        let gateway = state.gateway.clone();
+        tokio::spawn(async move {
+            let mut incoming = futures_util::stream::pending::<Result<Message, ()>>();
+            ws_loop(&mut outbox, &mut incoming, guards, filter, RAW, &state).await;
+        });
+        tokio::task::yield_now().await;
+
+        gateway.set_connected(false);
+        let message = queue.recv().await.unwrap();
+        assert_eq!(
+            message.to_str().unwrap(),
+            r#"{"connected":false,"type":"gateway"}"#
+        );
+        gateway.set_connected(true);
+        let message = queue.recv().await.unwrap();
+        assert_eq!(
+            message.to_str().unwrap(),
+            r#"{"connected":true,"type":"gateway"}"#
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ws_loop_closes_on_binary_or_oversized_messages() {
+        for msg in [
+            Message::binary(vec![0u8; 4]),
+            Message::text("x".repeat(WS_MAX_MESSAGE_SIZE + 1)),
+        ] {
+            let state = test_state();
+            let (outbox, mut queue, guards) = ws_session(&state, 16);
+            let incoming =
+                futures_util::stream::iter([Ok(msg)]).chain(futures_util::stream::pending());
+            assert_ws_loop_releases(&state, outbox, guards, incoming).await;
+
+            let close = queue.recv().await.unwrap();
+            assert_eq!(close.close_frame().map(|(code, _)| code), Some(1008));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn evicting_a_user_empties_the_cache_and_closes_their_streams() {
+        let state = test_state();
+        let config = config::Config {
+            api_key: Some("secret".to_string()),
+            ..(**state.config.load()).clone()
+        };
+        state.config.store(Arc::new(config));
+        let evict = |authorization: Option<&str>| {
+            evict_handler("1".into(), authorization.map(String::from), state.clone())
+        };
+        state.cache.set("1", &presence("1", 1)).await;
+
+        let status = |reply: warp::reply::Response| reply.status();
+        assert_eq!(status(evict(None).await.unwrap()), StatusCode::UNAUTHORIZED);
+
+        let (outbox, mut queue, guards) = ws_session(&state, 16);
+        let (_, reply) = tokio::join!(
+            assert_ws_loop_releases(&state, outbox, guards, futures_util::stream::pending()),
+            evict(Some("Bearer secret")),
+        );
+        assert_eq!(status(reply.unwrap()), StatusCode::NO_CONTENT);
+        let close = queue.recv().await.unwrap();
+        assert_eq!(close.close_frame().map(|(code, _)| code), Some(1000));
+
+        assert!(state.cache.get("1").await.is_none());
+        assert_eq!(
+            status(evict(Some("Bearer secret")).await.unwrap()),
+            StatusCode::NOT_FOUND
+        );
+
+        // streams without a close frame of their own just end
+        state.cache.set("1", &presence("1", 2)).await;
+        let sse = sse_handler("1".to_string(), state.clone(), IpAddr::from([127, 0, 0, 1]))
+            .await
+            .unwrap();
+        let mut sse = sse.into_body();
+        let snapshot = http_body_util::BodyExt::frame(&mut sse).await;
+        assert!(snapshot.is_some_and(|frame| frame.unwrap().is_data()));
+        assert_eq!(
+            status(evict(Some("Bearer secret")).await.unwrap()),
+            StatusCode::NO_CONTENT
+        );
+        assert!(http_body_util::BodyExt::frame(&mut sse).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn ndjson_ends_on_shutdown() {
+        let state = test_state();
+        let ip = IpAddr::from([127, 0, 0, 1]);
+        let mut reply = stream_handler("1".to_string(), state.clone(), ip)
+            .await
+            .unwrap();
+        let mut lines = server::take_stream(&mut reply).unwrap();
+
+        state.shutdown.send_replace(true);
+        assert!(lines.next().await.is_none());
+        drop(lines);
+        assert!(state.connections.is_empty() && state.watchers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn sse_ends_on_shutdown() {
+        let state = test_state();
+        let ip = IpAddr::from([127, 0, 0, 1]);
+        let reply = sse_handler("1".to_string(), state.clone(), ip)
+            .await
+            .unwrap();
+        let mut body = reply.into_body();
+
+        state.shutdown.send_replace(true);
+        assert!(http_body_util::BodyExt::frame(&mut body).await.is_none());
+        drop(body);
+        assert!(state.connections.is_empty() && state.watchers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn ws_rejects_invalid_user_ids_before_upgrading() {
+        use tower_service::Service;
+
+        let state = test_state();
+        let route = warp::path!("ws" / "v1" / String)
+            .and(warp::ws())
+            .and(warp::query::<WsQuery>())
+            .and(with_state(state.clone()))
+            .and(warp::any().map(|| IpAddr::from([127, 0, 0, 1])))
+            .and_then(ws_upgrade_handler);
+        let mut service = warp::service(route);
+        let mut upgrade = async |path: &str| {
+            let request = warp::http::Request::get(path)
+                .header("connection", "upgrade")
+                .header("upgrade", "websocket")
+                .header("sec-websocket-version", "13")
+                .header("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==")
+                .body(http_body_util::Empty::<Bytes>::new())
+                .unwrap();
+            service.call(request).await.unwrap()
+        };
+
+        let reply = upgrade("/ws/v1/01").await;
+        assert_eq!(reply.status(), StatusCode::BAD_REQUEST);
+        let body = http_body_util::BodyExt::collect(reply.into_body())
+            .await
+            .unwrap();
+        let error: serde_json::Value = serde_json::from_slice(&body.to_bytes()).unwrap();
+        assert_eq!(error["error"]["code"], "INVALID_USER_ID");
+
+        let reply = upgrade("/ws/v1/1").await;
+        assert_eq!(reply.status(), StatusCode::SWITCHING_PROTOCOLS);
+    }
+
+    #[tokio::test]
+    async fn sse_sends_a_snapshot_then_updates() {
+        let state = test_state();
+        state.cache.set("1", &presence("1", 1)).await;
+        let ip = IpAddr::from([127, 0, 0, 1]);
+        let reply = sse_handler("1".to_string(), state.clone(), ip)
+            .await
+            .unwrap();
+        assert_eq!(reply.headers()["content-type"], "text/event-stream");
+        assert_eq!(state.connections.get(&ip).map(|n| *n), Some(1));
+
+        let mut body = reply.into_body();
+        let mut next_event = async || {
+            let frame = http_body_util::BodyExt::frame(&mut body).await;
+            let data = frame.unwrap().unwrap().into_data().unwrap();
+            String::from_utf8(data.to_vec()).unwrap()
+        };
+        let snapshot = next_event().await;
+        assert!(snapshot.starts_with("event:snapshot\ndata:{"), "{snapshot}");
+
+        state
+            .watchers
+            .get("1")
+            .unwrap()
+            .send_replace(Watched::Presence(SharedPresence::new(presence("1", 2))));
+        let update = next_event().await;
+        assert!(update.starts_with("event:presence\n"), "{update}");
+        assert!(update.contains(r#""seq":2"#), "{update}");
+
+        drop(body);
+        assert!(state.watchers.is_empty(), "watcher leaked");
+        assert!(state.connections.is_empty(), "connection slot leaked");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ws_loop_releases_guards_when_writer_is_gone() {
+        let state = test_state();
+        let (outbox, queue, guards) = ws_session(&state, 16);
+        drop(queue);
+        assert_ws_loop_releases(&state, outbox, guards, futures_util::stream::pending()).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ws_multi_loop_routes_updates_and_releases_watchers() {
+        let state = test_state();
+        let (queue, mut sent) = mpsc::channel(16);
+        let mut outbox = WsOutbox {
+            queue,
+            slow: None,
+            metrics: state.metrics.clone(),
+        };
+        let (client, mut incoming) = mpsc::unbounded_channel::<Result<Message, ()>>();
+        let mut incoming = futures_util::stream::poll_fn(move |cx| incoming.poll_recv(cx));
+
+        let session = {
+            let state = state.clone();
+            tokio::spawn(async move {
+                ws_multi_loop(&mut outbox, &mut incoming, &state, &WsQuery::default()).await;
+            })
+        };
+        let text = |json: &str| Ok(Message::text(json));
+
+        client
+            .send(text(r#"{"subscribe": ["1", "2", "x"]}"#))
+            .unwrap();
+        let error = sent.recv().await.unwrap();
+        assert!(error.to_str().unwrap().contains(r#""invalid":["x"]"#));
+        assert_eq!(state.watchers.len(), 2);
+
+        state
+            .watchers
+            .get("2")
+            .unwrap()
+            .send(Watched::Presence(SharedPresence::new(presence("2", 5))))
+            .unwrap();
+        let update = sent.recv().await.unwrap();
+        let update: PresenceData = serde_json::from_str(update.to_str().unwrap()).unwrap();
+        assert_eq!((update.user_id.as_str(), update.seq), ("2", 5));
+
+        client.send(text(r#"{"unsubscribe": ["1"]}"#)).unwrap();
+        while state.watchers.contains_key("1") {
+            tokio::task::yield_now().await;
+        }
+
+        client.send(Ok(Message::close())).unwrap();
+        session.await.unwrap();
+        assert!(state.watchers.is_empty(), "watcher leaked");
+    }
+
+    #[test]
+    fn v2_wraps_every_message_in_a_versioned_envelope() {
+        let v2 = WsFormat {
+            enveloped: true,
+            ..RAW
+        };
+        let json = |text: String| serde_json::from_str::<serde_json::Value>(&text).unwrap();
+
+        let update = json(v2.presence(&presence("1", 3), None));
+        assert_eq!(
+            (update["v"].as_u64(), update["type"].as_str()),
+            (Some(2), Some("presence"))
+        );
+        assert_eq!(update["data"]["seq"], 3);
+        assert_eq!(
+            json(v2.encode(WsEvent::Cleared { user_id: "1" })),
+            serde_json::json!({"v": 2, "type": "cleared", "data": {"user_id": "1"}})
+        );
+        assert_eq!(
+            json(v2.encode(WsEvent::Resumed)),
+            serde_json::json!({"v": 2, "type": "resumed"})
+        );
+
+        // existing clients keep the bare shapes
+        assert_eq!(json(RAW.presence(&presence("1", 3), None))["seq"], 3);
+        assert_eq!(
+            json(RAW.encode(WsEvent::Cleared { user_id: "1" })),
+            serde_json::json!({"user_id": "1", "cleared": true})
+        );
+    }
+
+    #[test]
+    fn resuming_a_gone_presence_clears_it() {
+        let json = |text: Option<String>| serde_json::from_str::<serde_json::Value>(&text?).ok();
+        let current = presence("1", 12);
+
+        assert_eq!(
+            json(ws_first_payload(RAW, "1", Some(&current), Some(12))),
+            Some(serde_json::json!({"type": "resumed"}))
+        );
+        assert_eq!(
+            json(ws_first_payload(RAW, "1", Some(&current), Some(11))).map(|p| p["seq"].clone()),
+            Some(serde_json::json!(12))
+        );
+        assert_eq!(
+            json(ws_first_payload(RAW, "1", None, Some(12))),
+            Some(serde_json::json!({"user_id": "1", "cleared": true}))
+        );
+        assert_eq!(ws_first_payload(RAW, "1", None, None), None);
+    }
+
+    /// A socket that replays `incoming` and accepts `accepted` frames before
+    /// its sends start failing.
+    struct TestSocket {
+        incoming: futures_util::stream::BoxStream<'static, Result<Message, ()>>,
+        accepted: usize,
+    }
+
+    impl Stream for TestSocket {
+        type Item = Result<Message, ()>;
+
+        fn poll_next(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Option<Self::Item>> {
+            self.incoming.as_mut().poll_next(cx)
+        }
+    }
+
+    impl Sink<Message> for TestSocket {
+        type Error = ();
+
+        fn poll_ready(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Result<(), ()>> {
+            std::task::Poll::Ready(if self.accepted > 0 { Ok(()) } else { Err(()) })
+        }
+
+        fn start_send(mut self: std::pin::Pin<&mut Self>, _: Message) -> Result<(), ()> {
+            self.accepted -= 1;
+            Ok(())
+        }
+
+        fn poll_flush(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Result<(), ()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Result<(), ()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    /// Runs `ws_handler` for user "1" on `socket` and checks it let go of
+    /// the watcher and its cache entry.
+    async fn assert_ws_handler_releases(state: &AppState, socket: TestSocket, query: WsQuery) {
+        let conn = try_acquire_connection(
+            &state.connections,
+            "127.0.0.1".parse().unwrap(),
+            state.config.load().max_connections_per_ip,
+        )
+        .unwrap();
+        let exited = timeout(
+            Duration::from_secs(60),
+            ws_handler(socket, "1".to_string(), state.clone(), conn, query),
+        )
+        .await;
+
+        assert!(exited.is_ok(), "ws_handler didn't exit");
+        assert!(state.watchers.is_empty(), "watcher leaked");
+        assert!(
+            !state.cache.get_memory().contains_key("1"),
+            "cache entry leaked"
+        );
+        assert!(state.connections.is_empty(), "connection slot leaked");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ws_handler_releases_watcher_on_disconnect_during_resume() {
+        let state = test_state();
+        let socket = TestSocket {
+            incoming: futures_util::stream::empty().boxed(),
+            accepted: usize::MAX,
+        };
+        let query = WsQuery {
+            resume: Some("1".to_string()),
+            ..WsQuery::default()
+        };
+        assert_ws_handler_releases(&state, socket, query).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ws_handler_releases_watcher_when_snapshot_send_fails() {
+        let state = test_state();
+        state.cache.set("1", &presence("1", 1)).await;
+        // the ready frame goes out, the snapshot doesn't
+        let socket = TestSocket {
+            incoming: futures_util::stream::pending().boxed(),
+            accepted: 1,
+        };
+        assert_ws_handler_releases(&state, socket, WsQuery::default()).await;
+    }
+
+    #[tokio::test]
+    async fn torn_down_watcher_can_be_resubscribed() {
+        let state = test_state();
+        let Watching { mut rx, guard } = subscribe(&state, "1").unwrap();
+
+        // simulate the sender going away underneath a live connection
+        state.watchers.remove("1");
+        assert!(rx.changed().await.is_err());
+
+        let mut rx = guard.resubscribe();
+        state
+            .watchers
+            .get("1")
+            .unwrap()
+            .send(Watched::Presence(SharedPresence::new(presence("1", 2))))
+            .unwrap();
+        rx.changed().await.unwrap();
+        assert_eq!(rx.borrow().presence().unwrap().presence.seq, 2);
+    }
+
+    #[tokio::test]
+    async fn last_watcher_leaves_the_cache_to_polling_clients() {
+        let state = test_state();
+        let subscribed = subscribe(&state, "1").unwrap();
+        state.cache.set("1", &presence("1", 1)).await;
+        state.register_interest("1");
+        drop(subscribed);
+        assert!(state.watchers.is_empty());
+        assert!(state.cache.get_memory().contains_key("1"));
+
+        let subscribed = subscribe(&state, "2").unwrap();
+        state.cache.set("2", &presence("2", 1)).await;
+        drop(subscribed);
+        assert!(!state.cache.get_memory().contains_key("2"));
+    }
+
+    #[tokio::test]
+    async fn concurrent_subscribe_and_disconnect_keeps_watcher_consistent() {
+        let state = test_state();
+        let _watching = subscribe(&state, "1").unwrap();
+
+        let tasks: Vec<_> = (0..64)
+            .map(|_| {
+                let state = state.clone();
+                tokio::spawn(async move {
+                    let watching = subscribe(&state, "1").unwrap();
+                    tokio::task::yield_now().await;
+                    drop(watching);
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        let watcher = state.watchers.get("1").expect("watcher still registered");
+        assert_eq!(watcher.receiver_count(), 1);
+    }
+
+    #[test]
+    fn forwarded_for_skips_trusted_hops() {
+        let xff = "6.6.6.6, 203.0.113.7, 10.0.0.2";
+        assert_eq!(forwarded_client_ip(xff, 0), None);
+        assert_eq!(forwarded_client_ip(xff, 1), "10.0.0.2".parse().ok());
+        assert_eq!(forwarded_client_ip(xff, 2), "203.0.113.7".parse().ok());
+        assert_eq!(forwarded_client_ip(xff, 4), None);
+        assert_eq!(forwarded_client_ip("garbage", 1), None);
+    }
+
+    #[test]
+    fn client_ip_falls_back_to_the_peer_address() {
+        let mut config = (**test_state().config.load()).clone();
+        let peer = IpAddr::from([203, 0, 113, 7]);
+
+        assert_eq!(client_ip(&config, None, None, Some(peer)), peer);
+        assert_eq!(
+            client_ip(&config, None, Some("6.6.6.6"), Some(peer)),
+            peer,
+            "X-Forwarded-For is ignored without trusted proxies"
+        );
+        assert_eq!(
+            client_ip(&config, Some("198.51.100.1"), None, Some(peer)),
+            IpAddr::from([198, 51, 100, 1])
+        );
+        config.trusted_proxy_hops = 1;
+        assert_eq!(
+            client_ip(&config, None, Some("6.6.6.6"), Some(peer)),
+            IpAddr::from([6, 6, 6, 6])
+        );
+        let mapped: IpAddr = "::ffff:203.0.113.7".parse().unwrap();
+        assert_eq!(client_ip(&config, None, None, Some(mapped)), peer);
+    }
+
+    #[test]
+    fn normalize_strips_mentions() {
+        assert_eq!(normalize_user_id("123".into()), "123");
+        assert_eq!(normalize_user_id("<@123>".into()), "123");
+        assert_eq!(normalize_user_id("<@!123>".into()), "123");
+        assert_eq!(normalize_user_id("%3C%40123%3E".into()), "123");
+        assert_eq!(normalize_user_id("%3c%40%21123%3e".into()), "123");
+        assert_eq!(normalize_user_id("<@123".into()), "<@123");
+        assert!(!validate_user_id(&normalize_user_id("<@abc>".into())));
+    }
+
+    #[test]
+    fn user_ids_must_be_snowflakes() {
+        assert_eq!(parse_user_id("492731761680187403"), Ok(492731761680187403));
+        assert_eq!(parse_user_id("18446744073709551615"), Ok(u64::MAX));
+        for invalid in [
+            "",
+            "0",
+            "00",
+            "0123",
+            "18446744073709551616",
+            "99999999999999999999",
+        ] {
+            assert!(parse_user_id(invalid).is_err(), "{invalid:?} was accepted");
+        }
+        assert!(parse_user_id("00").unwrap_err().contains("can't be 0"));
+        assert!(
+            parse_user_id("18446744073709551616")
+                .unwrap_err()
+                .contains("too large")
+        );
+    }
+
+    #[test]
+    fn connection_limit_rejects_past_max() {
+        let connections: ConnectionCounter = Arc::new(DashMap::new());
+        let ip = IpAddr::from([10, 0, 0, 1]);
+
+        let guards: Vec<_> = (0..3)
+            .map(|_| try_acquire_connection(&connections, ip, 3).expect("slot available"))
+            .collect();
+
+        assert_eq!(guards.len(), 3);
+        assert!(try_acquire_connection(&connections, ip, 3).is_none());
+        assert_eq!(*connections.get(&ip).unwrap(), 3);
+    }
+
+    #[test]
+    fn dropping_guard_frees_slot() {
+        let connections: ConnectionCounter = Arc::new(DashMap::new());
+        let ip = IpAddr::from([10, 0, 0, 1]);
+
+        let mut guards: Vec<_> = (0..2)
+            .map(|_| try_acquire_connection(&connections, ip, 2).unwrap())
+            .collect();
+        assert!(try_acquire_connection(&connections, ip, 2).is_none());
+
+        guards.pop();
+        assert_eq!(*connections.get(&ip).unwrap(), 1);
+        assert!(try_acquire_connection(&connections, ip, 2).is_some());
+    }
+
+    #[test]
+    fn dropping_all_guards_removes_ip_entry() {
+        let connections: ConnectionCounter = Arc::new(DashMap::new());
+        let ip = IpAddr::from([10, 0, 0, 1]);
+        let other = IpAddr::from([10, 0, 0, 2]);
+
+        let guards: Vec<_> = (0..2)
+            .map(|_| try_acquire_connection(&connections, ip, 2).unwrap())
+            .collect();
+        let _other_guard = try_acquire_connection(&connections, other, 2).unwrap();
+
+        drop(guards);
+        assert!(!connections.contains_key(&ip));
+        assert_eq!(*connections.get(&other).unwrap(), 1);
+    }
+
+    #[test]
+    fn total_connection_limit_spans_ips() {
+        let state = test_state();
+        let config = config::Config {
+            max_total_connections: 2,
+            ..(**state.config.load()).clone()
+        };
+        state.config.store(Arc::new(config));
+        let (a, b, c) = (
+            IpAddr::from([10, 0, 0, 1]),
+            IpAddr::from([10, 0, 0, 2]),
+            IpAddr::from([10, 0, 0, 3]),
+        );
+
+        let first = acquire_connection(&state, a).unwrap();
+        let _second = acquire_connection(&state, b).unwrap();
+        assert_eq!(
+            connection_limit_reached(&state, c),
+            Some(ConnectionLimit::Total)
+        );
+        assert_eq!(
+            acquire_connection(&state, c).err(),
+            Some(ConnectionLimit::Total)
+        );
+        // the per-IP slot taken on the way is given back
+        assert!(!state.connections.contains_key(&c));
+        assert_eq!(state.metrics.total_connection_rejections.get(), 1);
+
+        drop(first);
+        assert!(acquire_connection(&state, c).is_ok());
+    }
+
+    #[test]
+    fn connection_limit_replies_ask_clients_to_back_off() {
+        for (limit, status) in [
+            (ConnectionLimit::PerIp, StatusCode::TOO_MANY_REQUESTS),
+            (ConnectionLimit::Total, StatusCode::SERVICE_UNAVAILABLE),
+        ] {
+            let reply = limit.reply();
+            assert_eq!(reply.status(), status);
+            assert_eq!(reply.headers()["retry-after"], "10");
+            assert_eq!(
+                limit.close().close_frame().map(|(code, _)| code),
+                Some(1013)
+            );
+        }
+    }
 }
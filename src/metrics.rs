@@ -0,0 +1,172 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use dashmap::DashMap;
+
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub dropped_presence_updates: Counter,
+    pub slow_clients: Counter,
+    pub watcher_limit_rejections: Counter,
+    pub subscriber_limit_rejections: Counter,
+    pub total_connection_rejections: Counter,
+    /// `GET /v1/{id}` answered from the cache, or not found.
+    pub cache_hits: Counter,
+    pub cache_misses: Counter,
+    pub discord_reconnects: Counter,
+    #[cfg(feature = "nats")]
+    pub dropped_nats_publishes: Counter,
+    /// Gateway events received, by event type.
+    gateway_events: DashMap<&'static str, Counter>,
+}
+
+/// Values read off the app state when `/metrics` is scraped.
+pub struct Gauges {
+    pub active_connections: usize,
+    /// `MAX_TOTAL_CONNECTIONS`, 0 without a limit.
+    pub max_total_connections: usize,
+    pub watched_users: usize,
+    pub gateway_connected: bool,
+}
+
+impl Metrics {
+    pub fn gateway_event(&self, kind: &'static str) {
+        match self.gateway_events.get(kind) {
+            Some(counter) => counter.inc(),
+            None => self.gateway_events.entry(kind).or_default().inc(),
+        }
+    }
+
+    pub fn gateway_events(&self) -> BTreeMap<&'static str, u64> {
+        self.gateway_events
+            .iter()
+            .map(|entry| (*entry.key(), entry.value().get()))
+            .collect()
+    }
+
+    /// Everything in the Prometheus text format, for `GET /metrics`.
+    pub fn prometheus(&self, gauges: &Gauges) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+            let _ = writeln!(out, "# HELP presence_{name} {help}");
+            let _ = writeln!(out, "# TYPE presence_{name} {kind}");
+            let _ = writeln!(out, "presence_{name} {value}");
+        };
+
+        metric(
+            "active_connections",
+            "gauge",
+            "Open WebSocket and NDJSON connections.",
+            gauges.active_connections as u64,
+        );
+        metric(
+            "max_total_connections",
+            "gauge",
+            "MAX_TOTAL_CONNECTIONS, 0 without a limit.",
+            gauges.max_total_connections as u64,
+        );
+        metric(
+            "watched_users",
+            "gauge",
+            "Distinct users watched by at least one stream.",
+            gauges.watched_users as u64,
+        );
+        metric(
+            "gateway_connected",
+            "gauge",
+            "Whether the Discord gateway is connected.",
+            gauges.gateway_connected as u64,
+        );
+        metric(
+            "updates_total",
+            "counter",
+            "Presence updates received from the gateway.",
+            self.gateway_events()
+                .get("presence_update")
+                .copied()
+                .unwrap_or(0),
+        );
+        metric(
+            "cache_hits_total",
+            "counter",
+            "GET /v1/{id} requests answered with a presence.",
+            self.cache_hits.get(),
+        );
+        metric(
+            "cache_misses_total",
+            "counter",
+            "GET /v1/{id} requests without a current presence.",
+            self.cache_misses.get(),
+        );
+        metric(
+            "discord_reconnects_total",
+            "counter",
+            "Times the Discord client was restarted.",
+            self.discord_reconnects.get(),
+        );
+        metric(
+            "dropped_presence_updates_total",
+            "counter",
+            "Presence updates dropped because the queue was full.",
+            self.dropped_presence_updates.get(),
+        );
+        metric(
+            "slow_clients_total",
+            "counter",
+            "WebSocket clients disconnected for not reading.",
+            self.slow_clients.get(),
+        );
+        metric(
+            "watcher_limit_rejections_total",
+            "counter",
+            "Streams refused because MAX_WATCHED_USERS was reached.",
+            self.watcher_limit_rejections.get(),
+        );
+        metric(
+            "subscriber_limit_rejections_total",
+            "counter",
+            "Streams refused because MAX_SUBSCRIBERS_PER_USER was reached for their user.",
+            self.subscriber_limit_rejections.get(),
+        );
+        metric(
+            "total_connection_rejections_total",
+            "counter",
+            "Connections refused because MAX_TOTAL_CONNECTIONS was reached.",
+            self.total_connection_rejections.get(),
+        );
+        #[cfg(feature = "nats")]
+        metric(
+            "dropped_nats_publishes_total",
+            "counter",
+            "Presence updates that couldn't be published to NATS.",
+            self.dropped_nats_publishes.get(),
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP presence_gateway_events_total Gateway events received, by type."
+        );
+        let _ = writeln!(out, "# TYPE presence_gateway_events_total counter");
+        for (kind, count) in self.gateway_events() {
+            let _ = writeln!(
+                out,
+                "presence_gateway_events_total{{type=\"{kind}\"}} {count}"
+            );
+        }
+        out
+    }
+}
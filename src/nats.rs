@@ -0,0 +1,75 @@
+use std::sync::Arc;
+
+use bytes::Bytes;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+use crate::SharedPresence;
+use crate::config::Config;
+use crate::metrics::Metrics;
+
+/// Updates waiting to be published. Beyond this (broker down or slow) new
+/// updates are dropped and counted rather than buffered.
+const PUBLISH_BUFFER: usize = 1024;
+
+/// Publishes presence updates to NATS for downstream consumers. Publishing
+/// never blocks the update pipeline and a missing broker is not fatal.
+#[derive(Clone)]
+pub struct Sink {
+    updates: mpsc::Sender<(String, Bytes)>,
+    subject: Arc<str>,
+    metrics: Arc<Metrics>,
+}
+
+impl Sink {
+    /// Starts the publisher if `NATS_URL` is set.
+    pub fn from_config(config: &Config, metrics: Arc<Metrics>) -> Option<Self> {
+        let url = config.nats_url.clone()?;
+        let (updates, rx) = mpsc::channel(PUBLISH_BUFFER);
+        tokio::spawn(publish(url, rx, metrics.clone()));
+
+        Some(Self {
+            updates,
+            subject: config.nats_subject.as_str().into(),
+            metrics,
+        })
+    }
+
+    pub fn publish(&self, shared: &SharedPresence) {
+        let subject = self.subject.replace("{user_id}", &shared.presence.user_id);
+        if self
+            .updates
+            .try_send((subject, shared.json_bytes()))
+            .is_err()
+        {
+            self.metrics.dropped_nats_publishes.inc();
+        }
+    }
+}
+
+async fn publish(url: String, mut rx: mpsc::Receiver<(String, Bytes)>, metrics: Arc<Metrics>) {
+    // connects in the background and keeps reconnecting, so a broker that is
+    // down at startup doesn't stop the service
+    let client = match async_nats::ConnectOptions::new()
+        .retry_on_initial_connect()
+        .connect(url.as_str())
+        .await
+    {
+        Ok(client) => client,
+        Err(err) => {
+            warn!(?err, "invalid nats configuration, not publishing presence");
+            while rx.recv().await.is_some() {
+                metrics.dropped_nats_publishes.inc();
+            }
+            return;
+        }
+    };
+    info!("publishing presence to nats");
+
+    while let Some((subject, payload)) = rx.recv().await {
+        if let Err(err) = client.publish(subject, payload).await {
+            warn!(?err, "failed to publish presence to nats");
+            metrics.dropped_nats_publishes.inc();
+        }
+    }
+}
@@ -0,0 +1,299 @@
+//! The OpenAPI 3 description served at `GET /openapi.json`.
+//!
+//! Paths are written out by hand. The presence schemas are derived from the
+//! serde types with `schemars`, so field shapes can't drift from the responses.
+
+use presence::PresenceData;
+use schemars::generate::SchemaSettings;
+use serde_json::{Value, json};
+
+const GET_PRESENCE: &str = "Adds `progress_ms`, `duration_ms`, `remaining_ms` and \
+    `should_refresh` to `spotify`. With `?tz=` \
+    the timestamps are also given as ISO 8601 (`timestamp`, `spotify.started_at`, \
+    `spotify.ends_at`), with `?flatten=1` the Spotify fields move to the top level.";
+const WS_PRESENCE: &str = "Sends `{\"type\": \"ready\", \"user_id\": ...}`, the current \
+    presence, then a `PresenceData` text message per update. Once the presence expires without an update it sends \
+    `{\"user_id\": ..., \"cleared\": true}`. Sends `{\"type\": \"gateway\", \"connected\": ...}` when the \
+    Discord gateway drops or comes back. Closes with 1001 on shutdown.";
+const WS_VERSION: &str = "`2` wraps every message as `{\"v\": 2, \"type\": ..., \"data\": ...}`, \
+    e.g. `type: presence` with the `PresenceData` as `data`";
+const EVICT_PRESENCE: &str = "Removes the presence from memory and Redis, on every instance \
+    sharing the Redis. WebSockets on `/ws/v1/{userid}` close with 1000, `/ws/v1` drops the \
+    subscription and sends `cleared`, NDJSON, SSE and gRPC streams end.";
+const SSE_PRESENCE: &str = "A `snapshot` event with the current `PresenceData`, if there is \
+    one, then a `presence` event per update. Idle streams get a comment line every 15s.";
+const NOT_A_MEMBER: &str = "Not a guild member, with `REQUIRE_MEMBERSHIP`";
+const RATE_LIMITED: &str = "`RATE_LIMIT_BURST` used up, retry after `Retry-After` seconds";
+const WS_ONCE: &str = "`1` sends the current presence, or `cleared` without one, then closes \
+    with 1000";
+const WS_RESUME: &str = "`1` waits up to 500ms for a `resume` op as the first frame before \
+    sending the snapshot";
+const PROGRESS_UPDATES: &str = "`0` skips updates where only the Spotify progress changed";
+
+fn json_response(description: &str, schema: &str) -> Value {
+    json!({
+        "description": description,
+        "content": {
+            "application/json": {"schema": {"$ref": format!("#/components/schemas/{schema}")}}
+        }
+    })
+}
+
+fn user_id_parameter() -> Value {
+    json!({
+        "name": "userid",
+        "in": "path",
+        "required": true,
+        "description": "Discord user id",
+        "schema": {"type": "string", "pattern": "^[1-9][0-9]{0,19}$"}
+    })
+}
+
+fn flag_parameter(name: &str, description: &str) -> Value {
+    json!({
+        "name": name,
+        "in": "query",
+        "required": false,
+        "description": description,
+        "schema": {"type": "string", "enum": ["0", "1", "true", "false"]}
+    })
+}
+
+pub fn document() -> Value {
+    let mut generator = SchemaSettings::openapi3().into_generator();
+    generator.subschema_for::<PresenceData>();
+    let mut schemas = generator.take_definitions(true);
+    schemas.insert(
+        "NoPresence".to_string(),
+        json!({
+            "description": "A guild member without a current presence",
+            "type": "object",
+            "required": ["user_id", "online", "spotify"],
+            "properties": {
+                "user_id": {"type": "string"},
+                "online": {"type": "boolean", "enum": [false]},
+                "spotify": {"nullable": true, "enum": [null]}
+            }
+        }),
+    );
+    schemas.insert(
+        "Membership".to_string(),
+        json!({
+            "type": "object",
+            "required": ["in_server", "guilds"],
+            "properties": {
+                "in_server": {"type": "boolean"},
+                "guilds": {
+                    "description": "The configured guilds the user is a member of",
+                    "type": "array",
+                    "items": {"type": "string"}
+                }
+            }
+        }),
+    );
+    schemas.insert(
+        "Error".to_string(),
+        json!({
+            "type": "object",
+            "required": ["error"],
+            "properties": {
+                "error": {
+                    "type": "object",
+                    "required": ["code", "message"],
+                    "properties": {
+                        "code": {"type": "string", "example": "INVALID_USER_ID"},
+                        "message": {"type": "string"}
+                    }
+                }
+            }
+        }),
+    );
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "presence",
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "Discord presence (Spotify, games, custom status) of guild members"
+        },
+        "paths": {
+            "/v1/{userid}": {
+                "get": {
+                    "summary": "Current presence of a user",
+                    "description": GET_PRESENCE,
+                    "parameters": [
+                        user_id_parameter(),
+                        {
+                            "name": "tz",
+                            "in": "query",
+                            "required": false,
+                            "description": "IANA timezone for the ISO 8601 timestamps",
+                            "schema": {"type": "string"}
+                        },
+                        {
+                            "name": "min_seq",
+                            "in": "query",
+                            "required": false,
+                            "description": "Wait up to 2s for the presence to reach this `seq`",
+                            "schema": {"type": "integer", "format": "uint64", "minimum": 0}
+                        },
+                        flag_parameter("flatten", "Put the Spotify fields at the top level"),
+                    ],
+                    "responses": {
+                        "200": {
+                            "description": "The presence, or `online: false` without one",
+                            "content": {
+                                "application/json": {
+                                    "schema": {
+                                        "oneOf": [
+                                            {"$ref": "#/components/schemas/PresenceData"},
+                                            {"$ref": "#/components/schemas/NoPresence"}
+                                        ]
+                                    }
+                                }
+                            }
+                        },
+                        "400": json_response("Invalid user id or timezone", "Error"),
+                        "403": json_response(NOT_A_MEMBER, "Error"),
+                        "404": json_response("Not a member of any configured guild", "Error"),
+                        "425": json_response("`min_seq` wasn't reached in time", "Error"),
+                        "429": json_response(RATE_LIMITED, "Error")
+                    }
+                },
+                "delete": {
+                    "summary": "Evict a user's cached presence",
+                    "description": EVICT_PRESENCE,
+                    "parameters": [user_id_parameter()],
+                    "security": [{"apiKey": []}],
+                    "responses": {
+                        "204": {"description": "The presence was evicted"},
+                        "400": json_response("Invalid user id", "Error"),
+                        "401": json_response("Missing or wrong `API_KEY`", "Error"),
+                        "404": json_response("Nothing was cached for the user", "Error")
+                    }
+                }
+            },
+            "/v1/{userid}/in_server": {
+                "get": {
+                    "summary": "Whether a user is a member of the configured guilds",
+                    "parameters": [user_id_parameter()],
+                    "security": [{}, {"apiKey": []}],
+                    "responses": {
+                        "200": json_response("Membership", "Membership"),
+                        "400": json_response("Invalid user id", "Error"),
+                        "401": json_response("Missing or wrong `API_KEY`, once set", "Error"),
+                        "429": json_response(RATE_LIMITED, "Error"),
+                        "503": json_response("The bot isn't in any configured guild", "Error")
+                    }
+                }
+            },
+            "/v1/{userid}/spotify": {
+                "get": {
+                    "summary": "Just the Spotify activity of a user",
+                    "description": "The `spotify` object of `GET /v1/{userid}`.",
+                    "parameters": [user_id_parameter()],
+                    "responses": {
+                        "200": json_response("What the user is listening to", "SpotifyActivity"),
+                        "204": {"description": "The user has a presence but isn't listening"},
+                        "400": json_response("Invalid user id", "Error"),
+                        "403": json_response(NOT_A_MEMBER, "Error"),
+                        "404": json_response("No presence for the user", "Error"),
+                        "429": json_response(RATE_LIMITED, "Error")
+                    }
+                }
+            },
+            "/sse/v1/{userid}": {
+                "get": {
+                    "summary": "Server-Sent Events with a user's presence updates",
+                    "description": SSE_PRESENCE,
+                    "parameters": [user_id_parameter()],
+                    "responses": {
+                        "200": {
+                            "description": "The event stream",
+                            "content": {"text/event-stream": {"schema": {"type": "string"}}}
+                        },
+                        "400": json_response("Invalid user id", "Error"),
+                        "403": json_response(NOT_A_MEMBER, "Error"),
+                        "429": json_response("`MAX_CONNECTIONS_PER_IP` reached", "Error"),
+                        "503": json_response(
+                            "`MAX_TOTAL_CONNECTIONS` or `MAX_WATCHED_USERS` reached",
+                            "Error"
+                        )
+                    }
+                }
+            },
+            "/ws/v1/{userid}": {
+                "get": {
+                    "summary": "WebSocket with a user's presence updates",
+                    "description": WS_PRESENCE,
+                    "parameters": [
+                        user_id_parameter(),
+                        flag_parameter("progress_updates", PROGRESS_UPDATES),
+                        flag_parameter("flatten", "Put the Spotify fields at the top level"),
+                        flag_parameter("once", WS_ONCE),
+                        flag_parameter("resume", WS_RESUME),
+                        {
+                            "name": "v",
+                            "in": "query",
+                            "required": false,
+                            "description": WS_VERSION,
+                            "schema": {"type": "string", "enum": ["2"]}
+                        },
+                    ],
+                    "responses": {
+                        "101": {
+                            "description": "Switching to the WebSocket protocol",
+                            "content": {
+                                "application/json": {
+                                    "schema": {"$ref": "#/components/schemas/PresenceData"}
+                                }
+                            }
+                        },
+                        "400": json_response("Invalid user id", "Error"),
+                        "403": json_response(NOT_A_MEMBER, "Error"),
+                        "503": json_response("`MAX_WATCHED_USERS` reached", "Error")
+                    }
+                }
+            }
+        },
+        "components": {
+            "schemas": schemas,
+            "securitySchemes": {
+                "apiKey": {"type": "http", "scheme": "bearer", "description": "`API_KEY`"}
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_schema_reference_resolves() {
+        fn refs<'a>(value: &'a Value, out: &mut Vec<&'a str>) {
+            match value {
+                Value::Object(map) => {
+                    if let Some(Value::String(target)) = map.get("$ref") {
+                        out.push(target);
+                    }
+                    map.values().for_each(|v| refs(v, out));
+                }
+                Value::Array(items) => items.iter().for_each(|v| refs(v, out)),
+                _ => {}
+            }
+        }
+
+        let document = document();
+        let mut targets = Vec::new();
+        refs(&document, &mut targets);
+        assert!(targets.contains(&"#/components/schemas/PresenceData"));
+        for target in targets {
+            let name = target.strip_prefix("#/components/schemas/").unwrap();
+            assert!(
+                document["components"]["schemas"].get(name).is_some(),
+                "{target} doesn't resolve"
+            );
+        }
+    }
+}
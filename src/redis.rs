@@ -1,15 +1,19 @@
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, LazyLock};
 use std::time::Duration;
 
 use dashmap::DashMap;
+use futures_util::StreamExt;
 use redis::AsyncCommands;
-use redis::aio::ConnectionManager;
-use tokio::sync::OnceCell;
-use tracing::{info, warn};
+use redis::aio::{ConnectionManager, PubSubStream};
+use serde::Deserialize;
+use tokio::sync::{OnceCell, mpsc};
+use tracing::{debug, info, warn};
 
-use crate::PresenceData;
-
-const CACHE_TTL_SECS: u64 = 300;
+use crate::config::Config;
+use crate::config::SharedConfig;
+use crate::{PresenceData, SharedPresence, UserWatchers, Watched};
 
 static REDIS_CLIENT: OnceCell<Option<ConnectionManager>> = OnceCell::const_new();
 
@@ -50,52 +54,309 @@ pub fn is_redis_available() -> bool {
     REDIS_CLIENT.get().map(|opt| opt.is_some()).unwrap_or(false)
 }
 
-async fn get_redis() -> Option<ConnectionManager> {
+pub async fn get_redis() -> Option<ConnectionManager> {
     REDIS_CLIENT.get()?.clone()
 }
 
+/// Whether Redis answers a `PING` within a second. False when not configured.
+pub async fn ping() -> bool {
+    let Some(mut redis) = get_redis().await else {
+        return false;
+    };
+    let pong = tokio::time::timeout(
+        Duration::from_secs(1),
+        redis::cmd("PING").query_async::<String>(&mut redis),
+    )
+    .await;
+    matches!(pong, Ok(Ok(_)))
+}
+
+/// Hashes of open connections per client IP, one per instance under
+/// `<name>:<instance>`. Named with [`Config::redis_key`], like every key and
+/// channel below.
+const CONNECTIONS_KEY: &str = "connections:by_ip";
+/// How long an instance's hash outlives its last refresh, so the counts of an
+/// instance that crashed go away on their own.
+const CONNECTIONS_TTL: Duration = Duration::from_secs(60);
+const CONNECTIONS_REFRESH: Duration = Duration::from_secs(20);
+
+/// Mirrors this instance's open connections per client IP into its own Redis
+/// hash, for `MIRROR_CONNECTION_COUNTS`. Readers sum the hashes of all
+/// instances. Changes go through one queue and are applied in order, never
+/// holding up the connection.
+#[derive(Debug, Clone)]
+pub struct ConnectionMirror(mpsc::UnboundedSender<(IpAddr, i64)>);
+
+impl ConnectionMirror {
+    pub fn start(config: &Config) -> Self {
+        let key = config.redis_key(&format!("{CONNECTIONS_KEY}:{}", *INSTANCE_ID));
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(mirror_connections(key, rx));
+        Self(tx)
+    }
+
+    /// Adjusts `ip`'s count by `delta`.
+    pub fn add(&self, ip: IpAddr, delta: i64) {
+        let _ = self.0.send((ip, delta));
+    }
+}
+
+/// Applies `delta` to `ip`'s count, dropping it at zero, and returns the count.
+fn count_connection(counts: &mut HashMap<IpAddr, i64>, ip: IpAddr, delta: i64) -> i64 {
+    let count = counts.entry(ip).or_default();
+    *count += delta;
+    let count = *count;
+    if count <= 0 {
+        counts.remove(&ip);
+    }
+    count
+}
+
+/// Keeps `key` in line with the counts until the last [`ConnectionMirror`] is
+/// gone. Each change is written as the new count, and the whole hash is
+/// rewritten on every refresh, so failed writes or a Redis restart heal.
+async fn mirror_connections(key: String, mut changes: mpsc::UnboundedReceiver<(IpAddr, i64)>) {
+    let mut counts = HashMap::new();
+    let mut refresh = tokio::time::interval(CONNECTIONS_REFRESH);
+    let ttl = CONNECTIONS_TTL.as_secs() as i64;
+
+    loop {
+        let mut pipe = redis::pipe();
+        tokio::select! {
+            change = changes.recv() => {
+                let Some((ip, delta)) = change else {
+                    break;
+                };
+                let field = ip.to_string();
+                match count_connection(&mut counts, ip, delta) {
+                    count if count > 0 => pipe.hset(&key, field, count),
+                    _ => pipe.hdel(&key, field),
+                };
+                pipe.expire(&key, ttl);
+            }
+            _ = refresh.tick() => {
+                let fields: Vec<_> = counts.iter().map(|(ip, n)| (ip.to_string(), *n)).collect();
+                pipe.atomic().del(&key);
+                if !fields.is_empty() {
+                    pipe.hset_multiple(&key, &fields).expire(&key, ttl);
+                }
+            }
+        }
+
+        let Some(mut redis) = get_redis().await else {
+            continue;
+        };
+        let result: redis::RedisResult<()> = pipe.query_async(&mut redis).await;
+        if let Err(err) = result {
+            debug!(?err, "failed to mirror connection counts");
+        }
+    }
+}
+
+/// Channel presence updates are fanned out on with `REDIS_PUBSUB`,
+/// `presence:updates` by default.
+const UPDATES_CHANNEL: &str = "updates";
+/// Channel `DELETE /v1/{id}` is announced on, `presence:evictions` by default.
+const EVICTIONS_CHANNEL: &str = "evictions";
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(1);
+
+/// Tells this instance's own messages on the updates and evictions channels
+/// apart.
+static INSTANCE_ID: LazyLock<String> = LazyLock::new(|| {
+    let started = chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default();
+    format!("{}-{started}", std::process::id())
+});
+
+#[derive(Deserialize)]
+struct Relayed {
+    origin: String,
+    presence: PresenceData,
+}
+
+#[derive(Deserialize)]
+struct Eviction {
+    origin: String,
+    user_id: String,
+}
+
+/// Publishes an update on the updates channel for the other instances. No-op
+/// without Redis.
+pub async fn publish_presence(config: &Config, shared: &SharedPresence) {
+    let Some(mut redis) = get_redis().await else {
+        return;
+    };
+    let message = format!(
+        r#"{{"origin":"{}","presence":{}}}"#,
+        *INSTANCE_ID,
+        shared.json()
+    );
+    let channel = config.redis_key(UPDATES_CHANNEL);
+    let result: redis::RedisResult<()> = redis.publish(channel, message).await;
+    if let Err(err) = result {
+        debug!(?err, "failed to publish presence update");
+    }
+}
+
+/// Feeds updates other instances publish into the local watchers, so clients
+/// connected here get presence whose gateway events land elsewhere. Keeps
+/// resubscribing until the process exits.
+pub async fn relay_presence_updates(config: SharedConfig, watchers: UserWatchers) {
+    let Ok(url) = std::env::var("REDIS_URL") else {
+        warn!("REDIS_PUBSUB is set without REDIS_URL, not relaying presence updates");
+        return;
+    };
+    let channel = config.load().redis_key(UPDATES_CHANNEL);
+    follow(&url, &channel, "presence updates", |payload| {
+        relay(&watchers, payload)
+    })
+    .await;
+}
+
+/// Tells the other instances to drop their copy of an evicted presence and
+/// close the streams watching the user. No-op without Redis.
+pub async fn publish_eviction(config: &Config, user_id: &str) {
+    let Some(mut redis) = get_redis().await else {
+        return;
+    };
+    let message = serde_json::json!({"origin": *INSTANCE_ID, "user_id": user_id}).to_string();
+    let channel = config.redis_key(EVICTIONS_CHANNEL);
+    let result: redis::RedisResult<()> = redis.publish(channel, message).await;
+    if let Err(err) = result {
+        warn!(?err, user_id, "failed to publish eviction");
+    }
+}
+
+/// Applies evictions made on other instances to this one's memory cache and
+/// watchers. Keeps resubscribing until the process exits.
+pub async fn relay_evictions(config: SharedConfig, cache: Arc<Cache>, watchers: UserWatchers) {
+    let Ok(url) = std::env::var("REDIS_URL") else {
+        return;
+    };
+    let channel = config.load().redis_key(EVICTIONS_CHANNEL);
+    follow(&url, &channel, "evictions", |payload| {
+        evict(&cache, &watchers, payload)
+    })
+    .await;
+}
+
+/// Hands every message on `channel` to `handle`, resubscribing whenever the
+/// subscription drops.
+async fn follow(url: &str, channel: &str, what: &str, mut handle: impl FnMut(&[u8])) {
+    loop {
+        match subscribe(url, channel).await {
+            Ok(mut messages) => {
+                info!("subscribed to {what}");
+                while let Some(message) = messages.next().await {
+                    handle(message.get_payload_bytes());
+                }
+                warn!("{what} subscription lost, resubscribing");
+            }
+            Err(err) => warn!(?err, "failed to subscribe to {what}"),
+        }
+        tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+    }
+}
+
+async fn subscribe(url: &str, channel: &str) -> redis::RedisResult<PubSubStream> {
+    let mut pubsub = redis::Client::open(url)?.get_async_pubsub().await?;
+    pubsub.subscribe(channel).await?;
+    Ok(pubsub.into_on_message())
+}
+
+fn relay(watchers: &UserWatchers, payload: &[u8]) {
+    let relayed = match serde_json::from_slice::<Relayed>(payload) {
+        Ok(relayed) => relayed,
+        Err(err) => {
+            debug!(?err, "ignoring malformed presence update");
+            return;
+        }
+    };
+    // this instance already delivered its own updates
+    if relayed.origin == *INSTANCE_ID {
+        return;
+    }
+    if let Some(watcher) = watchers.get(&relayed.presence.user_id) {
+        let _ = watcher.send(Watched::Presence(SharedPresence::new(relayed.presence)));
+    }
+}
+
+fn evict(cache: &Cache, watchers: &UserWatchers, payload: &[u8]) {
+    let eviction = match serde_json::from_slice::<Eviction>(payload) {
+        Ok(eviction) => eviction,
+        Err(err) => {
+            debug!(?err, "ignoring malformed eviction");
+            return;
+        }
+    };
+    // this instance already evicted the user, and may have a new watcher for
+    // them by now
+    if eviction.origin == *INSTANCE_ID {
+        return;
+    }
+    cache.memory.remove(&eviction.user_id);
+    crate::evict_watcher(watchers, &eviction.user_id);
+    info!(
+        user_id = eviction.user_id,
+        "presence evicted on another instance"
+    );
+}
+
 pub struct Cache {
     memory: Arc<DashMap<String, PresenceData>>,
+    config: SharedConfig,
 }
 
 impl Cache {
-    pub fn new() -> Self {
+    pub fn new(config: SharedConfig) -> Self {
         Self {
             memory: Arc::new(DashMap::new()),
+            config,
         }
     }
 
+    /// `presence:<id>` by default, see [`Config::redis_key`].
+    fn key(&self, user_id: &str) -> String {
+        self.config.load().redis_key(user_id)
+    }
+
+    /// The newest copy of the presence, see [`freshest`].
     pub async fn get(&self, user_id: &str) -> Option<PresenceData> {
+        let mut shared = None;
         if let Some(mut redis) = get_redis().await {
-            let key = format!("presence:{}", user_id);
-            match redis.get::<_, Option<String>>(&key).await {
-                Ok(Some(json)) => {
-                    if let Ok(data) = serde_json::from_str(&json) {
-                        return Some(data);
-                    }
+            let key = self.key(user_id);
+            if let Ok(Some(json)) = redis.get::<_, Option<String>>(&key).await
+                && let Ok(data) = serde_json::from_str::<PresenceData>(&json)
+            {
+                let config = self.config.load();
+                if config.touch_on_read {
+                    touch(&mut redis, &key, &data, &config).await;
                 }
-                Ok(None) => return None,
-                Err(_) => {}
+                shared = Some(data);
             }
         }
 
-        self.memory.get(user_id).map(|r| r.clone())
+        freshest(shared, self.memory.get(user_id).map(|r| r.clone()))
     }
 
     pub async fn set(&self, user_id: &str, data: &PresenceData) {
+        // memory first, so this node has the update while the Redis write,
+        // which other instances and restarts read, is in flight
+        self.memory.insert(user_id.to_string(), data.clone());
+
         if let Some(mut redis) = get_redis().await {
-            let key = format!("presence:{}", user_id);
+            let key = self.key(user_id);
+            // keep entries around for the stale-if-error window past their TTL
+            let config = self.config.load();
+            let ttl = config.redis_ttl_secs + config.stale_if_error_secs;
             if let Ok(json) = serde_json::to_string(data) {
-                let _: Result<(), _> = redis.set_ex(&key, json, CACHE_TTL_SECS).await;
+                let _: Result<(), _> = redis.set_ex(&key, json, ttl).await;
             }
         }
-
-        self.memory.insert(user_id.to_string(), data.clone());
     }
 
     pub async fn remove(&self, user_id: &str) {
         if let Some(mut redis) = get_redis().await {
-            let key = format!("presence:{}", user_id);
+            let key = self.key(user_id);
             let _: Result<(), _> = redis.del(&key).await;
         }
 
@@ -107,6 +368,36 @@ impl Cache {
     }
 }
 
+/// Precedence between what Redis returned (`None` for a miss, an error or an
+/// unreadable value alike) and this instance's memory: the newer one by
+/// `timestamp_ms`, Redis on a tie.
+///
+/// Redis holds whatever any instance wrote last, memory only this instance's
+/// own writes, which land there first and stay put when the Redis write fails.
+/// So a miss doesn't hide a presence this instance just wrote, and an older
+/// copy on either side never shadows a newer one. Whatever comes back still
+/// goes through the callers' staleness checks.
+fn freshest(shared: Option<PresenceData>, local: Option<PresenceData>) -> Option<PresenceData> {
+    match (shared, local) {
+        (Some(shared), Some(local)) if local.timestamp_ms > shared.timestamp_ms => Some(local),
+        (Some(shared), _) => Some(shared),
+        (None, local) => local,
+    }
+}
+
+/// Resets the key's TTL on read, but never past `REDIS_TTL_SECS` or the point
+/// where the presence would be considered stale anyway (plus the
+/// stale-if-error window).
+async fn touch(redis: &mut ConnectionManager, key: &str, data: &PresenceData, config: &Config) {
+    let now = chrono::Utc::now().timestamp_millis();
+    let grace_ms = config.stale_if_error_secs as i64 * 1000;
+    let until_stale_ms = data.timestamp_ms + config.presence_ttl_ms() + grace_ms - now;
+    let ttl_ms = until_stale_ms.min(config.redis_ttl_secs as i64 * 1000 + grace_ms);
+    if ttl_ms > 0 {
+        let _: Result<(), _> = redis.pexpire(key, ttl_ms).await;
+    }
+}
+
 pub async fn wait_for_redis(timeout: Duration) -> bool {
     let start = std::time::Instant::now();
     let retry_delay = Duration::from_millis(200);
@@ -120,3 +411,82 @@ pub async fn wait_for_redis(timeout: Duration) -> bool {
 
     false
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relay_skips_own_updates() {
+        let watchers: UserWatchers = Arc::new(DashMap::new());
+        let (tx, mut rx) = tokio::sync::watch::channel(Watched::Nothing);
+        watchers.insert("1".to_string(), tx);
+        let message = |origin: &str| {
+            format!(
+                r#"{{"origin":"{origin}","presence":{{"user_id":"1","spotify":null,"timestamp_ms":0,"seq":3}}}}"#
+            )
+        };
+
+        relay(&watchers, message(&INSTANCE_ID).as_bytes());
+        assert!(!rx.has_changed().unwrap());
+
+        relay(&watchers, message("elsewhere").as_bytes());
+        assert!(rx.has_changed().unwrap());
+        assert_eq!(rx.borrow_and_update().presence().unwrap().presence.seq, 3);
+    }
+
+    #[test]
+    fn evictions_from_elsewhere_close_local_watchers() {
+        let config: SharedConfig = Arc::new(arc_swap::ArcSwap::from_pointee(
+            crate::config::Config::load().unwrap(),
+        ));
+        let cache = Cache::new(config);
+        let watchers: UserWatchers = Arc::new(DashMap::new());
+        let (tx, mut rx) = tokio::sync::watch::channel(Watched::Nothing);
+        watchers.insert("1".to_string(), tx);
+        let presence: PresenceData =
+            serde_json::from_str(r#"{"user_id":"1","spotify":null,"timestamp_ms":0,"seq":3}"#)
+                .unwrap();
+        cache.memory.insert("1".to_string(), presence);
+        let message = |origin: &str| format!(r#"{{"origin":"{origin}","user_id":"1"}}"#);
+
+        evict(&cache, &watchers, message(&INSTANCE_ID).as_bytes());
+        assert!(cache.memory.contains_key("1") && watchers.contains_key("1"));
+
+        evict(&cache, &watchers, message("elsewhere").as_bytes());
+        assert!(cache.memory.is_empty() && watchers.is_empty());
+        assert!(matches!(*rx.borrow_and_update(), Watched::Evicted));
+    }
+
+    #[test]
+    fn connection_counts_drop_out_at_zero() {
+        let mut counts = HashMap::new();
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+
+        assert_eq!(count_connection(&mut counts, ip, 1), 1);
+        assert_eq!(count_connection(&mut counts, ip, 1), 2);
+        assert_eq!(count_connection(&mut counts, ip, -1), 1);
+        assert_eq!(count_connection(&mut counts, ip, -1), 0);
+        assert!(counts.is_empty());
+    }
+
+    #[test]
+    fn reads_prefer_the_newest_copy() {
+        let at = |timestamp_ms: i64, seq: u64| {
+            serde_json::from_value::<PresenceData>(serde_json::json!({
+                "user_id": "1", "spotify": null, "timestamp_ms": timestamp_ms, "seq": seq
+            }))
+            .ok()
+        };
+        let seq = |p: Option<PresenceData>| p.map(|p| p.seq);
+
+        // Redis hit
+        assert_eq!(seq(freshest(at(2, 1), at(1, 2))), Some(1));
+        assert_eq!(seq(freshest(at(1, 1), at(2, 2))), Some(2));
+        assert_eq!(seq(freshest(at(1, 1), at(1, 2))), Some(1));
+        assert_eq!(seq(freshest(at(1, 1), None)), Some(1));
+        // Redis miss or error
+        assert_eq!(seq(freshest(None, at(1, 2))), Some(2));
+        assert_eq!(seq(freshest(None, None)), None);
+    }
+}
@@ -0,0 +1,216 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures_util::StreamExt;
+use futures_util::stream::BoxStream;
+use http_body_util::combinators::UnsyncBoxBody;
+use http_body_util::{BodyExt, StreamBody};
+use hyper::body::{Frame, Incoming};
+use hyper::server::conn::http1;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto::Builder;
+use hyper_util::server::graceful::GracefulShutdown;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tokio::time::timeout;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tower_service::Service;
+use tracing::{debug, info, warn};
+use warp::http::{HeaderValue, Request, Response, header};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+type ResponseBody = UnsyncBoxBody<Bytes, BoxError>;
+
+// warp has no public way to stream an arbitrary response body, so handlers
+// stash the stream in an extension and `into_hyper_response` swaps it in.
+#[derive(Clone)]
+struct StreamingBody(Arc<Mutex<Option<BoxStream<'static, Bytes>>>>);
+
+pub fn streaming_reply(
+    content_type: &'static str,
+    stream: BoxStream<'static, Bytes>,
+) -> warp::reply::Response {
+    let mut res = warp::reply::Response::default();
+    let headers = res.headers_mut();
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+    res.extensions_mut()
+        .insert(StreamingBody(Arc::new(Mutex::new(Some(stream)))));
+    res
+}
+
+/// The stream of a [`streaming_reply`], for tests that read it without
+/// serving the response.
+#[cfg(test)]
+pub fn take_stream(res: &mut warp::reply::Response) -> Option<BoxStream<'static, Bytes>> {
+    let StreamingBody(slot) = res.extensions_mut().remove::<StreamingBody>()?;
+    slot.lock().ok()?.take()
+}
+
+/// The address a connection was accepted from, added to each of its requests
+/// as an extension.
+#[derive(Debug, Clone, Copy)]
+pub struct PeerAddr(pub SocketAddr);
+
+fn into_hyper_response(res: warp::reply::Response) -> Response<ResponseBody> {
+    let (mut parts, body) = res.into_parts();
+
+    if let Some(StreamingBody(slot)) = parts.extensions.remove::<StreamingBody>()
+        && let Some(stream) = slot.lock().ok().and_then(|mut s| s.take())
+    {
+        let body = StreamBody::new(stream.map(|chunk| Ok::<_, BoxError>(Frame::data(chunk))));
+        return Response::from_parts(parts, body.boxed_unsync());
+    }
+
+    Response::from_parts(parts, body.map_err(BoxError::from).boxed_unsync())
+}
+
+/// How long in-flight requests (including open NDJSON streams) get to finish
+/// once shutdown starts.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(10);
+
+/// How long a client gets to complete the TLS handshake.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+/// How connections are accepted.
+pub struct Options {
+    /// Serve HTTPS, offering `h2` and `http/1.1` via ALPN.
+    pub tls: Option<TlsAcceptor>,
+    /// Accept HTTP/2 with prior knowledge over cleartext.
+    pub h2c: bool,
+}
+
+/// Loads a PEM certificate chain and private key into a TLS acceptor that
+/// negotiates HTTP/2 or HTTP/1.1 via ALPN.
+pub fn tls_acceptor(cert_path: &str, key_path: &str) -> Result<TlsAcceptor, String> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("failed to read TLS_CERT {cert_path}: {e}"))?;
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|e| format!("failed to read TLS_KEY {key_path}: {e}"))?;
+
+    let mut config = ServerConfig::builder_with_provider(Arc::new(
+        tokio_rustls::rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+    .map_err(|e| format!("invalid TLS configuration: {e}"))?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+pub async fn serve<S>(
+    svc: S,
+    addr: SocketAddr,
+    options: Options,
+    mut shutdown: watch::Receiver<bool>,
+) where
+    S: Service<Request<Incoming>, Response = warp::reply::Response, Error = Infallible>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    let listener = TcpListener::bind(addr)
+        .await
+        .unwrap_or_else(|e| panic!("failed to bind {addr}: {e}"));
+
+    // the auto builder tells HTTP/2 apart by its connection preface, so
+    // without TLS or h2c connections go to a plain HTTP/1 builder instead
+    let allow_h2 = options.tls.is_some() || options.h2c;
+    let builder = Builder::new(TokioExecutor::new());
+    let graceful = GracefulShutdown::new();
+
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = crate::wait_for_shutdown(&mut shutdown) => break,
+        };
+        let (stream, peer) = match accepted {
+            Ok(conn) => conn,
+            Err(err) => {
+                warn!(?err, "accept error");
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+
+        let svc = svc.clone();
+        let svc = hyper::service::service_fn(move |mut req: Request<Incoming>| {
+            req.extensions_mut().insert(PeerAddr(peer));
+            let mut svc = svc.clone();
+            async move {
+                let res = svc.call(req).await?;
+                Ok::<_, Infallible>(into_hyper_response(res))
+            }
+        });
+
+        let builder = builder.clone();
+        let tls = options.tls.clone();
+        let watcher = graceful.watcher();
+        let mut shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            let io: Box<dyn Io> = match tls {
+                Some(tls) => match timeout(TLS_HANDSHAKE_TIMEOUT, tls.accept(stream)).await {
+                    Ok(Ok(stream)) => Box::new(stream),
+                    Ok(Err(err)) => {
+                        debug!(?err, "tls handshake failed");
+                        return;
+                    }
+                    Err(_) => {
+                        debug!("tls handshake timed out");
+                        return;
+                    }
+                },
+                None => Box::new(stream),
+            };
+
+            let io = TokioIo::new(io);
+            if allow_h2 {
+                let conn = builder.serve_connection_with_upgrades(io, svc);
+                if let Err(err) = watcher.watch(conn).await {
+                    debug!(?err, "connection error");
+                }
+            } else {
+                // the graceful watcher can't drive an upgradeable HTTP/1
+                // connection, so hold it for the connection count and close
+                // on our own shutdown signal
+                let _watcher = watcher;
+                let conn = http1::Builder::new()
+                    .serve_connection(io, svc)
+                    .with_upgrades();
+                let mut conn = std::pin::pin!(conn);
+                let result = tokio::select! {
+                    result = conn.as_mut() => result,
+                    _ = crate::wait_for_shutdown(&mut shutdown) => {
+                        conn.as_mut().graceful_shutdown();
+                        conn.await
+                    }
+                };
+                if let Err(err) = result {
+                    debug!(?err, "connection error");
+                }
+            }
+        });
+    }
+
+    drop(listener);
+    info!("http server draining connections");
+    tokio::select! {
+        _ = graceful.shutdown() => {}
+        _ = tokio::time::sleep(SHUTDOWN_GRACE) => {
+            warn!("connections still open after shutdown grace period, closing");
+        }
+    }
+}
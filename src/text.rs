@@ -0,0 +1,186 @@
+//! One-line plain-text rendering of a presence, for IRC bridges, status bars
+//! and other consumers that don't want JSON.
+//!
+//! A template is plain text with `{placeholder}`s. Unknown placeholders are
+//! left as they are and placeholders with no value render as an empty string.
+
+use crate::{OnlineStatus, PresenceData};
+
+/// Longest `?format=` template accepted, in bytes.
+pub const MAX_FORMAT_LEN: usize = 256;
+
+const SPOTIFY_FORMAT: &str = "🎵 {track} — {artist} ({progress}/{duration})";
+const STATUS_FORMAT: &str = "{emoji} {status}";
+
+fn status_emoji(status: OnlineStatus) -> &'static str {
+    match status {
+        OnlineStatus::Online => "🟢",
+        OnlineStatus::Idle => "🌙",
+        OnlineStatus::Dnd => "⛔",
+        OnlineStatus::Offline => "⚫",
+    }
+}
+
+/// `m:ss`, or `h:mm:ss` from an hour up.
+fn duration(ms: i64) -> String {
+    let secs = ms.max(0) / 1000;
+    let (h, m, s) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if h > 0 {
+        format!("{h}:{m:02}:{s:02}")
+    } else {
+        format!("{m}:{s:02}")
+    }
+}
+
+fn placeholder(presence: &PresenceData, now_ms: i64, name: &str) -> Option<String> {
+    let spotify = presence.spotify.as_ref();
+    let value = match name {
+        "track" => spotify?.track.clone()?,
+        "artist" => spotify?.artist.clone()?,
+        "album" => spotify?.album.clone()?,
+        "progress" => duration(spotify?.progress_ms(now_ms)?),
+        "duration" => duration(spotify?.duration_ms()?),
+        "stage" => presence.stage.as_ref()?.channel_name.clone(),
+        "platform" => presence.primary_platform.clone()?,
+        "status" => presence.status.as_str().to_string(),
+        "emoji" => status_emoji(presence.status).to_string(),
+        _ => return None,
+    };
+    Some(value)
+}
+
+fn is_known(name: &str) -> bool {
+    matches!(
+        name,
+        "track"
+            | "artist"
+            | "album"
+            | "progress"
+            | "duration"
+            | "stage"
+            | "platform"
+            | "status"
+            | "emoji"
+    )
+}
+
+/// Renders `presence` with `format`, or with the built-in line (the track
+/// while listening to Spotify, the status otherwise) when `format` is `None`.
+pub fn render(presence: &PresenceData, format: Option<&str>, now_ms: i64) -> String {
+    let format = format.unwrap_or(if presence.spotify.is_some() {
+        SPOTIFY_FORMAT
+    } else {
+        STATUS_FORMAT
+    });
+
+    let mut out = String::with_capacity(format.len());
+    let mut rest = format;
+    while let Some(open) = rest.find('{') {
+        out.push_str(&rest[..open]);
+        let after = &rest[open + 1..];
+        match after.find('}') {
+            Some(close) if is_known(&after[..close]) => {
+                let name = &after[..close];
+                out.push_str(&placeholder(presence, now_ms, name).unwrap_or_default());
+                rest = &after[close + 1..];
+            }
+            _ => {
+                out.push('{');
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+
+    // the result is a single line whatever ends up in track names
+    out.replace(['\r', '\n'], " ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SpotifyActivity;
+
+    fn presence(spotify: Option<SpotifyActivity>, status: OnlineStatus) -> PresenceData {
+        PresenceData {
+            user_id: "1".to_string(),
+            status,
+            spotify,
+            game: None,
+            rich_presence: None,
+            custom_status: None,
+            stage: None,
+            client_status: None,
+            primary_platform: None,
+            username: None,
+            display_name: None,
+            avatar_url: None,
+            idle_since_ms: None,
+            first_seen_ms: None,
+            timestamp_ms: 0,
+            seq: 1,
+        }
+    }
+
+    fn listening() -> SpotifyActivity {
+        SpotifyActivity {
+            track: Some("Track".to_string()),
+            artist: Some("Artist".to_string()),
+            album: None,
+            album_art_url: None,
+            album_art_hash: None,
+            album_art: None,
+            track_url: None,
+            started_at_ms: Some(1_000),
+            ends_at_ms: Some(1_000 + 225_000),
+        }
+    }
+
+    #[test]
+    fn default_line_shows_track_or_status() {
+        let p = presence(Some(listening()), OnlineStatus::Online);
+        assert_eq!(
+            render(&p, None, 1_000 + 83_000),
+            "🎵 Track — Artist (1:23/3:45)"
+        );
+
+        assert_eq!(
+            render(&presence(None, OnlineStatus::Dnd), None, 0),
+            "⛔ dnd"
+        );
+        assert_eq!(
+            render(&presence(None, OnlineStatus::Offline), None, 0),
+            "⚫ offline"
+        );
+    }
+
+    #[test]
+    fn progress_is_clamped_to_the_track() {
+        let p = presence(Some(listening()), OnlineStatus::Offline);
+        assert_eq!(render(&p, Some("{progress}"), 0), "0:00");
+        assert_eq!(render(&p, Some("{progress}"), 10_000_000), "3:45");
+    }
+
+    #[test]
+    fn custom_format_substitutes_known_placeholders() {
+        let p = presence(Some(listening()), OnlineStatus::Idle);
+        assert_eq!(
+            render(&p, Some("{artist}: {track} [{album}] {status} {x} {"), 0),
+            "Artist: Track [] idle {x} {"
+        );
+    }
+
+    #[test]
+    fn output_stays_on_one_line() {
+        let mut spotify = listening();
+        spotify.track = Some("a\r\nb".to_string());
+        let p = presence(Some(spotify), OnlineStatus::Offline);
+        assert_eq!(render(&p, Some("{track}\n"), 0), "a  b ");
+    }
+
+    #[test]
+    fn long_durations_include_hours() {
+        assert_eq!(duration(3_723_000), "1:02:03");
+        assert_eq!(duration(-5), "0:00");
+    }
+}